@@ -0,0 +1,135 @@
+// Compares the way previews used to be read, the whole lrprev file and then a scan for each
+// "level_N" name, against reading its index and a single level, over a synthetic preview
+// cache laid out like lightroom's.
+use std::fs;
+use std::path::{Path, PathBuf};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, RgbImage};
+use tauri_app_lib::image_pipeline::RequestedSize;
+use tauri_app_lib::lrprev;
+
+const FILES_IN_CACHE : usize = 32;
+const LEVEL_SIZES : [(u32, u32); 5] = [(160, 107), (320, 213), (640, 427), (1280, 853), (2560, 1707)];
+
+fn section(name: &str, data: &[u8]) -> Vec<u8> {
+    // names are nul padded, and data padded out to a multiple of 16
+    let header_length = 24 + (name.len() + 1).next_multiple_of(8);
+    let padding_length = data.len().next_multiple_of(16) - data.len();
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"AgHg");
+    bytes.extend_from_slice(&(header_length as u16).to_be_bytes());
+    bytes.push(0);
+    bytes.push(0);
+    bytes.extend_from_slice(&(data.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(&(padding_length as u64).to_be_bytes());
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.resize(header_length, 0);
+    bytes.extend_from_slice(data);
+    bytes.resize(bytes.len() + padding_length, 0);
+    return bytes;
+}
+
+fn jpeg(width: u32, height: u32) -> Vec<u8> {
+    let pixels = RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]));
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(pixels)
+        .write_with_encoder(JpegEncoder::new_with_quality(&mut data, 85))
+        .unwrap();
+    return data;
+}
+
+fn synthetic_lrprev() -> Vec<u8> {
+    let levels: Vec<String> = LEVEL_SIZES
+        .iter()
+        .map(|(width, height)| format!("{{ height = {}, width = {} }}", height, width))
+        .collect();
+    let header = format!(
+        "pyramid = {{ colorProfile = \"AdobeRGB\", croppedHeight = 1707, croppedWidth = 2560, digest = \"0\", levels = {{ {} }}, quality = \"standard\", uuid = \"0\" }}",
+        levels.join(", ")
+    );
+    let mut file = section("header", header.as_bytes());
+    for (index, (width, height)) in LEVEL_SIZES.iter().enumerate() {
+        file.extend(section(&format!("level_{}", index + 1), &jpeg(*width, *height)));
+    }
+    return file;
+}
+
+fn synthetic_cache() -> (PathBuf, Vec<String>) {
+    let root = std::env::temp_dir().join(format!("reflex-lrprev-bench-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let contents = synthetic_lrprev();
+    let paths = (0..FILES_IN_CACHE)
+        .map(|index| {
+            let path = root.join(format!("{:04}.lrprev", index));
+            fs::write(&path, &contents).unwrap();
+            return path.to_string_lossy().into_owned();
+        })
+        .collect();
+    return (root, paths);
+}
+
+// the reader as it was before it understood the section headers, kept as the baseline
+fn find_subsequence(container: &[u8], search_term: &[u8]) -> Option<usize> {
+    return container
+        .windows(search_term.len())
+        .position(|window| window == search_term);
+}
+
+fn scanned_jpeg_segments(name: &str) -> Vec<Vec<u8>> {
+    let data: Vec<u8> = fs::read(name).unwrap();
+    let mut recorded_starts: Vec<usize> = Vec::new();
+    for i in 1..10 {
+        let level_s = format!("level_{i}");
+        let last_start = recorded_starts.last().copied().unwrap_or(0);
+        let found_search_term = find_subsequence(&data[last_start..], level_s.as_bytes());
+        if found_search_term.is_none() {
+            break;
+        }
+        recorded_starts.push(last_start + found_search_term.unwrap() + level_s.len() + 1);
+    }
+    return (0..recorded_starts.len())
+        .map(|range_index| {
+            let end = recorded_starts.get(range_index + 1).copied().unwrap_or(data.len());
+            return data[recorded_starts[range_index]..end].to_vec();
+        })
+        .collect();
+}
+
+fn cleanup(root: &Path) {
+    let _ = fs::remove_dir_all(root);
+}
+
+fn bench_lrprev_read(c: &mut Criterion) {
+    let (root, paths) = synthetic_cache();
+    let mut group = c.benchmark_group("lrprev_read");
+    group.bench_function("whole file scan, smallest level", |b| {
+        b.iter(|| {
+            for path in paths.iter() {
+                let segments = scanned_jpeg_segments(path);
+                black_box(segments.first().unwrap().len());
+            }
+        })
+    });
+    group.bench_function("index and level, smallest level", |b| {
+        b.iter(|| {
+            for path in paths.iter() {
+                let (_, jpeg) = lrprev::read_level_for_request(path, RequestedSize::Smallest).unwrap();
+                black_box(jpeg.len());
+            }
+        })
+    });
+    group.bench_function("index and level, 640px", |b| {
+        b.iter(|| {
+            for path in paths.iter() {
+                let (_, jpeg) = lrprev::read_level_for_request(path, RequestedSize::MaxDimension(640)).unwrap();
+                black_box(jpeg.len());
+            }
+        })
+    });
+    group.finish();
+    cleanup(&root);
+}
+
+criterion_group!(benches, bench_lrprev_read);
+criterion_main!(benches);
@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::lua_table::{self, LuaParseError, LuaTable, LuaValue};
+
+// the catalog lightroom opens on startup
+const DEFAULT_CATALOG_KEY : &str = "libraryToLoad20";
+// a string holding a serialised lua list of catalog paths
+const RECENT_CATALOGS_KEY : &str = "recentLibraries20";
+const STANDARD_PREVIEW_SIZE_KEY : &str = "AgLibrary_standardPreviewSize";
+const PREVIEW_QUALITY_KEY : &str = "AgLibrary_previewQuality";
+const DISCARD_PREVIEWS_AFTER_KEY : &str = "AgLibrary_discard1to1PreviewsAfter";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AgprefsError {
+    #[error("failed to read preferences file {path}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to parse preferences file {path}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: LuaParseError,
+    },
+    #[error("preferences file {0} has no `prefs` table")]
+    MissingPrefsTable(PathBuf),
+    #[error("preferences file {0} does not name a catalog to load")]
+    NoDefaultCatalog(PathBuf),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PreviewSettings {
+    pub standard_size: Option<u32>,
+    pub quality: Option<String>,
+    pub discard_full_size_after: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LightroomPreferences {
+    pub default_catalog: Option<String>,
+    pub recent_catalogs: Vec<String>,
+    pub previews: PreviewSettings,
+}
+
+fn value_to_string(value: &LuaValue) -> Option<String> {
+    match value {
+        LuaValue::String(s) => Some(s.clone()),
+        LuaValue::Number(n) => Some(n.to_string()),
+        LuaValue::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+// recent catalogs are usually a string containing a lua list,
+// but accept a plain table too
+fn parse_catalog_list(value: &LuaValue) -> Vec<String> {
+    let nested;
+    let table = match value {
+        LuaValue::Table(table) => table,
+        LuaValue::String(serialised) => match lua_table::parse_value(serialised) {
+            Ok(LuaValue::Table(table)) => {
+                nested = table;
+                &nested
+            }
+            _ => return Vec::new(),
+        },
+        _ => return Vec::new(),
+    };
+    return table
+        .array()
+        .into_iter()
+        .filter_map(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect();
+}
+
+fn preferences_from_table(prefs: &LuaTable) -> LightroomPreferences {
+    let default_catalog = prefs
+        .get(DEFAULT_CATALOG_KEY)
+        .and_then(|v| v.as_str())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned());
+    let recent_catalogs = prefs
+        .get(RECENT_CATALOGS_KEY)
+        .map(parse_catalog_list)
+        .unwrap_or_default();
+    let previews = PreviewSettings {
+        standard_size: prefs
+            .get(STANDARD_PREVIEW_SIZE_KEY)
+            .and_then(|v| v.as_f64())
+            .map(|n| n as u32),
+        quality: prefs.get(PREVIEW_QUALITY_KEY).and_then(value_to_string),
+        discard_full_size_after: prefs.get(DISCARD_PREVIEWS_AFTER_KEY).and_then(value_to_string),
+    };
+    return LightroomPreferences {
+        default_catalog,
+        recent_catalogs,
+        previews,
+    };
+}
+
+/// Parse the contents of an agprefs file, which is a lua chunk of the form `prefs = { ... }`.
+pub fn parse_preferences(contents: &str) -> Result<Option<LightroomPreferences>, LuaParseError> {
+    let assignments = lua_table::parse_assignments(contents)?;
+    let prefs = assignments
+        .iter()
+        .find(|(name, _)| name == "prefs")
+        .and_then(|(_, value)| value.as_table());
+    return Ok(prefs.map(preferences_from_table));
+}
+
+pub fn read_preferences(path: &Path) -> Result<LightroomPreferences, AgprefsError> {
+    let contents = fs::read(path).map_err(|source| AgprefsError::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    // agprefs are utf-8, but don't fail on the odd stray byte in an unrelated preference
+    let contents = String::from_utf8_lossy(&contents);
+    let parsed = parse_preferences(&contents).map_err(|source| AgprefsError::Parse {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    return parsed.ok_or_else(|| AgprefsError::MissingPrefsTable(path.to_path_buf()));
+}
@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use log::{info, warn};
+use serde::Serialize;
+use sqlx::Row;
+use crate::catalog_db::{self, CatalogError};
+use crate::color_labels::NO_LABEL_COLOR;
+use crate::image_data::ImageMetadataFields;
+use crate::metadata_helper::parse_capture_time;
+use crate::smart_collection::{self, ImageFacts, SmartRules};
+
+// collections and collection sets form a tree through parent. Lightroom keeps a few of its own
+// in here too, e.g. the quick collection, which it marks systemOnly.
+const COLLECTIONS_QUERY : &str = "
+    select
+        cast(id_local as integer) as id,
+        cast(name as text) as name,
+        cast(parent as integer) as parent,
+        cast(creationId as text) as creationId,
+        {system_only} as systemOnly
+    from AgLibraryCollection";
+
+const COLLECTION_IMAGES_QUERY : &str = "
+    select distinct
+        cast(collection as integer) as collection,
+        cast(image as integer) as image
+    from AgLibraryCollectionImage";
+
+const SMART_COLLECTION_RULES_QUERY : &str = "
+    select
+        cast(collection as integer) as collection,
+        cast(content as text) as content
+    from AgLibraryCollectionContent
+    where owningModule = 'ag.library.smart_collection'";
+
+const REQUIRED_COLUMNS : [(&str, &[&str]); 3] = [
+    ("AgLibraryCollection", &["id_local", "name", "parent", "creationId"]),
+    ("AgLibraryCollectionImage", &["collection", "image"]),
+    ("AgLibraryCollectionContent", &["collection", "content", "owningModule"]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectionKind {
+    Collection,
+    SmartCollection,
+    CollectionSet,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CollectionNode {
+    pub id: u64,
+    pub name: String,
+    pub kind: CollectionKind,
+    // for a set, the images in any collection beneath it, each counted once.
+    // None when a smart collection's rules couldn't be evaluated
+    pub image_count: Option<usize>,
+    pub error: Option<String>,
+    pub children: Vec<CollectionNode>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CatalogCollections {
+    pub tree: Vec<CollectionNode>,
+    // the ids of the collections each image is in, and of the sets above them, sorted
+    pub image_collections: HashMap<u64, Vec<u64>>,
+}
+
+struct CollectionRow {
+    name: String,
+    parent: Option<u64>,
+    kind: CollectionKind,
+}
+
+fn collection_kind(creation_id: &str) -> Option<CollectionKind> {
+    return match creation_id {
+        "com.adobe.ag.library.collection" => Some(CollectionKind::Collection),
+        "com.adobe.ag.library.smart_collection" => Some(CollectionKind::SmartCollection),
+        "com.adobe.ag.library.group" => Some(CollectionKind::CollectionSet),
+        // publish services and the like
+        _ => None,
+    };
+}
+
+// what smart collection rules test, from the image as read from the catalog
+fn image_facts(image: &ImageMetadataFields, collections: Vec<String>) -> ImageFacts {
+    let file_name = image.filename.rsplit(['/', '\\']).next().unwrap_or(&image.filename);
+    return ImageFacts {
+        rating: image.embedded_rating.unwrap_or(0) as f64,
+        pick: image.pick.unwrap_or(0) as f64,
+        color_label: image.color_label.clone().unwrap_or_default(),
+        label_color: image.label_color.clone().filter(|color| color != NO_LABEL_COLOR).unwrap_or_default(),
+        capture_time: image.datetime_original.as_deref().and_then(parse_capture_time),
+        touch_time: image.touch_time.as_deref().and_then(parse_capture_time),
+        filename: file_name.to_owned(),
+        folder: image.folder.clone().unwrap_or_default(),
+        file_format: image.file_format.clone().unwrap_or_default(),
+        camera: image.model.clone(),
+        lens: image.lens_model.clone(),
+        iso: image.iso_speed_rating.map(|iso| iso as f64),
+        focal_length: image.focal_length,
+        aperture: image.aperture_value,
+        shutter_speed: image
+            .shutter_speed_value
+            .filter(|(_, denominator)| *denominator != 0)
+            .map(|(numerator, denominator)| numerator as f64 / denominator as f64),
+        has_gps: image.has_gps.unwrap_or(false),
+        keywords: image.keywords.clone().unwrap_or_default(),
+        collections,
+    };
+}
+
+struct TreeBuilder<'a> {
+    collections: &'a HashMap<u64, CollectionRow>,
+    children: HashMap<u64, Vec<u64>>,
+    members: &'a HashMap<u64, HashSet<u64>>,
+    errors: &'a HashMap<u64, String>,
+}
+
+impl TreeBuilder<'_> {
+    // the node and every image beneath it
+    fn build(&self, id: u64, ancestors: &mut Vec<u64>) -> (CollectionNode, HashSet<u64>) {
+        let collection = &self.collections[&id];
+        let mut images = self.members.get(&id).cloned().unwrap_or_default();
+        let mut children = Vec::new();
+        ancestors.push(id);
+        for child in self.children.get(&id).cloned().unwrap_or_default() {
+            // a broken catalog could make a loop of parents
+            if ancestors.contains(&child) {
+                warn!("collection {} is its own ancestor, leaving it out", child);
+                continue;
+            }
+            let (node, child_images) = self.build(child, ancestors);
+            images.extend(child_images);
+            children.push(node);
+        }
+        ancestors.pop();
+        // sets first, as lightroom lists them
+        children.sort_by_key(|node| (node.kind != CollectionKind::CollectionSet, node.name.to_lowercase()));
+        let error = self.errors.get(&id).cloned();
+        let node = CollectionNode {
+            id,
+            name: collection.name.clone(),
+            kind: collection.kind,
+            image_count: if error.is_some() { None } else { Some(images.len()) },
+            error,
+            children,
+        };
+        return (node, images);
+    }
+}
+
+/// The catalog's collections and collection sets with how many images are in each, and the
+/// collections each image is in. Smart collections are evaluated from their rules against
+/// `images`, as read from the catalog along with their keywords and label colours.
+pub async fn load_catalog_collections(cat_path: &str, images: &[ImageMetadataFields]) -> Result<CatalogCollections, CatalogError> {
+    let mut db = catalog_db::connect_read_only(cat_path).await?;
+    let mut collection_columns = HashSet::new();
+    for (table, columns) in REQUIRED_COLUMNS {
+        let found = catalog_db::table_columns(&mut db, cat_path, table).await?;
+        catalog_db::require_columns(&found, cat_path, table, columns)?;
+        if table == "AgLibraryCollection" {
+            collection_columns = found;
+        }
+    }
+    let database_error = |source| CatalogError::Database { path: cat_path.to_owned(), source };
+
+    let system_only = if collection_columns.contains("systemOnly") { "cast(systemOnly as text)" } else { "null" };
+    let collections_query = COLLECTIONS_QUERY.replace("{system_only}", system_only);
+    let mut collections = HashMap::new();
+    for row in sqlx::query(&collections_query).fetch_all(&mut db).await.map_err(database_error)? {
+        let id = row.try_get::<i64, _>("id").map_err(database_error)? as u64;
+        let system_only = row.try_get::<Option<String>, _>("systemOnly").map_err(database_error)?;
+        if matches!(system_only.as_deref(), Some("1") | Some("1.0") | Some("true")) {
+            continue;
+        }
+        let creation_id = row.try_get::<Option<String>, _>("creationId").map_err(database_error)?;
+        let kind = creation_id.as_deref().and_then(collection_kind);
+        if kind.is_none() {
+            continue;
+        }
+        let name = row.try_get::<Option<String>, _>("name").map_err(database_error)?.unwrap_or_default();
+        let parent = row.try_get::<Option<i64>, _>("parent").map_err(database_error)?.map(|parent| parent as u64);
+        collections.insert(id, CollectionRow { name, parent, kind: kind.unwrap() });
+    }
+
+    let mut members: HashMap<u64, HashSet<u64>> = HashMap::new();
+    for row in sqlx::query(COLLECTION_IMAGES_QUERY).fetch_all(&mut db).await.map_err(database_error)? {
+        let collection = row.try_get::<Option<i64>, _>("collection").map_err(database_error)?;
+        let image = row.try_get::<Option<i64>, _>("image").map_err(database_error)?;
+        if collection.is_none() || image.is_none() {
+            continue;
+        }
+        let collection = collection.unwrap() as u64;
+        let is_collection = collections.get(&collection).map(|row| row.kind == CollectionKind::Collection);
+        if is_collection != Some(true) {
+            continue;
+        }
+        members.entry(collection).or_default().insert(image.unwrap() as u64);
+    }
+
+    // relative dates in the rules are as of now, as they are when lightroom shows the collection
+    let now = chrono::Local::now().naive_local();
+    let mut errors = HashMap::new();
+    let mut smart_rules: Vec<(u64, SmartRules)> = Vec::new();
+    for row in sqlx::query(SMART_COLLECTION_RULES_QUERY).fetch_all(&mut db).await.map_err(database_error)? {
+        let collection = row.try_get::<Option<i64>, _>("collection").map_err(database_error)?;
+        if collection.is_none() {
+            continue;
+        }
+        let collection = collection.unwrap() as u64;
+        let is_smart = collections.get(&collection).map(|row| row.kind == CollectionKind::SmartCollection);
+        if is_smart != Some(true) {
+            continue;
+        }
+        let content = row.try_get::<Option<String>, _>("content").map_err(database_error)?.unwrap_or_default();
+        match smart_collection::parse_rules(&content, now) {
+            Ok(rules) => smart_rules.push((collection, rules)),
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                warn!("smart collection {:?} can't be evaluated: {:#}", collections[&collection].name, err);
+                errors.insert(collection, format!("{:#}", err));
+            }
+        }
+    }
+    for (id, collection) in &collections {
+        let has_rules = smart_rules.iter().any(|(smart_id, _)| smart_id == id) || errors.contains_key(id);
+        if collection.kind == CollectionKind::SmartCollection && !has_rules {
+            errors.insert(*id, "smart collection has no rules".to_owned());
+        }
+    }
+
+    if !smart_rules.is_empty() {
+        // smart collections can test which ordinary collections an image is in, by name
+        let mut image_collection_names: HashMap<u64, Vec<String>> = HashMap::new();
+        for (collection, images) in &members {
+            for image in images {
+                image_collection_names.entry(*image).or_default().push(collections[collection].name.clone());
+            }
+        }
+        let image_facts: Vec<(u64, ImageFacts)> = images
+            .iter()
+            .filter(|image| image.id.is_some())
+            .map(|image| {
+                let id = image.id.unwrap();
+                let collection_names = image_collection_names.remove(&id).unwrap_or_default();
+                return (id, image_facts(image, collection_names));
+            })
+            .collect();
+        for (collection, rules) in &smart_rules {
+            let matched = image_facts
+                .iter()
+                .filter(|(_, facts)| rules.matches(facts))
+                .map(|(id, _)| *id)
+                .collect();
+            members.insert(*collection, matched);
+        }
+    }
+
+    let mut builder = TreeBuilder {
+        collections: &collections,
+        children: HashMap::new(),
+        members: &members,
+        errors: &errors,
+    };
+    // roots hang off a made up id 0, which id_local never is
+    for (id, collection) in &collections {
+        let parent = collection.parent.filter(|parent| collections.contains_key(parent)).unwrap_or(0);
+        builder.children.entry(parent).or_default().push(*id);
+    }
+    let mut tree = Vec::new();
+    let mut ancestors = Vec::new();
+    for id in builder.children.get(&0).cloned().unwrap_or_default() {
+        tree.push(builder.build(id, &mut ancestors).0);
+    }
+    tree.sort_by_key(|node| (node.kind != CollectionKind::CollectionSet, node.name.to_lowercase()));
+
+    let mut image_collections: HashMap<u64, Vec<u64>> = HashMap::new();
+    add_image_collections(&tree, &mut Vec::new(), &members, &mut image_collections);
+    for ids in image_collections.values_mut() {
+        ids.sort();
+        ids.dedup();
+    }
+
+    info!("read {} collections ({} smart) from {}", collections.len(), smart_rules.len() + errors.len(), cat_path);
+    return Ok(CatalogCollections { tree, image_collections });
+}
+
+// each image is in its collections and every set above them
+fn add_image_collections(nodes: &[CollectionNode], sets: &mut Vec<u64>, members: &HashMap<u64, HashSet<u64>>, image_collections: &mut HashMap<u64, Vec<u64>>) {
+    for node in nodes {
+        if node.kind == CollectionKind::CollectionSet {
+            sets.push(node.id);
+            add_image_collections(&node.children, sets, members, image_collections);
+            sets.pop();
+            continue;
+        }
+        for image in members.get(&node.id).into_iter().flatten() {
+            let ids = image_collections.entry(*image).or_default();
+            ids.push(node.id);
+            ids.extend(sets.iter().copied());
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use futures::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+use sqlx::{ConnectOptions, Row};
+
+// Adobe_DBVersion is written as MMmmmmm, e.g. "1300025" for Lightroom Classic 13.
+// We know the layouts from Lightroom 4 up to Lightroom Classic 14. Older catalogs are refused,
+// newer ones are only warned about, as the table and column checks decide whether we can read them.
+const MIN_SUPPORTED_MAJOR : u32 = 4;
+const MAX_KNOWN_MAJOR : u32 = 14;
+
+const PREVIEW_INDEX_QUERY : &str =
+    "select cast(imageId as integer) as imageId, uuid, digest, orientation from ImageCacheEntry";
+// older preview stores don't record the orientation alongside the cache entry
+const PREVIEW_INDEX_QUERY_WITHOUT_ORIENTATION : &str =
+    "select cast(imageId as integer) as imageId, uuid, digest, null as orientation from ImageCacheEntry";
+// the entries are counted before they're streamed, so the queries never overlap
+const PREVIEW_POOL_CONNECTIONS : u32 = 1;
+// report progress this often, large catalogs have hundreds of thousands of entries
+const PROGRESS_INTERVAL_ROWS : usize = 10000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CatalogError {
+    #[error("failed to read {path}")]
+    Database {
+        path: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("catalog schema version {version} is not supported, reflex supports Lightroom {MIN_SUPPORTED_MAJOR} and later")]
+    UnsupportedVersion { version: String },
+    #[error("{path} has no {table} table, it may be from an unsupported version of Lightroom")]
+    MissingTable { path: String, table: String },
+    #[error("{table} in {path} has no {column} column, it may be from an unsupported version of Lightroom")]
+    MissingColumn { path: String, table: String, column: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogVersion {
+    pub raw: String,
+    pub major: u32,
+}
+
+/// What we know about the layout of a catalog and its companion databases,
+/// which decides the queries we run against them.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CatalogSchema {
+    // None when the catalog itself couldn't be opened (e.g. lightroom holds an exclusive lock)
+    pub catalog_version: Option<CatalogVersion>,
+    pub preview_store_version: Option<String>,
+    pub preview_cache_has_orientation: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct PreviewData {
+    pub image_id: u64,
+    pub uuid: String,
+    pub digest: String,
+    pub orientation: Option<String>,
+}
+
+pub async fn connect_read_only(path: &str) -> Result<SqliteConnection, CatalogError> {
+    return SqliteConnectOptions::new()
+        .read_only(true)
+        .filename(path)
+        .connect()
+        .await
+        .map_err(|source| CatalogError::Database { path: path.to_owned(), source });
+}
+
+pub async fn connect_read_only_pool(path: &str) -> Result<SqlitePool, CatalogError> {
+    return SqlitePoolOptions::new()
+        .max_connections(PREVIEW_POOL_CONNECTIONS)
+        .connect_with(SqliteConnectOptions::new().read_only(true).filename(path))
+        .await
+        .map_err(|source| CatalogError::Database { path: path.to_owned(), source });
+}
+
+pub async fn table_columns(db: &mut SqliteConnection, path: &str, table: &str) -> Result<HashSet<String>, CatalogError> {
+    let rows = sqlx::query("select name from pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(db)
+        .await
+        .map_err(|source| CatalogError::Database { path: path.to_owned(), source })?;
+    let columns: HashSet<String> = rows
+        .iter()
+        .filter_map(|row| row.try_get::<String, _>("name").ok())
+        .collect();
+    if columns.is_empty() {
+        return Err(CatalogError::MissingTable { path: path.to_owned(), table: table.to_owned() });
+    }
+    return Ok(columns);
+}
+
+pub fn require_columns(columns: &HashSet<String>, path: &str, table: &str, required: &[&str]) -> Result<(), CatalogError> {
+    for column in required {
+        if !columns.contains(*column) {
+            return Err(CatalogError::MissingColumn {
+                path: path.to_owned(),
+                table: table.to_owned(),
+                column: column.to_string(),
+            });
+        }
+    }
+    return Ok(());
+}
+
+fn parse_db_version(raw: &str) -> Option<CatalogVersion> {
+    let digits: String = raw.chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 6 {
+        return None;
+    }
+    let major = digits[..digits.len() - 5].parse::<u32>().ok()?;
+    return Some(CatalogVersion { raw: raw.to_owned(), major });
+}
+
+async fn read_catalog_version(cat_path: &str) -> Result<Option<CatalogVersion>, CatalogError> {
+    let mut db = match connect_read_only(cat_path).await {
+        Ok(db) => db,
+        Err(err) => {
+            warn!("{:#}", anyhow::Error::from(err));
+            return Ok(None);
+        }
+    };
+    let raw: Option<String> = sqlx::query_scalar(
+        "select cast(value as text) from Adobe_variablesTable where name = 'Adobe_DBVersion'"
+    )
+        .fetch_optional(&mut db)
+        .await
+        .map_err(|source| CatalogError::Database { path: cat_path.to_owned(), source })?;
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Err(CatalogError::UnsupportedVersion { version: "unknown".to_owned() }),
+    };
+    let version = parse_db_version(&raw)
+        .ok_or_else(|| CatalogError::UnsupportedVersion { version: raw.clone() })?;
+    if version.major < MIN_SUPPORTED_MAJOR {
+        return Err(CatalogError::UnsupportedVersion { version: raw });
+    }
+    if version.major > MAX_KNOWN_MAJOR {
+        warn!("catalog schema version {} is newer than Lightroom Classic {}, reading it if its tables are as we expect", raw, MAX_KNOWN_MAJOR);
+    }
+    return Ok(Some(version));
+}
+
+/// Work out the catalog's schema version and the layouts of the tables we read,
+/// failing with a compatibility error if they're not ones we know how to query.
+pub async fn detect_schema(cat_path: &str, preview_db_path: &str, metadata_db_path: &str) -> Result<CatalogSchema, CatalogError> {
+    let catalog_version = read_catalog_version(cat_path).await?;
+
+    let mut previews_db = connect_read_only(preview_db_path).await?;
+    let preview_store_version: Option<String> = sqlx::query_scalar("select cast(version as text) from StoreInfo")
+        .fetch_optional(&mut previews_db)
+        .await
+        .ok()
+        .flatten();
+    let cache_columns = table_columns(&mut previews_db, preview_db_path, "ImageCacheEntry").await?;
+    require_columns(&cache_columns, preview_db_path, "ImageCacheEntry", &["imageId", "uuid", "digest"])?;
+
+    // part of every catalog we open, so check it's there to be read. The images themselves
+    // come from the catalog, this fills in the dimensions and exif it doesn't harvest
+    let mut metadata_db = connect_read_only(metadata_db_path).await?;
+    let metadata_columns = table_columns(&mut metadata_db, metadata_db_path, "AgImagesMetadata").await?;
+    require_columns(&metadata_columns, metadata_db_path, "AgImagesMetadata", &["imageid", "com_adobe_absoluteFilepath"])?;
+
+    let schema = CatalogSchema {
+        catalog_version,
+        preview_store_version,
+        preview_cache_has_orientation: cache_columns.contains("orientation"),
+    };
+    info!("detected catalog schema {:?}", schema);
+    return Ok(schema);
+}
+
+impl CatalogSchema {
+    fn preview_index_query(&self) -> &'static str {
+        if self.preview_cache_has_orientation {
+            return PREVIEW_INDEX_QUERY;
+        }
+        return PREVIEW_INDEX_QUERY_WITHOUT_ORIENTATION;
+    }
+}
+
+/// Stream every ImageCacheEntry row, calling `progress` with (rows loaded, total rows)
+/// as they arrive.
+pub async fn load_preview_index<F>(pool: &SqlitePool, preview_db_path: &str, schema: &CatalogSchema, mut progress: F) -> Result<HashMap<u64, PreviewData>, CatalogError>
+where
+    F: FnMut(usize, usize),
+{
+    let map_err = |source| CatalogError::Database { path: preview_db_path.to_owned(), source };
+    let total: i64 = sqlx::query_scalar("select count(*) from ImageCacheEntry")
+        .fetch_one(pool)
+        .await
+        .map_err(map_err)?;
+    let total = total.max(0) as usize;
+    progress(0, total);
+
+    let mut rows = sqlx::query(schema.preview_index_query()).fetch(pool);
+    let mut image_id_to_image = HashMap::with_capacity(total);
+    let mut loaded = 0;
+    while let Some(row) = rows.try_next().await.map_err(map_err)? {
+        let entry = (|| -> Result<PreviewData, sqlx::Error> {
+            return Ok(PreviewData {
+                image_id: row.try_get::<i64, _>("imageId")? as u64,
+                uuid: row.try_get("uuid")?,
+                digest: row.try_get("digest")?,
+                orientation: row.try_get("orientation")?,
+            });
+        })();
+        match entry {
+            Ok(image) => {
+                image_id_to_image.insert(image.image_id, image);
+            }
+            Err(err) => warn!("skipping malformed ImageCacheEntry row: {}", err),
+        }
+        loaded += 1;
+        if loaded % PROGRESS_INTERVAL_ROWS == 0 {
+            progress(loaded, total);
+        }
+    }
+    progress(loaded, total);
+    return Ok(image_id_to_image);
+}
+
+/// The id_global of every image in the catalog, which is also the name of its smart preview.
+pub async fn load_image_uuids(cat_path: &str) -> Result<HashMap<u64, String>, CatalogError> {
+    let mut db = connect_read_only(cat_path).await?;
+    let rows = sqlx::query("select cast(id_local as integer) as id_local, id_global from Adobe_images")
+        .fetch_all(&mut db)
+        .await
+        .map_err(|source| CatalogError::Database { path: cat_path.to_owned(), source })?;
+    let mut image_id_to_uuid = HashMap::new();
+    for row in rows {
+        let id_local = row.try_get::<i64, _>("id_local");
+        let id_global = row.try_get::<String, _>("id_global");
+        if let (Ok(id_local), Ok(id_global)) = (id_local, id_global) {
+            image_id_to_uuid.insert(id_local as u64, id_global);
+        }
+    }
+    return Ok(image_id_to_uuid);
+}
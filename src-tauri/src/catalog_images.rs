@@ -0,0 +1,203 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+use log::{info, warn};
+use sqlx::Row;
+use crate::catalog_db::{self, CatalogError};
+use crate::image_data::ImageMetadataFields;
+
+// each image's file, folder and root folder, with whatever exif lightroom harvested from it.
+// Virtual copies are images of their own that share a file, so appear once each. Columns are
+// cast as sqlite will happily keep an integer in a real column, and sqlx won't decode it.
+const CATALOG_IMAGES_QUERY : &str = "
+    select
+        cast(image.id_local as integer) as id,
+        cast(image.captureTime as text) as captureTime,
+        cast(image.rating as real) as rating,
+        cast(image.pick as real) as pick,
+        cast(image.colorLabels as text) as colorLabels,
+        cast(image.fileFormat as text) as fileFormat,
+        cast(image.touchTime as real) as touchTime,
+        rootFolder.absolutePath as rootPath,
+        folder.pathFromRoot as pathFromRoot,
+        file.baseName as baseName,
+        file.extension as extension,
+        camera.value as model,
+        lens.value as lens,
+        cast(exif.aperture as real) as aperture,
+        cast(exif.shutterSpeed as real) as shutterSpeed,
+        cast(exif.focalLength as real) as focalLength,
+        cast(exif.isoSpeedRating as real) as isoSpeedRating,
+        cast(exif.flashFired as integer) as flashFired,
+        cast(exif.hasGPS as integer) as hasGPS
+    from Adobe_images image
+    join AgLibraryFile file on file.id_local = image.rootFile
+    join AgLibraryFolder folder on folder.id_local = file.folder
+    join AgLibraryRootFolder rootFolder on rootFolder.id_local = folder.rootFolder
+    left join AgHarvestedExifMetadata exif on exif.image = image.id_local
+    left join AgInternedExifCameraModel camera on camera.id_local = exif.cameraModelRef
+    left join AgInternedExifLens lens on lens.id_local = exif.lensRef
+    order by image.id_local desc";
+
+const REQUIRED_COLUMNS : [(&str, &[&str]); 7] = [
+    ("Adobe_images", &["id_local", "rootFile", "captureTime", "rating", "pick", "colorLabels", "fileFormat", "touchTime"]),
+    ("AgLibraryFile", &["id_local", "folder", "baseName", "extension"]),
+    ("AgLibraryFolder", &["id_local", "rootFolder", "pathFromRoot"]),
+    ("AgLibraryRootFolder", &["id_local", "absolutePath"]),
+    ("AgHarvestedExifMetadata", &["image", "cameraModelRef", "lensRef", "aperture", "shutterSpeed", "focalLength", "isoSpeedRating", "flashFired", "hasGPS"]),
+    ("AgInternedExifCameraModel", &["id_local", "value"]),
+    ("AgInternedExifLens", &["id_local", "value"]),
+];
+// as capture times are handed to the frontend
+const TOUCH_TIME_FORMAT : &str = "%Y-%m-%dT%H:%M:%S";
+// how far from a whole 1/n, or a whole tenth of a second, an exposure time can be and still be
+// taken as one, which allows for lightroom's rounding of the APEX value
+const EXPOSURE_TIME_TOLERANCE : f64 = 0.05;
+
+// lightroom keeps paths with forward slashes and a trailing one on each folder, even on windows,
+// where the rest of the app expects backslashes (as metadatahelper.db has them)
+fn image_path(root_path: &str, path_from_root: &str, base_name: &str, extension: &str) -> (String, String) {
+    let folder = format!("{}{}", root_path, path_from_root);
+    let folder = folder.trim_end_matches('/');
+    let mut filename = format!("{}/{}", folder, base_name);
+    if !extension.is_empty() {
+        filename = format!("{}.{}", filename, extension);
+    }
+    let is_windows = root_path.as_bytes().get(1) == Some(&b':');
+    if is_windows {
+        return (folder.replace('/', "\\"), filename.replace('/', "\\"));
+    }
+    return (folder.to_owned(), filename);
+}
+
+/// Lightroom harvests aperture as an APEX value, Av = 2 log2(N), we want the f-number.
+pub fn f_number_from_apex(aperture: f64) -> f64 {
+    return (2f64.powf(aperture / 2.0) * 10.0).round() / 10.0;
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        return a.max(1);
+    }
+    return gcd(b, a % b);
+}
+
+/// Lightroom harvests shutter speed as an APEX value, Tv = -log2(t), we want the exposure time.
+pub fn exposure_time_from_apex(shutter_speed: f64) -> Option<(u32, u32)> {
+    return exposure_time_from_seconds(2f64.powf(-shutter_speed));
+}
+
+/// An exposure time as a fraction: 1/n below a second when it's a whole n, as most stops
+/// are, otherwise tenths of a second, e.g. 4/10 or 13/10.
+pub fn exposure_time_from_seconds(seconds: f64) -> Option<(u32, u32)> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return None;
+    }
+    if seconds < 1.0 {
+        let denominator = 1.0 / seconds;
+        if (denominator - denominator.round()).abs() <= EXPOSURE_TIME_TOLERANCE {
+            return Some((1, denominator.round() as u32));
+        }
+        // the fractional stops, 0.3, 0.4, 0.6 and 0.8 seconds
+        let tenths = seconds * 10.0;
+        if tenths.round() >= 1.0 && (tenths - tenths.round()).abs() <= EXPOSURE_TIME_TOLERANCE {
+            return Some((tenths.round() as u32, 10));
+        }
+        return Some((1, denominator.round() as u32));
+    }
+    let tenths = (seconds * 10.0).round() as u32;
+    let divisor = gcd(tenths, 10);
+    return Some((tenths / divisor, 10 / divisor));
+}
+
+// touchTime is when the image was last edited, in seconds since the start of 2001
+fn touch_time(seconds: f64) -> Option<NaiveDateTime> {
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return None;
+    }
+    let epoch = NaiveDate::from_ymd_opt(2001, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    return epoch.checked_add_signed(Duration::milliseconds((seconds * 1000.0) as i64));
+}
+
+fn pick_flag(pick: f64) -> i8 {
+    if pick > 0.0 {
+        return 1;
+    }
+    if pick < 0.0 {
+        return -1;
+    }
+    return 0;
+}
+
+fn optional<T>(row: &sqlx::sqlite::SqliteRow, column: &str) -> Option<T>
+where
+    T: for<'r> sqlx::Decode<'r, sqlx::Sqlite> + sqlx::Type<sqlx::Sqlite>,
+{
+    return row.try_get::<Option<T>, _>(column).ok().flatten();
+}
+
+fn image_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<ImageMetadataFields, sqlx::Error> {
+    let root_path: String = row.try_get("rootPath")?;
+    let path_from_root: String = optional(row, "pathFromRoot").unwrap_or_default();
+    let base_name: String = row.try_get("baseName")?;
+    let extension: String = optional(row, "extension").unwrap_or_default();
+    let (folder, filename) = image_path(&root_path, &path_from_root, &base_name, &extension);
+    return Ok(ImageMetadataFields {
+        id: Some(row.try_get::<i64, _>("id")? as u64),
+        folder: Some(folder),
+        filename,
+        datetime_original: optional(row, "captureTime"),
+        model: optional(row, "model"),
+        lens_model: optional(row, "lens"),
+        shutter_speed_value: optional::<f64>(row, "shutterSpeed").and_then(exposure_time_from_apex),
+        aperture_value: optional::<f64>(row, "aperture").map(f_number_from_apex),
+        focal_length: optional(row, "focalLength"),
+        iso_speed_rating: optional::<f64>(row, "isoSpeedRating").map(|iso| iso.round().clamp(0.0, u16::MAX as f64) as u16),
+        // lightroom doesn't harvest these
+        exposure_program: None,
+        metering_mode: None,
+        // only whether it fired, which is the lowest bit of the exif value
+        flash: optional::<i64>(row, "flashFired").map(|fired| if fired != 0 { 1 } else { 0 }),
+        embedded_rating: optional::<f64>(row, "rating").map(|rating| rating.round() as i16),
+        // unflagged is stored as 0 or left null
+        pick: Some(pick_flag(optional::<f64>(row, "pick").unwrap_or(0.0))),
+        color_label: Some(optional::<String>(row, "colorLabels").unwrap_or_default().trim().to_owned()),
+        // which needs the catalog's label set, see color_labels
+        label_color: None,
+        file_format: optional(row, "fileFormat"),
+        touch_time: optional::<f64>(row, "touchTime")
+            .and_then(touch_time)
+            .map(|time| time.format(TOUCH_TIME_FORMAT).to_string()),
+        has_gps: optional::<i64>(row, "hasGPS").map(|has_gps| has_gps != 0),
+        // the catalog only has these for some images, metadatahelper.db has them for all
+        file_dimensions: None,
+        cropped_dimensions: None,
+        // read separately, see catalog_keywords
+        keywords: None,
+        // and catalog_collections
+        collections: None,
+        // and develop_settings
+        develop: None,
+    });
+}
+
+/// Every image in the catalog, the most recently added first, read from the catalog itself
+/// rather than metadatahelper.db.
+pub async fn load_catalog_images(cat_path: &str) -> Result<Vec<ImageMetadataFields>, CatalogError> {
+    let mut db = catalog_db::connect_read_only(cat_path).await?;
+    for (table, columns) in REQUIRED_COLUMNS {
+        let found = catalog_db::table_columns(&mut db, cat_path, table).await?;
+        catalog_db::require_columns(&found, cat_path, table, columns)?;
+    }
+    let rows = sqlx::query(CATALOG_IMAGES_QUERY)
+        .fetch_all(&mut db)
+        .await
+        .map_err(|source| CatalogError::Database { path: cat_path.to_owned(), source })?;
+    let mut images = Vec::with_capacity(rows.len());
+    for row in &rows {
+        match image_from_row(row) {
+            Ok(image) => images.push(image),
+            Err(err) => warn!("skipping malformed catalog image row: {}", err),
+        }
+    }
+    info!("read {} images from {}", images.len(), cat_path);
+    return Ok(images);
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet};
+use log::{info, warn};
+use serde::Serialize;
+use sqlx::Row;
+use crate::catalog_db::{self, CatalogError};
+
+// keywords form a tree through parent, under a single nameless root lightroom creates with the
+// catalog. Synonyms, export settings and so on are left alone.
+const KEYWORDS_QUERY : &str = "
+    select
+        cast(id_local as integer) as id,
+        cast(name as text) as name,
+        cast(parent as integer) as parent
+    from AgLibraryKeyword";
+
+// an image is tagged once per keyword, but nothing in the schema stops a repeat
+const KEYWORD_IMAGES_QUERY : &str = "
+    select distinct
+        cast(image as integer) as image,
+        cast(tag as integer) as tag
+    from AgLibraryKeywordImage";
+
+const REQUIRED_COLUMNS : [(&str, &[&str]); 2] = [
+    ("AgLibraryKeyword", &["id_local", "name", "parent"]),
+    ("AgLibraryKeywordImage", &["image", "tag"]),
+];
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KeywordNode {
+    pub id: u64,
+    pub name: String,
+    // images tagged with this keyword itself
+    pub image_count: usize,
+    // images tagged with this keyword or any beneath it, each counted once
+    pub total_image_count: usize,
+    pub children: Vec<KeywordNode>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CatalogKeywords {
+    pub tree: Vec<KeywordNode>,
+    // the names of each image's keywords, sorted
+    pub image_keywords: HashMap<u64, Vec<String>>,
+}
+
+struct KeywordRow {
+    name: Option<String>,
+    parent: Option<u64>,
+}
+
+struct TreeBuilder<'a> {
+    keywords: &'a HashMap<u64, KeywordRow>,
+    children: HashMap<u64, Vec<u64>>,
+    keyword_images: &'a HashMap<u64, HashSet<u64>>,
+    // a broken catalog could make a loop of parents, which is never reached from a root
+    visited: HashSet<u64>,
+}
+
+impl TreeBuilder<'_> {
+    // the node and every image beneath it
+    fn build(&mut self, id: u64, name: String) -> (KeywordNode, HashSet<u64>) {
+        let mut images = self.keyword_images.get(&id).cloned().unwrap_or_default();
+        let image_count = images.len();
+        let mut children = Vec::new();
+        for (child, child_images) in self.build_children(id) {
+            images.extend(child_images);
+            children.push(child);
+        }
+        let node = KeywordNode {
+            id,
+            name,
+            image_count,
+            total_image_count: images.len(),
+            children,
+        };
+        return (node, images);
+    }
+
+    // a nameless keyword is only there to hold others, so they take its place
+    fn build_children(&mut self, parent: u64) -> Vec<(KeywordNode, HashSet<u64>)> {
+        let mut built = Vec::new();
+        for id in self.children.get(&parent).cloned().unwrap_or_default() {
+            self.visited.insert(id);
+            match self.keywords[&id].name.clone() {
+                Some(name) => built.push(self.build(id, name)),
+                None => built.extend(self.build_children(id)),
+            }
+        }
+        built.sort_by_key(|(node, _)| node.name.to_lowercase());
+        return built;
+    }
+}
+
+fn keyword_tree(keywords: &HashMap<u64, KeywordRow>, keyword_images: &HashMap<u64, HashSet<u64>>) -> Vec<KeywordNode> {
+    let mut builder = TreeBuilder {
+        keywords,
+        children: HashMap::new(),
+        keyword_images,
+        visited: HashSet::new(),
+    };
+    // roots hang off a made up id 0, which id_local never is
+    for (id, keyword) in keywords {
+        let parent = keyword.parent.filter(|parent| keywords.contains_key(parent)).unwrap_or(0);
+        builder.children.entry(parent).or_default().push(*id);
+    }
+    for siblings in builder.children.values_mut() {
+        siblings.sort();
+    }
+    let tree = builder.build_children(0).into_iter().map(|(node, _)| node).collect();
+    if builder.visited.len() < keywords.len() {
+        warn!("{} keywords are in a loop of parents, and left out", keywords.len() - builder.visited.len());
+    }
+    return tree;
+}
+
+/// The catalog's keyword hierarchy with how many images are under each keyword,
+/// and each image's keywords.
+pub async fn load_catalog_keywords(cat_path: &str) -> Result<CatalogKeywords, CatalogError> {
+    let mut db = catalog_db::connect_read_only(cat_path).await?;
+    for (table, columns) in REQUIRED_COLUMNS {
+        let found = catalog_db::table_columns(&mut db, cat_path, table).await?;
+        catalog_db::require_columns(&found, cat_path, table, columns)?;
+    }
+    let database_error = |source| CatalogError::Database { path: cat_path.to_owned(), source };
+
+    let mut keywords = HashMap::new();
+    for row in sqlx::query(KEYWORDS_QUERY).fetch_all(&mut db).await.map_err(database_error)? {
+        let id = row.try_get::<i64, _>("id").map_err(database_error)? as u64;
+        let name = row
+            .try_get::<Option<String>, _>("name")
+            .map_err(database_error)?
+            .filter(|name| !name.trim().is_empty());
+        let parent = row.try_get::<Option<i64>, _>("parent").map_err(database_error)?.map(|parent| parent as u64);
+        keywords.insert(id, KeywordRow { name, parent });
+    }
+
+    let mut keyword_images: HashMap<u64, HashSet<u64>> = HashMap::new();
+    let mut image_keywords: HashMap<u64, Vec<String>> = HashMap::new();
+    for row in sqlx::query(KEYWORD_IMAGES_QUERY).fetch_all(&mut db).await.map_err(database_error)? {
+        let image = row.try_get::<Option<i64>, _>("image").map_err(database_error)?;
+        let tag = row.try_get::<Option<i64>, _>("tag").map_err(database_error)?;
+        if image.is_none() || tag.is_none() {
+            continue;
+        }
+        let (image, tag) = (image.unwrap() as u64, tag.unwrap() as u64);
+        let name = keywords.get(&tag).and_then(|keyword| keyword.name.clone());
+        if name.is_none() {
+            continue;
+        }
+        keyword_images.entry(tag).or_default().insert(image);
+        image_keywords.entry(image).or_default().push(name.unwrap());
+    }
+    for names in image_keywords.values_mut() {
+        names.sort();
+        names.dedup();
+    }
+
+    let tree = keyword_tree(&keywords, &keyword_images);
+    info!("read {} keywords on {} images from {}", keywords.len(), image_keywords.len(), cat_path);
+    return Ok(CatalogKeywords { tree, image_keywords });
+}
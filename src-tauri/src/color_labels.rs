@@ -0,0 +1,160 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use crate::lr_discovery::{self, PreferencesOrigin};
+use crate::lua_table::{self, LuaTable};
+
+// an image's colour label is stored as the name the label set of the day gave it, e.g. "Red"
+// or "Approved", so which colour it is depends on the set. Sets are numbered in this order,
+// and templates key their names by the colours in lowercase.
+pub const LABEL_COLORS : [&str; 5] = ["Red", "Yellow", "Green", "Blue", "Purple"];
+// a label no colour in the set is named, which lightroom shows as white
+pub const CUSTOM_LABEL_COLOR : &str = "Custom";
+pub const NO_LABEL_COLOR : &str = "None";
+
+// the sets lightroom ships with, the first being what catalogs start with
+const BUILT_IN_LABEL_SETS : [(&str, [&str; 5]); 3] = [
+    ("Lightroom Default", ["Red", "Yellow", "Green", "Blue", "Purple"]),
+    ("Bridge Default", ["Select", "Second", "Approved", "Review", "To Do"]),
+    ("Review Status", ["To Delete", "Color Correction Needed", "Good to Use", "Retouching Needed", "To Print"]),
+];
+
+const LABEL_SETS_FOLDER : &str = "Color Label Sets";
+// where presets are kept when lightroom is set to store them with the catalog
+const CATALOG_SETTINGS_FOLDER : &str = "Lightroom Settings";
+// relative to a macOS home directory, as the preferences are kept apart from the presets there
+const MACOS_SETTINGS_RELPATH : &str = "Library/Application Support/Adobe/Lightroom";
+
+#[derive(Clone, Debug)]
+pub struct LabelSet {
+    pub name: String,
+    // in the order of LABEL_COLORS
+    pub labels: [String; 5],
+}
+
+impl LabelSet {
+    /// The colour the set gives a label, NO_LABEL_COLOR for no label at all.
+    pub fn color_for(&self, label: &str) -> &'static str {
+        let label = label.trim();
+        if label.is_empty() {
+            return NO_LABEL_COLOR;
+        }
+        return self.labels
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(label))
+            .map(|index| LABEL_COLORS[index])
+            .unwrap_or(CUSTOM_LABEL_COLOR);
+    }
+
+    fn covers(&self, label: &str) -> bool {
+        return !label.trim().is_empty() && self.color_for(label) != CUSTOM_LABEL_COLOR;
+    }
+}
+
+// a .lrtemplate, e.g.
+//   s = {
+//       title = "Studio",
+//       type = "Color Label Set",
+//       value = {
+//           red = "Reject",
+//           ...
+//       },
+//   }
+fn label_set_from_template(contents: &str) -> Option<LabelSet> {
+    let assignments = lua_table::parse_assignments(contents).ok()?;
+    let template = assignments.first()?.1.as_table()?;
+    let values: &LuaTable = template.get("value")?.as_table()?;
+    let name = template
+        .get("title")
+        .or_else(|| template.get("internalName"))
+        .and_then(|name| name.as_str())?
+        .to_owned();
+    let labels = LABEL_COLORS.map(|color| {
+        return values
+            .get(&color.to_lowercase())
+            .and_then(|label| label.as_str())
+            .unwrap_or("")
+            .to_owned();
+    });
+    if labels.iter().all(|label| label.is_empty()) {
+        return None;
+    }
+    return Some(LabelSet { name, labels });
+}
+
+// beside the catalog, then beside each install's preferences
+fn label_set_folders(cat_path: &Path) -> Vec<PathBuf> {
+    let mut folders = Vec::new();
+    if let Some(catalog_dir) = cat_path.parent() {
+        folders.push(catalog_dir.join(CATALOG_SETTINGS_FOLDER).join(LABEL_SETS_FOLDER));
+    }
+    for candidate in lr_discovery::find_preferences_files() {
+        // the preferences are in a Preferences folder beside the presets' folders
+        let settings_dir = candidate.path.parent().and_then(|preferences_dir| preferences_dir.parent());
+        if settings_dir.is_some() {
+            folders.extend(lr_discovery::resolve_case_insensitive(settings_dir.unwrap(), &[LABEL_SETS_FOLDER]));
+        }
+        if let PreferencesOrigin::MacOs { home } = &candidate.origin {
+            folders.push(home.join(MACOS_SETTINGS_RELPATH).join(LABEL_SETS_FOLDER));
+        }
+    }
+    let mut unique: Vec<PathBuf> = Vec::new();
+    for folder in folders {
+        if folder.is_dir() && !unique.contains(&folder) {
+            unique.push(folder);
+        }
+    }
+    return unique;
+}
+
+/// Every label set the catalog could be using: lightroom's own, and any the user has made.
+pub fn known_label_sets(cat_path: &Path) -> Vec<LabelSet> {
+    let mut sets: Vec<LabelSet> = BUILT_IN_LABEL_SETS
+        .iter()
+        .map(|(name, labels)| LabelSet {
+            name: name.to_string(),
+            labels: labels.map(|label| label.to_owned()),
+        })
+        .collect();
+    for folder in label_set_folders(cat_path) {
+        let entries = match fs::read_dir(&folder) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("failed to read {}: {}", folder.display(), err);
+                continue;
+            }
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let is_template = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("lrtemplate"));
+            if !is_template {
+                continue;
+            }
+            let label_set = fs::read(&path)
+                .ok()
+                .and_then(|contents| label_set_from_template(&String::from_utf8_lossy(&contents)));
+            match label_set {
+                Some(label_set) => sets.push(label_set),
+                None => warn!("{} is not a label set we can read", path.display()),
+            }
+        }
+    }
+    return sets;
+}
+
+/// The set that names the most of the catalog's labels, which is the one it's been labelled
+/// with. Lightroom's default wins a tie, as it's what every catalog starts with.
+pub fn choose_label_set<'a>(sets: &'a [LabelSet], labels: &HashSet<String>) -> &'a LabelSet {
+    let mut chosen = &sets[0];
+    let mut chosen_covers = 0;
+    for set in sets {
+        let covers = labels.iter().filter(|label| set.covers(label)).count();
+        if covers > chosen_covers {
+            chosen = set;
+            chosen_covers = covers;
+        }
+    }
+    info!("labels are read with the {:?} label set, which names {} of {} labels", chosen.name, chosen_covers, labels.len());
+    return chosen;
+}
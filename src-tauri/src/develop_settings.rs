@@ -0,0 +1,335 @@
+use std::collections::{BTreeMap, HashMap};
+use futures::TryStreamExt;
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use crate::catalog_db::{self, CatalogError};
+use crate::image_data::ImageMetadataFields;
+use crate::lua_table::{self, LuaParseError, LuaTable};
+
+// each image's current develop settings, as a lua table, e.g.
+//   s = {
+//       CameraProfile = "Adobe Standard",
+//       Contrast2012 = 12,
+//       CropAngle = 0,
+//       Exposure2012 = 0.35,
+//       Temperature = 5350,
+//       WhiteBalance = "Custom",
+//       ...
+//   }
+const DEVELOP_SETTINGS_QUERY : &str = "
+    select
+        cast(image as integer) as image,
+        cast(text as text) as text
+    from Adobe_imageDevelopSettings";
+
+const REQUIRED_COLUMNS : [(&str, &[&str]); 1] = [
+    ("Adobe_imageDevelopSettings", &["image", "text"]),
+];
+// how many images' settings are read before they're parsed, enough to keep every core busy
+// without holding a large catalog's worth of text
+const PARSE_BATCH_ROWS : usize = 1024;
+
+// the basic panel's sliders, under their process version 2012 (and later) names, all of which
+// sit at 0 until they're moved. Images still on an older process version have differently
+// named sliders with other defaults, so are left without these.
+const SLIDERS : [(&str, &str); 11] = [
+    ("exposure", "Exposure2012"),
+    ("contrast", "Contrast2012"),
+    ("highlights", "Highlights2012"),
+    ("shadows", "Shadows2012"),
+    ("whites", "Whites2012"),
+    ("blacks", "Blacks2012"),
+    ("texture", "Texture"),
+    ("clarity", "Clarity2012"),
+    ("dehaze", "Dehaze"),
+    ("vibrance", "Vibrance"),
+    ("saturation", "Saturation"),
+];
+
+// sliders are stored to a few decimal places, anything closer to 0 hasn't been moved
+const UNTOUCHED_TOLERANCE : f64 = 1e-6;
+const AS_SHOT_WHITE_BALANCE : &str = "As Shot";
+
+#[derive(Debug, thiserror::Error)]
+pub enum DevelopSettingsError {
+    #[error("failed to parse develop settings")]
+    Parse(#[from] LuaParseError),
+    #[error("develop settings are not a table")]
+    NotATable,
+}
+
+/// The crop as fractions of the image, with the angle it's straightened by in degrees.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Crop {
+    pub top: f64,
+    pub left: f64,
+    pub bottom: f64,
+    pub right: f64,
+    pub angle: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DevelopSettings {
+    pub process_version: Option<String>,
+    // the slider values by their names in SLIDERS, for the sliders the image has
+    pub sliders: BTreeMap<String, f64>,
+    pub white_balance: Option<String>,
+    // in kelvin and lightroom's tint units, which only raw files have. Other files are adjusted
+    // relative to how they were shot instead, which we leave alone
+    pub temperature: Option<f64>,
+    pub tint: Option<f64>,
+    // the look's name where there is one, as that's what lightroom shows, e.g. "Adobe Color"
+    pub profile: Option<String>,
+    // None when the image isn't cropped or straightened
+    pub crop: Option<Crop>,
+}
+
+impl DevelopSettings {
+    /// The sliders that have been moved from 0.
+    pub fn touched_sliders(&self) -> impl Iterator<Item = (&String, &f64)> {
+        return self.sliders.iter().filter(|(_, value)| value.abs() > UNTOUCHED_TOLERANCE);
+    }
+
+    pub fn white_balance_changed(&self) -> bool {
+        return self.white_balance.as_deref().is_some_and(|white_balance| white_balance != AS_SHOT_WHITE_BALANCE);
+    }
+
+    pub fn is_edited(&self) -> bool {
+        return self.touched_sliders().next().is_some() || self.white_balance_changed() || self.crop.is_some();
+    }
+}
+
+fn crop_from_settings(settings: &LuaTable) -> Option<Crop> {
+    let number = |key: &str, default: f64| settings.get(key).and_then(|value| value.as_f64()).unwrap_or(default);
+    let crop = Crop {
+        top: number("CropTop", 0.0),
+        left: number("CropLeft", 0.0),
+        bottom: number("CropBottom", 1.0),
+        right: number("CropRight", 1.0),
+        angle: number("CropAngle", 0.0),
+    };
+    let has_crop = settings.get("HasCrop").and_then(|value| value.as_bool()).unwrap_or(false);
+    let is_full_frame = crop.top.abs() < UNTOUCHED_TOLERANCE
+        && crop.left.abs() < UNTOUCHED_TOLERANCE
+        && (crop.bottom - 1.0).abs() < UNTOUCHED_TOLERANCE
+        && (crop.right - 1.0).abs() < UNTOUCHED_TOLERANCE
+        && crop.angle.abs() < UNTOUCHED_TOLERANCE;
+    if !has_crop && is_full_frame {
+        return None;
+    }
+    return Some(crop);
+}
+
+/// Parse the develop settings lightroom keeps for an image.
+pub fn parse_develop_settings(text: &str) -> Result<DevelopSettings, DevelopSettingsError> {
+    let value = match lua_table::parse_assignments(text) {
+        Ok(assignments) => assignments.into_iter().next().map(|(_, value)| value),
+        Err(_) => Some(lua_table::parse_value(text)?),
+    };
+    let settings = value.as_ref().and_then(|value| value.as_table()).ok_or(DevelopSettingsError::NotATable)?;
+    let text = |key: &str| -> Option<String> {
+        return settings
+            .get(key)
+            .and_then(|value| value.as_str())
+            .filter(|text| !text.is_empty())
+            .map(|text| text.to_owned());
+    };
+    let number = |key: &str| settings.get(key).and_then(|value| value.as_f64());
+
+    let sliders = SLIDERS
+        .iter()
+        .filter_map(|(name, key)| number(key).map(|value| (name.to_string(), value)))
+        .collect();
+    let look = settings
+        .get("Look")
+        .and_then(|look| look.as_table())
+        .and_then(|look| look.get("Name"))
+        .and_then(|name| name.as_str())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_owned());
+    return Ok(DevelopSettings {
+        process_version: text("ProcessVersion"),
+        sliders,
+        white_balance: text("WhiteBalance"),
+        temperature: number("Temperature"),
+        tint: number("Tint"),
+        profile: look.or_else(|| text("CameraProfile")),
+        crop: crop_from_settings(settings),
+    });
+}
+
+// parse a batch of (image, text) rows into image_settings, counting those that can't be read.
+// The tables are large, with tone curves and the like, so they're parsed in parallel.
+fn parse_batch(batch: &mut Vec<(u64, String)>, image_settings: &mut HashMap<u64, DevelopSettings>, unreadable: &mut usize) {
+    let parsed: Vec<(u64, Result<DevelopSettings, DevelopSettingsError>)> = batch
+        .par_iter()
+        .map(|(image, text)| (*image, parse_develop_settings(text)))
+        .collect();
+    batch.clear();
+    for (image, settings) in parsed {
+        match settings {
+            Ok(settings) => {
+                image_settings.insert(image, settings);
+            }
+            Err(err) => {
+                if *unreadable == 0 {
+                    warn!("develop settings for image {} can't be read: {:#}", image, anyhow::Error::from(err));
+                }
+                *unreadable += 1;
+            }
+        }
+    }
+}
+
+/// Every image's develop settings. Images whose settings can't be parsed are left out.
+pub async fn load_develop_settings(cat_path: &str) -> Result<HashMap<u64, DevelopSettings>, CatalogError> {
+    let mut db = catalog_db::connect_read_only(cat_path).await?;
+    for (table, columns) in REQUIRED_COLUMNS {
+        let found = catalog_db::table_columns(&mut db, cat_path, table).await?;
+        catalog_db::require_columns(&found, cat_path, table, columns)?;
+    }
+    let database_error = |source| CatalogError::Database { path: cat_path.to_owned(), source };
+    let mut image_settings = HashMap::new();
+    let mut unreadable = 0;
+    // streamed, so only a batch of the settings text is held at once
+    let mut rows = sqlx::query(DEVELOP_SETTINGS_QUERY).fetch(&mut db);
+    let mut batch = Vec::with_capacity(PARSE_BATCH_ROWS);
+    while let Some(row) = rows.try_next().await.map_err(database_error)? {
+        let image = row.try_get::<Option<i64>, _>("image").map_err(database_error)?;
+        let text = row.try_get::<Option<String>, _>("text").map_err(database_error)?;
+        if image.is_none() || text.is_none() {
+            continue;
+        }
+        batch.push((image.unwrap() as u64, text.unwrap()));
+        if batch.len() == PARSE_BATCH_ROWS {
+            parse_batch(&mut batch, &mut image_settings, &mut unreadable);
+        }
+    }
+    parse_batch(&mut batch, &mut image_settings, &mut unreadable);
+    if unreadable > 1 {
+        warn!("develop settings for {} images can't be read", unreadable);
+    }
+    info!("read develop settings for {} images from {}", image_settings.len(), cat_path);
+    return Ok(image_settings);
+}
+
+/// How often a slider is moved, and how far.
+#[derive(Clone, Debug, Serialize)]
+pub struct SliderUsage {
+    pub slider: String,
+    pub images_touched: usize,
+    // of the images with develop settings
+    pub share_touched: f64,
+    // over the images it was moved for
+    pub mean: f64,
+    pub median: f64,
+    pub mean_magnitude: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ProfileUsage {
+    pub profile: String,
+    pub images: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EditSummary {
+    // images with develop settings
+    pub images: usize,
+    pub edited: usize,
+    pub cropped: usize,
+    pub white_balance_changed: usize,
+    // the most touched first
+    pub sliders: Vec<SliderUsage>,
+    // the most used first
+    pub profiles: Vec<ProfileUsage>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CameraEdits {
+    pub camera: String,
+    pub edits: EditSummary,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EditReport {
+    pub all: EditSummary,
+    // the camera with the most images first
+    pub by_camera: Vec<CameraEdits>,
+    pub images_without_settings: usize,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        return (values[middle - 1] + values[middle]) / 2.0;
+    }
+    return values[middle];
+}
+
+fn summarise(settings: &[&DevelopSettings]) -> EditSummary {
+    let mut slider_values: HashMap<&String, Vec<f64>> = HashMap::new();
+    let mut profiles: HashMap<&str, usize> = HashMap::new();
+    for image_settings in settings {
+        for (slider, value) in image_settings.touched_sliders() {
+            slider_values.entry(slider).or_default().push(*value);
+        }
+        if image_settings.profile.is_some() {
+            *profiles.entry(image_settings.profile.as_deref().unwrap()).or_default() += 1;
+        }
+    }
+
+    let mut sliders: Vec<SliderUsage> = slider_values
+        .into_iter()
+        .map(|(slider, mut values)| SliderUsage {
+            slider: slider.clone(),
+            images_touched: values.len(),
+            share_touched: values.len() as f64 / settings.len() as f64,
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            mean_magnitude: values.iter().map(|value| value.abs()).sum::<f64>() / values.len() as f64,
+            median: median(&mut values),
+        })
+        .collect();
+    sliders.sort_by(|a, b| b.images_touched.cmp(&a.images_touched).then_with(|| a.slider.cmp(&b.slider)));
+    let mut profiles: Vec<ProfileUsage> = profiles
+        .into_iter()
+        .map(|(profile, images)| ProfileUsage { profile: profile.to_owned(), images })
+        .collect();
+    profiles.sort_by(|a, b| b.images.cmp(&a.images).then_with(|| a.profile.cmp(&b.profile)));
+
+    return EditSummary {
+        images: settings.len(),
+        edited: settings.iter().filter(|image_settings| image_settings.is_edited()).count(),
+        cropped: settings.iter().filter(|image_settings| image_settings.crop.is_some()).count(),
+        white_balance_changed: settings.iter().filter(|image_settings| image_settings.white_balance_changed()).count(),
+        sliders,
+        profiles,
+    };
+}
+
+/// How the images have been edited, overall and for each camera.
+pub fn edit_report(images: &[ImageMetadataFields]) -> EditReport {
+    let with_settings: Vec<(&ImageMetadataFields, &DevelopSettings)> = images
+        .iter()
+        .filter_map(|image| image.develop.as_ref().map(|settings| (image, settings)))
+        .collect();
+    let mut by_camera: HashMap<&str, Vec<&DevelopSettings>> = HashMap::new();
+    for (image, settings) in &with_settings {
+        by_camera.entry(image.model.as_deref().unwrap_or("Unknown")).or_default().push(settings);
+    }
+    let mut by_camera: Vec<CameraEdits> = by_camera
+        .into_iter()
+        .map(|(camera, settings)| CameraEdits { camera: camera.to_owned(), edits: summarise(&settings) })
+        .collect();
+    by_camera.sort_by(|a, b| b.edits.images.cmp(&a.edits.images).then_with(|| a.camera.cmp(&b.camera)));
+
+    let all: Vec<&DevelopSettings> = with_settings.iter().map(|(_, settings)| *settings).collect();
+    return EditReport {
+        all: summarise(&all),
+        by_camera,
+        images_without_settings: images.len() - with_settings.len(),
+    };
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A file in a DiskCache, with its size and when it was last used.
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub length: u64,
+    pub used: SystemTime,
+}
+
+/// A directory of cached files kept within a size limit. A file's modification time is bumped
+/// whenever it's read, so the least recently used are the first to go once it's over the limit.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> DiskCache {
+        return DiskCache { dir, max_bytes };
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        return self.max_bytes;
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        return self.dir.join(name);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        return self.path(name).is_file();
+    }
+
+    /// The cached file's contents, marking it as recently used.
+    pub fn read(&self, name: &str) -> Option<Vec<u8>> {
+        let path = self.path(name);
+        let data = fs::read(&path).ok()?;
+        // failing this it's just evicted a little early
+        let _ = fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()));
+        return Some(data);
+    }
+
+    pub fn write(&self, name: &str, data: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        return fs::write(self.path(name), data);
+    }
+
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let read = fs::read_dir(&self.dir);
+        if read.is_err() {
+            return Vec::new();
+        }
+        return read
+            .unwrap()
+            .flatten()
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                if !metadata.is_file() {
+                    return None;
+                }
+                let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                return Some(CacheEntry { path: entry.path(), length: metadata.len(), used });
+            })
+            .collect();
+    }
+
+    /// Remove the least recently used files until the cache is within its limit, returning how
+    /// many were removed and how many bytes are left.
+    pub fn evict(&self) -> (usize, u64) {
+        let mut entries = self.entries();
+        let mut total: u64 = entries.iter().map(|entry| entry.length).sum();
+        if total <= self.max_bytes {
+            return (0, total);
+        }
+        entries.sort_by_key(|entry| entry.used);
+        let mut removed = 0;
+        for entry in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            if fs::remove_file(&entry.path).is_ok() {
+                total -= entry.length;
+                removed += 1;
+            }
+        }
+        return (removed, total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn set_used(cache: &DiskCache, name: &str, seconds_ago: u64) {
+        let file = fs::File::options().append(true).open(cache.path(name)).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(seconds_ago)).unwrap();
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("reflex-disk-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = DiskCache::new(dir.clone(), 25);
+        for (name, seconds_ago) in [("a", 30), ("b", 20), ("c", 10)] {
+            cache.write(name, &[0; 10]).unwrap();
+            set_used(&cache, name, seconds_ago);
+        }
+        // reading the oldest makes it the most recently used
+        assert_eq!(cache.read("a"), Some(vec![0; 10]));
+        assert_eq!(cache.evict(), (1, 20));
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+        assert_eq!(cache.evict(), (0, 20));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
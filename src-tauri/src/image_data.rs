@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::develop_settings::DevelopSettings;
 /*
 use tauri_plugin_sql::{Migration, MigrationKind};
 
@@ -28,6 +29,8 @@ pub const M1 : Migration = Migration {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ImageMetadataFields
 {
+    // the catalog's id_local for lightroom images, folder images don't have one yet
+    pub id: Option<u64>,
     pub folder: Option<String>,
     pub filename: String,
     pub datetime_original: Option<String>,
@@ -40,7 +43,26 @@ pub struct ImageMetadataFields
     pub exposure_program: Option<u16>,
     pub metering_mode: Option<u16>,
     pub flash: Option<u16>,
-    pub embedded_rating: Option<i16>
+    pub embedded_rating: Option<i16>,
+    // lightroom's flag, 1 for picked, 0 for unflagged and -1 for rejected
+    pub pick: Option<i8>,
+    // the label as the catalog stores it, and the colour the catalog's label set gives it
+    pub color_label: Option<String>,
+    pub label_color: Option<String>,
+    // lightroom's name for the kind of file, e.g. "RAW", "JPG" or "VIDEO"
+    pub file_format: Option<String>,
+    // when the image was last edited in lightroom
+    pub touch_time: Option<String>,
+    pub has_gps: Option<bool>,
+    // width and height, of the file and as cropped in lightroom, only known for lightroom images
+    pub file_dimensions: Option<(u32,u32)>,
+    pub cropped_dimensions: Option<(u32,u32)>,
+    // the names of the image's lightroom keywords
+    pub keywords: Option<Vec<String>>,
+    // the ids of the lightroom collections the image is in, and of the sets above them
+    pub collections: Option<Vec<u64>>,
+    // the image's develop settings in lightroom
+    pub develop: Option<DevelopSettings>
 }
 
 /*
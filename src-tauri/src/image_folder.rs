@@ -330,6 +330,7 @@ pub fn make_image_data_from_exif(folder: Option<String>, filename: String, exif_
         }
     }
     return image_data::ImageMetadataFields {
+        id: None,
         folder,
         filename,
         datetime_original,
@@ -342,6 +343,17 @@ pub fn make_image_data_from_exif(folder: Option<String>, filename: String, exif_
         exposure_program,
         metering_mode,
         flash,
-        embedded_rating
+        embedded_rating,
+        pick: None,
+        color_label: None,
+        label_color: None,
+        file_format: None,
+        touch_time: None,
+        has_gps: None,
+        file_dimensions: None,
+        cropped_dimensions: None,
+        keywords: None,
+        collections: None,
+        develop: None
     };
 }
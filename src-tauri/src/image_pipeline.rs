@@ -0,0 +1,429 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageFormat, ImageReader, Rgba};
+use serde::{Serialize, Serializer};
+use crate::{psd, tiff};
+
+// a level more than this much larger than the request is worth shrinking ourselves,
+// rather than sending the webview pixels it'll throw away
+const MAX_OVERSIZE_RATIO : f64 = 1.5;
+const JPEG_QUALITY : u8 = 85;
+
+// rows of the sRGB (D65) to XYZ matrix, for developing raw data
+pub const SRGB_TO_XYZ : [[f64; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+// fine enough steps that the curve's quantisation doesn't show through the 8 bit output
+const SRGB_LUT_SIZE : usize = 4096;
+// formats every webview we run in shows as they are, anything else is transcoded
+const WEBVIEW_FORMATS : [ImageFormat; 4] = [ImageFormat::Jpeg, ImageFormat::Gif, ImageFormat::WebP, ImageFormat::Bmp];
+// the chunks of a png follow its 8 byte signature, each a u32 length, a 4 byte type, the data
+// and a 4 byte crc
+const PNG_SIGNATURE_LENGTH : usize = 8;
+const PNG_CHUNK_OVERHEAD : usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error("failed to decode image")]
+    Decode(#[source] image::ImageError),
+    #[error("failed to encode image")]
+    Encode(#[source] image::ImageError),
+    #[error("failed to decode PSD")]
+    Psd(#[source] psd::PsdError),
+}
+
+fn serialize_base64<S>(data: &Vec<u8>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(data))
+}
+
+/// Where a served image came from, so the UI can say when it's not a full quality preview.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PreviewSource {
+    // a level of a standard lrprev preview
+    LightroomPreview,
+    // a DNG from "<CatalogName> Smart Previews.lrdata"
+    SmartPreview,
+    // the image file itself
+    OriginalFile,
+    // a raw original, developed by us as lightroom had no preview of it
+    DecodedRaw,
+    // a camera rendered jpeg from inside a raw file
+    EmbeddedPreview,
+}
+
+/// An image ready to send to the webview, along with what it actually is.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServedImage {
+    // None when we're passing along bytes we couldn't read
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub mime_type: String,
+    pub source: PreviewSource,
+    #[serde(serialize_with = "serialize_base64")]
+    pub data: Vec<u8>,
+}
+
+/// The size a caller wants, either a maximum long edge in pixels,
+/// or one end of whatever's available.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestedSize {
+    Smallest,
+    Largest,
+    MaxDimension(u32),
+}
+
+impl RequestedSize {
+    pub fn from_args(mode: Option<&str>, max_dimension: Option<u32>) -> Option<RequestedSize> {
+        if let Some(max_dimension) = max_dimension {
+            return Some(RequestedSize::MaxDimension(max_dimension.max(1)));
+        }
+        return match mode {
+            Some("lo") => Some(RequestedSize::Smallest),
+            Some("hi") => Some(RequestedSize::Largest),
+            _ => None,
+        };
+    }
+}
+
+/// Lightroom's orientation codes name where the stored image's top-left and top-right
+/// corners end up, taking the displayed corners as A (top-left), B, C, D clockwise.
+/// So "AB" is as stored, "BC" is rotated 90 degrees clockwise, and "BA" is mirrored.
+pub fn orientation_from_lightroom(code: &str) -> Option<Orientation> {
+    return match code.trim().to_ascii_uppercase().as_str() {
+        "AB" => Some(Orientation::NoTransforms),
+        "BC" => Some(Orientation::Rotate90),
+        "CD" => Some(Orientation::Rotate180),
+        "DA" => Some(Orientation::Rotate270),
+        "BA" => Some(Orientation::FlipHorizontal),
+        "DC" => Some(Orientation::FlipVertical),
+        // transposed, exif 5
+        "AD" => Some(Orientation::Rotate90FlipH),
+        // transversed, exif 7
+        "CB" => Some(Orientation::Rotate270FlipH),
+        _ => None,
+    };
+}
+
+fn srgb_gamma(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        return linear * 12.92;
+    }
+    return 1.055 * linear.powf(1.0 / 2.4) - 0.055;
+}
+
+static SRGB_LUT: std::sync::LazyLock<Vec<u8>> = std::sync::LazyLock::new(|| {
+    return (0..SRGB_LUT_SIZE)
+        .map(|i| (srgb_gamma(i as f64 / (SRGB_LUT_SIZE - 1) as f64) * 255.0).round() as u8)
+        .collect();
+});
+
+/// Linear light in 0..=1 to an 8 bit sRGB value.
+pub fn srgb_encode(linear: f64) -> u8 {
+    return SRGB_LUT[(linear.clamp(0.0, 1.0) * (SRGB_LUT_SIZE - 1) as f64) as usize];
+}
+
+pub fn mime_type_for(format: ImageFormat) -> String {
+    return format.to_mime_type().to_owned();
+}
+
+/// What encoded image bytes are, from their magic bytes rather than the file's extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceFormat {
+    // anything the image crate reads
+    Image(ImageFormat),
+    Psd,
+}
+
+pub fn detect_format(bytes: &[u8]) -> Option<SourceFormat> {
+    if psd::is_psd(bytes) {
+        return Some(SourceFormat::Psd);
+    }
+    return image::guess_format(bytes).ok().map(SourceFormat::Image);
+}
+
+// a png's bit depth is the first byte after its IHDR chunk's width and height
+fn png_bit_depth(bytes: &[u8]) -> Option<u8> {
+    if bytes.len() < 25 || &bytes[12..16] != b"IHDR" {
+        return None;
+    }
+    return Some(bytes[24]);
+}
+
+// sBIT holds the significant bits of each channel, as many of them as the colour type has,
+// and has to come before the image data
+fn png_significant_bits(bytes: &[u8]) -> Option<u8> {
+    let color_type = *bytes.get(25)?;
+    // greyscale, with or without alpha, has one colour channel, everything else three
+    let color_channels = if color_type == 0 || color_type == 4 { 1 } else { 3 };
+    let mut offset = PNG_SIGNATURE_LENGTH;
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_be_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let chunk_type = &bytes[offset + 4..offset + 8];
+        if chunk_type == b"IDAT" || chunk_type == b"IEND" {
+            return None;
+        }
+        if chunk_type == b"sBIT" {
+            let data = bytes.get(offset + 8..(offset + 8).checked_add(length)?)?;
+            return data.get(..color_channels)?.iter().copied().max();
+        }
+        offset = offset.checked_add(length)?.checked_add(PNG_CHUNK_OVERHEAD)?;
+    }
+    return None;
+}
+
+fn tiff_significant_bits(bytes: &[u8]) -> Option<u8> {
+    let tiff = tiff::parse_tiff(bytes).ok()?;
+    let bits = tiff.ifds.first()?.get_u32s(tiff::TAG_BITS_PER_SAMPLE).into_iter().max()?;
+    return u8::try_from(bits).ok();
+}
+
+/// How many bits of each sample hold data, when the file says. Scanners and some cameras
+/// write 10 or 12 bits into a 16 bit file, which shows as near black unless it's scaled up.
+pub fn significant_bits(bytes: &[u8], format: ImageFormat) -> Option<u8> {
+    return match format {
+        ImageFormat::Png => png_significant_bits(bytes),
+        ImageFormat::Tiff => tiff_significant_bits(bytes),
+        _ => None,
+    };
+}
+
+/// Can the webview show these bytes as they are? 16 bit pngs are transcoded along with
+/// everything the webview can't read at all, as they're twice the size for no visible gain.
+pub fn is_webview_safe(bytes: &[u8], format: ImageFormat) -> bool {
+    if format == ImageFormat::Png {
+        return png_bit_depth(bytes).is_some_and(|depth| depth <= 8);
+    }
+    return WEBVIEW_FORMATS.contains(&format);
+}
+
+/// The orientation recorded in the image's own exif, if we can read it.
+pub fn embedded_orientation(bytes: &[u8]) -> Orientation {
+    let decoder = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_decoder().ok());
+    if decoder.is_none() {
+        return Orientation::NoTransforms;
+    }
+    return decoder.unwrap().orientation().unwrap_or(Orientation::NoTransforms);
+}
+
+/// Dimensions and format from the image's header, without decoding the pixels.
+pub fn probe(bytes: &[u8]) -> Option<(u32, u32, ImageFormat)> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().ok()?;
+    let format = reader.format()?;
+    let (width, height) = reader.into_dimensions().ok()?;
+    return Some((width, height, format));
+}
+
+/// Decode, applying any orientation from the image's own exif, which re-encoding would lose.
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage, PipelineError> {
+    if psd::is_psd(bytes) {
+        return psd::decode_psd(bytes).map_err(PipelineError::Psd);
+    }
+    let reader = ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|err| PipelineError::Decode(image::ImageError::IoError(err)))?;
+    let mut decoder = reader.into_decoder().map_err(PipelineError::Decode)?;
+    let embedded_orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(PipelineError::Decode)?;
+    image.apply_orientation(embedded_orientation);
+    return Ok(image);
+}
+
+fn to_8bit(sample: u16) -> u8 {
+    return ((sample as u32 * 255 + 32767) / 65535) as u8;
+}
+
+// data the file says is right aligned is shifted up to fill the 16 bits, anything else is
+// scaled as it is, however dark it looks
+fn tone_map_16bit(image: &ImageBuffer<Rgba<u16>, Vec<u16>>, significant_bits: Option<u8>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let shift = match significant_bits {
+        Some(bits) if (1..16).contains(&bits) => 16 - bits as u32,
+        _ => 0,
+    };
+    return ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let scale = |sample: u16| to_8bit(((sample as u32) << shift).min(u16::MAX as u32) as u16);
+        return Rgba([scale(r), scale(g), scale(b), to_8bit(a)]);
+    });
+}
+
+// floating point images are linear light, often brighter than 1.0. The extended reinhard
+// curve brings the brightest sample down to 1.0 and leaves the shadows much as they were.
+fn tone_map_float(image: &ImageBuffer<Rgba<f32>, Vec<f32>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let peak = image
+        .pixels()
+        .flat_map(|pixel| pixel.0[..3].iter().copied())
+        .filter(|sample| sample.is_finite())
+        .fold(0.0f32, f32::max) as f64;
+    let white_squared = peak * peak;
+    return ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        let map = |sample: f32| {
+            let linear = if sample.is_finite() { (sample as f64).max(0.0) } else { 0.0 };
+            if peak <= 1.0 {
+                return srgb_encode(linear);
+            }
+            return srgb_encode(linear * (1.0 + linear / white_squared) / (1.0 + linear));
+        };
+        return Rgba([map(r), map(g), map(b), (a.clamp(0.0, 1.0) * 255.0).round() as u8]);
+    });
+}
+
+/// Bring 16 bit and floating point images down to 8 bits for display, 8 bit images are
+/// left as they are. `significant_bits` is what the file declared, see `significant_bits`.
+pub fn tone_map_to_8bit(image: DynamicImage, significant_bits: Option<u8>) -> DynamicImage {
+    let has_alpha = image.color().has_alpha();
+    let mapped = match image {
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_)
+        | DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_) => tone_map_16bit(&image.to_rgba16(), significant_bits),
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => tone_map_float(&image.to_rgba32f()),
+        _ => return image,
+    };
+    if has_alpha {
+        return DynamicImage::ImageRgba8(mapped);
+    }
+    return DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(mapped).to_rgb8());
+}
+
+pub fn encode_png(image: &DynamicImage, source: PreviewSource) -> Result<ServedImage, PipelineError> {
+    let mut data = Vec::new();
+    let rgba = DynamicImage::ImageRgba8(image.to_rgba8());
+    rgba.write_to(&mut Cursor::new(&mut data), ImageFormat::Png).map_err(PipelineError::Encode)?;
+    return Ok(ServedImage {
+        width: Some(rgba.width()),
+        height: Some(rgba.height()),
+        mime_type: mime_type_for(ImageFormat::Png),
+        source,
+        data,
+    });
+}
+
+pub fn encode_jpeg(image: &DynamicImage, source: PreviewSource) -> Result<ServedImage, PipelineError> {
+    let mut data = Vec::new();
+    // jpeg has no alpha, and no more than 8 bits per channel
+    let rgb = DynamicImage::ImageRgb8(image.to_rgb8());
+    let encoder = JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY);
+    rgb.write_with_encoder(encoder).map_err(PipelineError::Encode)?;
+    return Ok(ServedImage {
+        width: Some(rgb.width()),
+        height: Some(rgb.height()),
+        mime_type: mime_type_for(ImageFormat::Jpeg),
+        source,
+        data,
+    });
+}
+
+// FNV-1a, as cache keys outlive the process and std's hashers may change between releases
+pub const FNV_OFFSET_BASIS : u64 = 0xcbf29ce484222325;
+const FNV_PRIME : u64 = 0x100000001b3;
+
+/// FNV-1a, continuing from `hash`, which unlike std's hashers gives the same value in every
+/// build, so it's fine for anything kept between runs.
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    return bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME));
+}
+
+/// A key for images made from a file, from its path, size and modification time,
+/// so an edited or replaced file is made afresh.
+pub fn file_cache_key(original: &Path) -> Option<String> {
+    let metadata = fs::metadata(original).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut hash = fnv1a(FNV_OFFSET_BASIS, original.as_os_str().as_encoded_bytes());
+    hash = fnv1a(hash, &metadata.len().to_le_bytes());
+    hash = fnv1a(hash, &modified.to_le_bytes());
+    return Some(format!("{:016x}", hash));
+}
+
+pub fn long_edge(width: u32, height: u32) -> u32 {
+    return width.max(height);
+}
+
+/// Is an image with this long edge too big to send as-is for the request?
+pub fn is_oversized(long_edge: u32, requested: RequestedSize) -> bool {
+    return match requested {
+        RequestedSize::MaxDimension(max_dimension) => {
+            long_edge as f64 > max_dimension as f64 * MAX_OVERSIZE_RATIO
+        }
+        _ => false,
+    };
+}
+
+pub fn downscale(image: &DynamicImage, max_dimension: u32) -> DynamicImage {
+    return image.resize(max_dimension, max_dimension, FilterType::Triangle);
+}
+
+/// Serve encoded bytes as they are if the webview can show them and they suit the request,
+/// otherwise decode, shrink to the requested size, apply `orientation` and re-encode.
+pub fn serve_bytes(bytes: Vec<u8>, requested: RequestedSize, orientation: Orientation, source: PreviewSource) -> Result<ServedImage, PipelineError> {
+    let (width, height, format) = match probe(&bytes) {
+        Some(probed) => probed,
+        None => return serve_image(decode(&bytes)?, requested, orientation, source),
+    };
+    // the webview honours a jpeg's exif orientation, but not reliably anything else's
+    let needs_orienting = orientation != Orientation::NoTransforms
+        || (format != ImageFormat::Jpeg && embedded_orientation(&bytes) != Orientation::NoTransforms);
+    if needs_orienting || !is_webview_safe(&bytes, format) || is_oversized(long_edge(width, height), requested) {
+        let significant_bits = significant_bits(&bytes, format);
+        return serve_decoded(decode(&bytes)?, significant_bits, requested, orientation, source);
+    }
+    return Ok(ServedImage {
+        width: Some(width),
+        height: Some(height),
+        mime_type: mime_type_for(format),
+        source,
+        data: bytes,
+    });
+}
+
+/// An image we've decoded ourselves, shrunk to the requested size, oriented, brought down to
+/// 8 bits and encoded as a jpeg, or a png if it has transparency to keep.
+pub fn serve_image(image: DynamicImage, requested: RequestedSize, orientation: Orientation, source: PreviewSource) -> Result<ServedImage, PipelineError> {
+    return serve_decoded(image, None, requested, orientation, source);
+}
+
+fn serve_decoded(image: DynamicImage, significant_bits: Option<u8>, requested: RequestedSize, orientation: Orientation, source: PreviewSource) -> Result<ServedImage, PipelineError> {
+    let mut image = image;
+    if let RequestedSize::MaxDimension(max_dimension) = requested {
+        if is_oversized(long_edge(image.width(), image.height()), requested) {
+            image = downscale(&image, max_dimension);
+        }
+    }
+    image.apply_orientation(orientation);
+    let image = tone_map_to_8bit(image, significant_bits);
+    if image.color().has_alpha() {
+        return encode_png(&image, source);
+    }
+    return encode_jpeg(&image, source);
+}
+
+/// Bytes in a format we can't read, sent as they are for the webview to try.
+pub fn passthrough(bytes: Vec<u8>, source: PreviewSource) -> ServedImage {
+    return ServedImage {
+        width: None,
+        height: None,
+        mime_type: "application/octet-stream".to_owned(),
+        source,
+        data: bytes,
+    };
+}
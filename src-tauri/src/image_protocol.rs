@@ -0,0 +1,221 @@
+use tauri::http::{header, HeaderMap, Response, StatusCode};
+use tauri::Url;
+use crate::image_pipeline::{fnv1a, RequestedSize, ServedImage, FNV_OFFSET_BASIS};
+
+/// Images are served as reflex://localhost/<preview|original>/<image type>/<image id>?path=...
+/// with either mode=hi|lo or max_dimension=<pixels>, the same arguments as get_image_for_id.
+/// On windows the webview sees the same thing as http://reflex.localhost/...
+pub const SCHEME : &str = "reflex";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("malformed image url {0}")]
+    MalformedUrl(String),
+    #[error("unknown image route {0}, expected /preview/<type>/<id> or /original/<type>/<id>")]
+    UnknownRoute(String),
+    #[error("{0} needs a path, and either mode ('hi' or 'lo') or max_dimension")]
+    MissingArguments(String),
+}
+
+/// What's wanted, lightroom's preview (or the nearest thing we have), or the original file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageRoute {
+    Preview,
+    Original,
+}
+
+impl ImageRoute {
+    fn name(&self) -> &'static str {
+        return match self {
+            ImageRoute::Preview => "preview",
+            ImageRoute::Original => "original",
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImageRequest {
+    pub route: ImageRoute,
+    pub image_type: String,
+    pub image_id: String,
+    pub image_path: String,
+    pub requested: RequestedSize,
+}
+
+pub fn parse_request(uri: &str) -> Result<ImageRequest, ProtocolError> {
+    let url = Url::parse(uri).map_err(|_| ProtocolError::MalformedUrl(uri.to_owned()))?;
+    // image types and ids are plain ascii, only the path (in the query) needs decoding
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| segments
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| segment.to_owned())
+            .collect())
+        .unwrap_or_default();
+    if segments.len() != 3 {
+        return Err(ProtocolError::UnknownRoute(url.path().to_owned()));
+    }
+    let route = match segments[0].as_str() {
+        "preview" => ImageRoute::Preview,
+        "original" => ImageRoute::Original,
+        _ => return Err(ProtocolError::UnknownRoute(url.path().to_owned())),
+    };
+
+    let mut image_path = None;
+    let mut mode = None;
+    let mut max_dimension = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "path" => image_path = Some(value.into_owned()),
+            "mode" => mode = Some(value.into_owned()),
+            "max_dimension" => max_dimension = value.parse::<u32>().ok(),
+            // anything else (e.g. a cache buster) is ignored
+            _ => {}
+        }
+    }
+    let requested = RequestedSize::from_args(mode.as_deref(), max_dimension);
+    if image_path.is_none() || requested.is_none() {
+        return Err(ProtocolError::MissingArguments(uri.to_owned()));
+    }
+    return Ok(ImageRequest {
+        route,
+        image_type: segments[1].clone(),
+        image_id: segments[2].clone(),
+        image_path: image_path.unwrap(),
+        requested: requested.unwrap(),
+    });
+}
+
+/// A strong etag for `version`, which is the lrprev digest or the file key of whatever the
+/// image is made from, along with everything else that changes what we'd send back. The webview
+/// keeps etags across app updates, so the hash has to stay the same between builds.
+pub fn etag(request: &ImageRequest, version: &str) -> String {
+    let requested = format!("{:?}", request.requested);
+    let fields = [version, request.route.name(), &request.image_type, &request.image_id, &requested];
+    let mut hash = FNV_OFFSET_BASIS;
+    for field in fields {
+        hash = fnv1a(hash, field.as_bytes());
+        // so moving a character from one field to the next changes the etag
+        hash = fnv1a(hash, &[0]);
+    }
+    return format!("\"{:016x}\"", hash);
+}
+
+/// Whether the webview's If-None-Match already names `etag`.
+pub fn is_not_modified(headers: &HeaderMap, etag: &str) -> bool {
+    return headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|candidate| candidate.trim().trim_start_matches("W/"))
+        .any(|candidate| candidate == "*" || candidate == etag);
+}
+
+fn builder(status: StatusCode, etag: Option<&str>) -> tauri::http::response::Builder {
+    let mut builder = Response::builder()
+        .status(status)
+        // the webview may keep the image, but has to check with us before using it again
+        .header(header::CACHE_CONTROL, "no-cache");
+    if let Some(etag) = etag {
+        builder = builder.header(header::ETAG, etag);
+    }
+    return builder;
+}
+
+pub fn image_response(served: ServedImage, etag: Option<&str>) -> Response<Vec<u8>> {
+    return builder(StatusCode::OK, etag)
+        .header(header::CONTENT_TYPE, served.mime_type)
+        .header(header::CONTENT_LENGTH, served.data.len())
+        .body(served.data)
+        .unwrap();
+}
+
+pub fn not_modified_response(etag: &str) -> Response<Vec<u8>> {
+    return builder(StatusCode::NOT_MODIFIED, Some(etag))
+        .body(Vec::new())
+        .unwrap();
+}
+
+pub fn error_response(status: StatusCode, message: &str) -> Response<Vec<u8>> {
+    return builder(status, None)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(message.as_bytes().to_vec())
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tauri::http::HeaderValue;
+
+    fn if_none_match(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(header::IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        }
+        return headers;
+    }
+
+    #[test]
+    fn parse_request_decodes_the_path() {
+        let request = parse_request("reflex://localhost/preview/lightroom/1234?path=C%3A%5CPhotos%5Cmy%20shot%2B1.cr2&mode=hi").unwrap();
+        assert_eq!(request.route, ImageRoute::Preview);
+        assert_eq!(request.image_type, "lightroom");
+        assert_eq!(request.image_id, "1234");
+        assert_eq!(request.image_path, "C:\\Photos\\my shot+1.cr2");
+        assert_eq!(request.requested, RequestedSize::Largest);
+
+        let request = parse_request("http://reflex.localhost/original/folder/0?path=%2Fa%2Fb.jpg&max_dimension=640&v=2").unwrap();
+        assert_eq!(request.route, ImageRoute::Original);
+        assert_eq!(request.image_path, "/a/b.jpg");
+        assert_eq!(request.requested, RequestedSize::MaxDimension(640));
+    }
+
+    #[test]
+    fn parse_request_needs_a_size() {
+        let err = parse_request("reflex://localhost/preview/folder/0?path=%2Fa.jpg").unwrap_err();
+        assert!(matches!(err, ProtocolError::MissingArguments(_)));
+        let err = parse_request("reflex://localhost/preview/folder/0?path=%2Fa.jpg&mode=medium").unwrap_err();
+        assert!(matches!(err, ProtocolError::MissingArguments(_)));
+        let err = parse_request("reflex://localhost/preview/folder/0?path=%2Fa.jpg&max_dimension=big").unwrap_err();
+        assert!(matches!(err, ProtocolError::MissingArguments(_)));
+        let err = parse_request("reflex://localhost/preview/folder/0?mode=lo").unwrap_err();
+        assert!(matches!(err, ProtocolError::MissingArguments(_)));
+    }
+
+    #[test]
+    fn parse_request_rejects_unknown_routes() {
+        let err = parse_request("reflex://localhost/thumbnail/folder/0?path=%2Fa.jpg&mode=lo").unwrap_err();
+        assert!(matches!(err, ProtocolError::UnknownRoute(_)));
+        let err = parse_request("reflex://localhost/preview/folder?path=%2Fa.jpg&mode=lo").unwrap_err();
+        assert!(matches!(err, ProtocolError::UnknownRoute(_)));
+        let err = parse_request("not a url").unwrap_err();
+        assert!(matches!(err, ProtocolError::MalformedUrl(_)));
+    }
+
+    #[test]
+    fn etag_is_stable_and_covers_the_request() {
+        let request = parse_request("reflex://localhost/preview/lightroom/7?path=%2Fa.cr2&mode=lo").unwrap();
+        let tag = etag(&request, "digest");
+        assert_eq!(tag, etag(&request, "digest"));
+        assert!(tag.starts_with('"') && tag.ends_with('"') && tag.len() == 18);
+        assert_ne!(tag, etag(&request, "other digest"));
+        let larger = ImageRequest { requested: RequestedSize::Largest, ..request.clone() };
+        assert_ne!(tag, etag(&larger, "digest"));
+        let original = ImageRequest { route: ImageRoute::Original, ..request };
+        assert_ne!(tag, etag(&original, "digest"));
+    }
+
+    #[test]
+    fn is_not_modified_reads_if_none_match() {
+        let tag = "\"0123456789abcdef\"";
+        assert!(is_not_modified(&if_none_match(&[tag]), tag));
+        assert!(is_not_modified(&if_none_match(&["W/\"0123456789abcdef\""]), tag));
+        assert!(is_not_modified(&if_none_match(&["\"other\", \"0123456789abcdef\""]), tag));
+        assert!(is_not_modified(&if_none_match(&["\"other\"", tag]), tag));
+        assert!(is_not_modified(&if_none_match(&["*"]), tag));
+        assert!(!is_not_modified(&if_none_match(&["\"other\""]), tag));
+        assert!(!is_not_modified(&if_none_match(&[]), tag));
+    }
+}
@@ -0,0 +1,517 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use anyhow::Result;
+use image::metadata::Orientation;
+use log::{info, warn};
+use crate::catalog_db::PreviewData;
+use crate::{catalog_collections, catalog_keywords, develop_settings};
+use crate::image_data::ImageMetadataFields;
+use crate::image_pipeline::{self, PreviewSource, RequestedSize, ServedImage};
+use crate::{lr_discovery, lrprev, preview_audit, raw_decode, raw_preview, smart_preview, thumbnail_cache};
+
+/// The caches previews are served through, which outlive whichever source is open.
+pub struct ImageCaches<'a> {
+    pub raw_previews: &'a raw_decode::RawPreviewCache,
+    pub thumbnails: &'a thumbnail_cache::ThumbnailCache,
+}
+
+/// Where the open images come from, a lightroom catalog or a folder of files. Commands only
+/// go through this, so another kind of source needs nothing more than an implementation.
+/// Images are named by the id and path the frontend has for them, as not every source has ids.
+pub trait ImageSource: Send + Sync {
+    /// How the frontend types this source's images, "adobe" or "exif".
+    fn image_type(&self) -> &'static str;
+
+    fn total_images(&self) -> usize;
+
+    /// A page of images, in the order they're listed.
+    fn images(&self, offset: usize, limit: usize) -> Vec<ImageMetadataFields>;
+
+    fn image(&self, image_id: &str, image_path: &str) -> Option<ImageMetadataFields>;
+
+    /// The image's preview, or the nearest thing to one that we can make.
+    fn preview(&self, caches: &ImageCaches, image_id: &str, image_path: &str, requested: RequestedSize) -> Result<ServedImage>;
+
+    /// What the preview is made from, which changes whenever the preview does.
+    fn preview_version(&self, image_id: &str, image_path: &str) -> Option<String>;
+
+    /// Where the image's original file is, if it's one of the source's and we can get at it.
+    fn original(&self, image_id: &str, image_path: &str) -> Option<PathBuf>;
+
+    /// The source's keyword hierarchy, for sources that have keywords.
+    fn keyword_tree(&self) -> Vec<catalog_keywords::KeywordNode> {
+        return Vec::new();
+    }
+
+    /// The source's collections and collection sets, for sources that have collections.
+    fn collection_tree(&self) -> Vec<catalog_collections::CollectionNode> {
+        return Vec::new();
+    }
+
+    /// Check the source's preview cache against its images, for sources that have one.
+    fn audit_previews(&self) -> Result<preview_audit::PreviewAuditReport> {
+        return Err(anyhow::anyhow!("No Lightroom catalog is open"));
+    }
+
+    /// How the source's images have been edited, for sources that have develop settings.
+    fn edit_report(&self) -> Result<develop_settings::EditReport> {
+        return Err(anyhow::anyhow!("No Lightroom catalog is open"));
+    }
+}
+
+fn page(images: &[ImageMetadataFields], offset: usize, limit: usize) -> Vec<ImageMetadataFields> {
+    if offset > images.len() {
+        return Vec::new();
+    }
+    let end_point = min(offset.saturating_add(limit), images.len());
+    return images[offset..end_point].to_vec();
+}
+
+/// Nothing open, before a catalog or folder has been chosen or when one couldn't be read.
+pub struct EmptySource;
+
+impl ImageSource for EmptySource {
+    fn image_type(&self) -> &'static str {
+        return "none";
+    }
+
+    fn total_images(&self) -> usize {
+        return 0;
+    }
+
+    fn images(&self, _offset: usize, _limit: usize) -> Vec<ImageMetadataFields> {
+        return Vec::new();
+    }
+
+    fn image(&self, _image_id: &str, _image_path: &str) -> Option<ImageMetadataFields> {
+        return None;
+    }
+
+    fn preview(&self, _caches: &ImageCaches, _image_id: &str, _image_path: &str, _requested: RequestedSize) -> Result<ServedImage> {
+        return Err(anyhow::anyhow!("No catalog or folder is open"));
+    }
+
+    fn preview_version(&self, _image_id: &str, _image_path: &str) -> Option<String> {
+        return None;
+    }
+
+    fn original(&self, _image_id: &str, _image_path: &str) -> Option<PathBuf> {
+        return None;
+    }
+}
+
+// a raw's own embedded jpegs are the quickest thing to serve, when it has none
+// we develop it ourselves
+fn serve_raw_file(
+    raw_cache: &raw_decode::RawPreviewCache,
+    image_path: &str,
+    bytes: &[u8],
+    requested: RequestedSize
+) -> Result<ServedImage> {
+    let previews = raw_preview::read_embedded_previews(bytes);
+    if previews.is_ok() {
+        let previews = previews.unwrap();
+        let chosen = raw_preview::choose_preview(&previews.previews, requested);
+        if chosen.is_some() {
+            let served = image_pipeline::serve_bytes(
+                chosen.unwrap().jpeg.to_vec(),
+                requested,
+                previews.orientation,
+                PreviewSource::EmbeddedPreview
+            )?;
+            return Ok(served);
+        }
+    }
+    info!("no embedded preview in {}, developing it", image_path);
+    let cached = raw_cache.get_or_develop(Path::new(image_path))?;
+    let served = image_pipeline::serve_bytes(
+        cached.jpeg,
+        requested,
+        cached.orientation,
+        PreviewSource::DecodedRaw
+    )?;
+    return Ok(served);
+}
+
+/// Serve an image file directly: as it is if the webview can show it, transcoded if not,
+/// and raws by way of their embedded previews. Files we can't read at all are passed along untouched.
+pub fn serve_file(
+    raw_cache: &raw_decode::RawPreviewCache,
+    image_path: &str,
+    requested: RequestedSize
+) -> Result<ServedImage> {
+    let read_result = fs::read(image_path);
+    if read_result.is_err()
+    {
+        return Err(anyhow::Error::from(read_result.unwrap_err()).context("Failed to load image"));
+    }
+    let bytes = read_result.unwrap();
+    if raw_preview::is_tiff_raw(Path::new(image_path))
+    {
+        let served = serve_raw_file(raw_cache, image_path, &bytes, requested);
+        if served.is_ok()
+        {
+            return served;
+        }
+        warn!("{:#}", served.unwrap_err().context(format!("for image_path {}", image_path)));
+    }
+    if image_pipeline::detect_format(&bytes).is_none()
+    {
+        return Ok(image_pipeline::passthrough(bytes, PreviewSource::OriginalFile));
+    }
+    // any exif orientation is kept if the bytes are passed along, or applied if they're decoded
+    let served = image_pipeline::serve_bytes(
+        bytes.clone(),
+        requested,
+        Orientation::NoTransforms,
+        PreviewSource::OriginalFile
+    );
+    if served.is_err()
+    {
+        // a variant of the format we can't decode (e.g. a jpeg compressed tiff), the webview may yet
+        warn!("{:#}", anyhow::Error::from(served.unwrap_err()).context(format!("failed to transcode {}", image_path)));
+        return Ok(image_pipeline::passthrough(bytes, PreviewSource::OriginalFile));
+    }
+    return Ok(served.unwrap());
+}
+
+/// A lightroom catalog's images, served from its previews, smart previews or originals.
+pub struct LightroomSource {
+    preview_root: String,
+    smart_previews_root: Option<String>,
+    images: Vec<ImageMetadataFields>,
+    image_id_to_index: HashMap<u64, usize>,
+    // the catalog's id_global for every image, which names its smart preview
+    image_id_to_uuid: HashMap<u64, String>,
+    keyword_tree: Vec<catalog_keywords::KeywordNode>,
+    collection_tree: Vec<catalog_collections::CollectionNode>,
+    // None until the preview index has loaded, then replaced each time it's reloaded
+    image_id_to_preview: RwLock<Option<Arc<HashMap<u64, PreviewData>>>>,
+}
+
+impl LightroomSource {
+    pub fn new(
+        preview_root: String,
+        smart_previews_root: Option<String>,
+        images: Vec<ImageMetadataFields>,
+        image_id_to_uuid: HashMap<u64, String>,
+        keyword_tree: Vec<catalog_keywords::KeywordNode>,
+        collection_tree: Vec<catalog_collections::CollectionNode>
+    ) -> LightroomSource {
+        let image_id_to_index = images
+            .iter()
+            .enumerate()
+            .filter_map(|(index, image)| image.id.map(|id| (id, index)))
+            .collect();
+        return LightroomSource {
+            preview_root,
+            smart_previews_root,
+            images,
+            image_id_to_index,
+            image_id_to_uuid,
+            keyword_tree,
+            collection_tree,
+            image_id_to_preview: RwLock::new(None),
+        };
+    }
+
+    pub fn set_preview_index(&self, image_id_to_preview: HashMap<u64, PreviewData>) {
+        *self.image_id_to_preview.write().unwrap() = Some(Arc::new(image_id_to_preview));
+    }
+
+    fn preview_index(&self) -> Option<Arc<HashMap<u64, PreviewData>>> {
+        return self.image_id_to_preview.read().unwrap().clone();
+    }
+
+    fn preview_data(&self, image_id: u64) -> Option<PreviewData> {
+        return self.preview_index()?.get(&image_id).cloned();
+    }
+
+    fn preview_path(&self, preview: &PreviewData) -> String {
+        return lrprev::preview_path(Path::new(&self.preview_root), &preview.uuid, &preview.digest)
+            .to_string_lossy()
+            .into_owned();
+    }
+
+    // lightroom's own orientation for the image, when it has a standard preview
+    fn orientation(&self, image_id: u64, preview: Option<&PreviewData>) -> Option<Orientation> {
+        let code = preview?.orientation.as_ref()?;
+        let orientation = image_pipeline::orientation_from_lightroom(code);
+        if orientation.is_none() {
+            warn!("unknown orientation {} for image_id {}", code, image_id);
+        }
+        return orientation;
+    }
+
+    fn smart_preview_path(&self, image_id: u64, preview: Option<&PreviewData>) -> Option<PathBuf> {
+        let smart_previews_root = self.smart_previews_root.as_ref()?;
+        // the catalog knows every image's uuid, the preview cache only those with a standard preview
+        let uuid = self
+            .image_id_to_uuid
+            .get(&image_id)
+            .or_else(|| preview.map(|preview| &preview.uuid))?;
+        return smart_preview::find_smart_preview(smart_previews_root, uuid);
+    }
+
+    // the catalog's own path for the image, never one the frontend sends, so only the
+    // catalog's originals can be read
+    fn image_path(&self, image_id: u64) -> Option<String> {
+        return self
+            .image_id_to_index
+            .get(&image_id)
+            .map(|index| self.images[*index].filename.clone());
+    }
+
+    fn serve_preview(&self, caches: &ImageCaches, image_id: u64, image_path: &str, requested: RequestedSize) -> Result<ServedImage> {
+        // without the index we can't tell a missing preview from one we haven't read about yet,
+        // the frontend asks again once it's loaded
+        if self.preview_index().is_none() {
+            return Err(anyhow::anyhow!("The preview index is still loading"));
+        }
+        let preview = self.preview_data(image_id);
+        let orientation = self.orientation(image_id, preview.as_ref());
+        if preview.is_some() {
+            let pps = self.preview_path(preview.as_ref().unwrap());
+            // only the chosen level is read from disk, the rest of the file is skipped over
+            let image_result = lrprev::read_level_for_request(&pps, requested);
+            if image_result.is_ok() {
+                let (_, jpeg) = image_result.unwrap();
+                let served = image_pipeline::serve_bytes(
+                    jpeg,
+                    requested,
+                    orientation.unwrap_or(Orientation::NoTransforms),
+                    PreviewSource::LightroomPreview
+                )?;
+                return Ok(served);
+            }
+            // lightroom may have discarded the preview since we indexed it, a smart preview will do
+            warn!("{:#}", anyhow::Error::from(image_result.unwrap_err()).context(format!("for image_id {}", image_id)));
+        }
+
+        let smart_preview_path = self.smart_preview_path(image_id, preview.as_ref());
+        if smart_preview_path.is_some() {
+            let path = smart_preview_path.unwrap();
+            let smart_preview = smart_preview::decode_smart_preview(&path).map_err(|err| {
+                return anyhow::Error::from(err).context("Failed to load smart preview");
+            })?;
+            // lightroom's orientation includes any rotation made in lightroom, the DNG's only the camera's
+            let served = image_pipeline::serve_image(
+                smart_preview.image,
+                requested,
+                orientation.unwrap_or(smart_preview.orientation),
+                PreviewSource::SmartPreview
+            )?;
+            return Ok(served);
+        }
+
+        // fallback to developing the original ourselves
+        let original = lr_discovery::locate_lightroom_path(image_path);
+        if original.is_none() {
+            return Err(
+                anyhow::anyhow!("Neither a preview nor a smart preview exists, and the original is not accessible")
+                    .context(format!("for image_path {}", image_path))
+            );
+        }
+        let original = original.unwrap();
+        let raw_error = match caches.raw_previews.get_or_develop(&original) {
+            Ok(cached) => {
+                let served = image_pipeline::serve_bytes(
+                    cached.jpeg,
+                    requested,
+                    orientation.unwrap_or(cached.orientation),
+                    PreviewSource::DecodedRaw
+                )?;
+                return Ok(served);
+            }
+            Err(err) => anyhow::Error::from(err),
+        };
+
+        // not a raw, but perhaps a jpeg, tiff or psd original we can read directly,
+        // in which case its own exif orientation is applied rather than lightroom's
+        let bytes = fs::read(&original).ok().filter(|bytes| image_pipeline::detect_format(bytes).is_some());
+        if bytes.is_none() {
+            return Err(raw_error.context("Failed to develop original"));
+        }
+        let served = image_pipeline::serve_bytes(
+            bytes.unwrap(),
+            requested,
+            Orientation::NoTransforms,
+            PreviewSource::OriginalFile
+        )?;
+        return Ok(served);
+    }
+}
+
+impl ImageSource for LightroomSource {
+    fn image_type(&self) -> &'static str {
+        return "adobe";
+    }
+
+    fn total_images(&self) -> usize {
+        return self.images.len();
+    }
+
+    fn images(&self, offset: usize, limit: usize) -> Vec<ImageMetadataFields> {
+        return page(&self.images, offset, limit);
+    }
+
+    fn image(&self, image_id: &str, _image_path: &str) -> Option<ImageMetadataFields> {
+        let index = self.image_id_to_index.get(&image_id.parse::<u64>().ok()?)?;
+        return Some(self.images[*index].clone());
+    }
+
+    fn preview(&self, caches: &ImageCaches, image_id: &str, _image_path: &str, requested: RequestedSize) -> Result<ServedImage> {
+        let image_id_int = image_id
+            .parse::<u64>()
+            .map_err(|err| anyhow::Error::from(err).context(format!("for image_id {}", image_id)))?;
+        let image_path = self.image_path(image_id_int);
+        if image_path.is_none() {
+            return Err(anyhow::anyhow!("Not an image in the open catalog").context(format!("for image_id {}", image_id)));
+        }
+        return self
+            .serve_preview(caches, image_id_int, &image_path.unwrap(), requested)
+            .map_err(|err| err.context(format!("for image_id {}", image_id)));
+    }
+
+    // lightroom's digest for its own previews, otherwise the smart preview's or original's file key
+    fn preview_version(&self, image_id: &str, image_path: &str) -> Option<String> {
+        let image_id_int = image_id.parse::<u64>().ok()?;
+        // nothing is served until the index has loaded
+        let preview = self.preview_index()?.get(&image_id_int).cloned();
+        if preview.is_some() {
+            let preview = preview.unwrap();
+            return Some(format!("{}:{}", preview.digest, preview.orientation.as_deref().unwrap_or("")));
+        }
+        let smart_preview_path = self.smart_preview_path(image_id_int, None);
+        if smart_preview_path.is_some() {
+            return image_pipeline::file_cache_key(&smart_preview_path.unwrap());
+        }
+        return image_pipeline::file_cache_key(&self.original(image_id, image_path)?);
+    }
+
+    fn original(&self, image_id: &str, _image_path: &str) -> Option<PathBuf> {
+        let image_id_int = image_id.parse::<u64>().ok()?;
+        return lr_discovery::locate_lightroom_path(&self.image_path(image_id_int)?);
+    }
+
+    fn keyword_tree(&self) -> Vec<catalog_keywords::KeywordNode> {
+        return self.keyword_tree.clone();
+    }
+
+    fn collection_tree(&self) -> Vec<catalog_collections::CollectionNode> {
+        return self.collection_tree.clone();
+    }
+
+    fn audit_previews(&self) -> Result<preview_audit::PreviewAuditReport> {
+        let preview_index = self.preview_index();
+        if preview_index.is_none() {
+            return Err(anyhow::anyhow!("The preview index is still loading"));
+        }
+        let entries: Vec<PreviewData> = preview_index.unwrap().values().cloned().collect();
+        let catalog_image_ids: Vec<u64> = self.image_id_to_uuid.keys().copied().collect();
+        return Ok(preview_audit::audit_previews(Path::new(&self.preview_root), &entries, &catalog_image_ids));
+    }
+
+    fn edit_report(&self) -> Result<develop_settings::EditReport> {
+        return Ok(develop_settings::edit_report(&self.images));
+    }
+}
+
+/// A folder's image files, served from our own thumbnails or the files themselves.
+pub struct FolderSource {
+    images: Vec<ImageMetadataFields>,
+    path_to_index: HashMap<String, usize>,
+}
+
+impl FolderSource {
+    pub fn new(images: Vec<ImageMetadataFields>) -> FolderSource {
+        let path_to_index = images
+            .iter()
+            .enumerate()
+            .map(|(index, image)| (image.filename.clone(), index))
+            .collect();
+        return FolderSource { images, path_to_index };
+    }
+
+    /// Every file in the folder, for the thumbnail cache to fill in ahead of time.
+    pub fn originals(&self) -> Vec<String> {
+        return self.images.iter().map(|image| image.filename.clone()).collect();
+    }
+}
+
+impl ImageSource for FolderSource {
+    fn image_type(&self) -> &'static str {
+        return "exif";
+    }
+
+    fn total_images(&self) -> usize {
+        return self.images.len();
+    }
+
+    fn images(&self, offset: usize, limit: usize) -> Vec<ImageMetadataFields> {
+        return page(&self.images, offset, limit);
+    }
+
+    fn image(&self, _image_id: &str, image_path: &str) -> Option<ImageMetadataFields> {
+        let index = self.path_to_index.get(image_path)?;
+        return Some(self.images[*index].clone());
+    }
+
+    fn preview(&self, caches: &ImageCaches, _image_id: &str, image_path: &str, requested: RequestedSize) -> Result<ServedImage> {
+        let original = self.original("", image_path);
+        if original.is_none() {
+            return Err(anyhow::anyhow!("Not an image in the open folder").context(format!("for image_path {}", image_path)));
+        }
+        let original = original.unwrap();
+        let size = thumbnail_cache::ThumbnailSize::for_request(requested);
+        if size.is_none()
+        {
+            return serve_file(caches.raw_previews, image_path, requested)
+                .map_err(|err| err.context(format!("for image_path {}", image_path)));
+        }
+        let size = size.unwrap();
+        let thumbnail = match caches.thumbnails.get(&original, size) {
+            Some((jpeg, source)) => ServedImage {
+                width: None,
+                height: None,
+                mime_type: image_pipeline::mime_type_for(image::ImageFormat::Jpeg),
+                source,
+                data: jpeg,
+            },
+            None => {
+                let made = serve_file(
+                    caches.raw_previews,
+                    image_path,
+                    RequestedSize::MaxDimension(size.long_edge())
+                ).map_err(|err| err.context(format!("for image_path {}", image_path)))?;
+                caches.thumbnails.store(&original, size, &made);
+                made
+            }
+        };
+        // the thumbnail may still be a good deal bigger than asked for
+        if image_pipeline::probe(&thumbnail.data).is_none()
+        {
+            return Ok(thumbnail);
+        }
+        let source = thumbnail.source;
+        let served = image_pipeline::serve_bytes(thumbnail.data, requested, Orientation::NoTransforms, source)
+            .map_err(|err| anyhow::Error::from(err).context(format!("for image_path {}", image_path)))?;
+        return Ok(served);
+    }
+
+    fn preview_version(&self, image_id: &str, image_path: &str) -> Option<String> {
+        return image_pipeline::file_cache_key(&self.original(image_id, image_path)?);
+    }
+
+    // only the folder's own images are served, whatever path is asked for
+    fn original(&self, _image_id: &str, image_path: &str) -> Option<PathBuf> {
+        if !self.path_to_index.contains_key(image_path) {
+            return None;
+        }
+        let original = PathBuf::from(image_path);
+        return original.is_file().then_some(original);
+    }
+}
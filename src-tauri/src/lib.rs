@@ -1,18 +1,10 @@
-use std::cmp::min;
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 use anyhow::Result;
-use futures::executor::block_on;
-use glob::glob;
 use serde::{Deserialize, Serialize, Serializer};
-use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::ConnectOptions;
-use std::collections::HashMap;
-use std::env;
-use std::fs;
-use std::fs::File;
-use std::io::{self, BufRead};
+use serde::ser::SerializeStruct;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use futures::TryFutureExt;
 use sysinfo::Disks;
 use tauri::ipc::Response;
@@ -23,14 +15,40 @@ use tauri_plugin_dialog::{DialogExt, MessageDialogKind};
 use tauri_plugin_fs::FsExt;
 use tauri_plugin_opener::OpenerExt;
 use crate::image_data::ImageMetadataFields;
+use crate::image_source::ImageSource;
 use log::{Record, Level, Metadata, info, warn, error, LevelFilter};
 use chrono::{DateTime, Local};
 
 struct SimpleLogger;
 
-mod lrprev;
+pub mod lrprev;
 mod image_folder;
 mod image_data;
+mod lr_discovery;
+pub mod lua_table;
+mod agprefs;
+mod lr_catalogs;
+mod catalog_db;
+mod catalog_images;
+mod catalog_keywords;
+mod catalog_collections;
+mod color_labels;
+mod develop_settings;
+mod smart_collection;
+mod metadata_helper;
+mod lr_snapshot;
+pub mod image_pipeline;
+mod tiff;
+mod psd;
+mod smart_preview;
+mod disk_cache;
+mod raw_decode;
+mod raw_preview;
+mod thumbnail_cache;
+mod preview_audit;
+mod preview_index;
+mod image_protocol;
+mod image_source;
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -57,7 +75,9 @@ struct SharedAppState {
     conf_dirs: Option<LightroomConfDirs>,
     // folder_search_mode
     root_dir: Option<String>,
-    total_images: Option<usize>
+    total_images: Option<usize>,
+    // set when lightroom had the catalog open, and we're reading a copy of it
+    snapshot: Option<lr_snapshot::SnapshotInfo>
 }
 
 impl Clone for SharedAppState {
@@ -65,96 +85,186 @@ impl Clone for SharedAppState {
         SharedAppState {
             conf_dirs: self.conf_dirs.clone(),
             root_dir: self.root_dir.clone(),
-            total_images: self.total_images.clone()
+            total_images: self.total_images.clone(),
+            snapshot: self.snapshot.clone()
         }
     }
 }
 
 struct AppState {
     shared: SharedAppState,
-    // folder mode
-    image_db_from_files: Vec<image_data::ImageMetadataFields>,
-    image_db_to_index: HashMap<String, usize>,
-    // common?
-    image_id_to_image: Option<HashMap<u64, PreviewData>>,
+    // the open catalog or folder, which every command's images come through
+    source: Arc<dyn image_source::ImageSource>,
 }
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+impl AppState {
+    fn empty() -> AppState {
+        return AppState {
+            shared: SharedAppState {
+                conf_dirs: None,
+                root_dir: None,
+                total_images: None,
+                snapshot: None
+            },
+            source: Arc::new(image_source::EmptySource)
+        };
+    }
 }
 
-fn get_library_path_from_config_file(adobe_config_path: &str) -> Result<String, ()> {
-    let path_exists_result = fs::exists(&adobe_config_path);
-    if !path_exists_result.is_ok() {
-        error!("{}", "unimp 2");
-        unimplemented!();
-    }
-    if path_exists_result.is_ok() && !path_exists_result.unwrap() {
-        error!("{}", "unimp 3");
-        unimplemented!();
+// the source is shared rather than used under the lock, so serving one image doesn't hold up the rest
+fn current_source(state: &tauri::State<Mutex<AppState>>) -> Arc<dyn image_source::ImageSource> {
+    return state.lock().unwrap().source.clone();
+}
+
+fn get_library_path_from_config_file(adobe_config_path: &Path) -> Result<String, agprefs::AgprefsError> {
+    info!("reading from {}", adobe_config_path.display());
+    let prefs = agprefs::read_preferences(adobe_config_path)?;
+    return prefs
+        .default_catalog
+        .ok_or_else(|| agprefs::AgprefsError::NoDefaultCatalog(adobe_config_path.to_path_buf()));
+}
+
+// while lightroom has the catalog open, the live files can change underneath us,
+// so read from a snapshot of them instead
+async fn snapshot_if_in_use(conf_dirs: &LightroomConfDirs) -> Result<(LightroomConfDirs, Option<lr_snapshot::SnapshotInfo>), lr_snapshot::SnapshotError> {
+    let db_paths = [
+        conf_dirs.cat_path.as_str(),
+        conf_dirs.metadata_db_path.as_str(),
+        conf_dirs.preview_db_path.as_str()
+    ];
+    let markers = lr_snapshot::in_use_markers(&conf_dirs.cat_path, &db_paths);
+    if markers.is_empty() {
+        return Ok((conf_dirs.clone(), None));
     }
+    info!("catalog is in use ({}), reading from a snapshot", markers.join(", "));
+    let (copies, snapshot) = lr_snapshot::take_snapshot(&db_paths, markers).await?;
+    // the lrprev files themselves are never modified in place, so preview_root stays put
+    let mut snapshot_dirs = conf_dirs.clone();
+    snapshot_dirs.cat_path = copies[0].clone();
+    snapshot_dirs.metadata_db_path = copies[1].clone();
+    snapshot_dirs.preview_db_path = copies[2].clone();
+    return Ok((snapshot_dirs, Some(snapshot)));
+}
 
-    info!("{}", "reading from ".to_owned() + &adobe_config_path);
-    if let Ok(lines) = read_lines(&adobe_config_path) {
-        // Consumes the iterator, returns an (Optional) String
-        // FIXME: Should really parse this properly
-        for line in lines.map_while(Result::ok) {
-            // println!("{}", line);
-            if line.contains("libraryToLoad20") {
-                let parts = line.split("=");
-                let parts_collected = parts.collect::<Vec<&str>>();
-                // if the path contains an equals let's rebuild it
-                // TODO: Stripping the path of quotes should be redundant/confusing
-                let library_path: String = parts_collected[1..]
-                    .join("=")
-                    .trim()
-                    .trim_end_matches(",")
-                    .trim_matches('"')
-                    .to_owned();
-                return Ok(library_path);
+// everything read from a catalog when it's opened
+struct LoadedCatalog {
+    shared: SharedAppState,
+    schema: catalog_db::CatalogSchema,
+    image_id_to_uuid: HashMap<u64, String>,
+    images: Vec<ImageMetadataFields>,
+    keyword_tree: Vec<catalog_keywords::KeywordNode>,
+    collection_tree: Vec<catalog_collections::CollectionNode>,
+}
+
+// the preview index is loaded separately, see start_preview_index
+async fn load_catalog(conf_dirs: &LightroomConfDirs) -> CommandResult<LoadedCatalog> {
+    let (readable_dirs, snapshot) = snapshot_if_in_use(conf_dirs).await?;
+    let schema = catalog_db::detect_schema(
+        &readable_dirs.cat_path,
+        &readable_dirs.preview_db_path,
+        &readable_dirs.metadata_db_path
+    ).await?;
+    // only needed for smart previews, so carry on without them if the catalog can't be read
+    let image_id_to_uuid = catalog_db::load_image_uuids(&readable_dirs.cat_path)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("{:#}", anyhow::Error::from(err));
+            return HashMap::new();
+        });
+    // fills in what the catalog doesn't harvest, so carry on without it too
+    let helper_metadata = metadata_helper::load_helper_metadata(&readable_dirs.metadata_db_path)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("{:#}", anyhow::Error::from(err));
+            return HashMap::new();
+        });
+    let catalog_images = catalog_images::load_catalog_images(&readable_dirs.cat_path).await;
+    let mut images = match catalog_images {
+        Ok(mut images) => {
+            for image in images.iter_mut() {
+                let helper = image.id.and_then(|id| helper_metadata.get(&id));
+                if helper.is_some() {
+                    helper.unwrap().apply_to(image);
+                }
             }
+            images
+        }
+        // metadatahelper.db has all but the virtual copies, which beats showing nothing
+        Err(err) if !helper_metadata.is_empty() => {
+            warn!("reading images from metadatahelper.db instead: {:#}", anyhow::Error::from(err));
+            let mut images: Vec<ImageMetadataFields> = helper_metadata
+                .values()
+                .filter_map(|helper| helper.to_fields())
+                .collect();
+            images.sort_by(|a, b| b.id.cmp(&a.id));
+            images
+        }
+        Err(err) => return Err(err.into()),
+    };
+    // keywords are an extra, the images are still worth showing without them
+    let keywords = catalog_keywords::load_catalog_keywords(&readable_dirs.cat_path)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("{:#}", anyhow::Error::from(err));
+            return catalog_keywords::CatalogKeywords::default();
+        });
+    for image in images.iter_mut() {
+        if image.id.is_some() {
+            image.keywords = Some(keywords.image_keywords.get(&image.id.unwrap()).cloned().unwrap_or_default());
         }
     }
-    unimplemented!()
-}
-
-struct PreviewData {
-    image_id: u64,
-    uuid: String,
-    digest: String,
-    orientation: Option<String>,
-}
-
-async fn do_sql(preview_db_path: &str) -> HashMap<u64, PreviewData> {
-    let connection = SqliteConnectOptions::new()
-        .read_only(true)
-        .filename(preview_db_path);
-    let mut db = connection.connect().await.unwrap();
-    let qresult = sqlx::query!("select imageId, uuid, digest, orientation from ImageCacheEntry")
-        .fetch_all(&mut db)
-        .await;
-    let mut image_id_to_image = HashMap::new();
-    if qresult.is_ok() {
-        let qr = qresult.unwrap();
-        for image in qr {
-            image_id_to_image.insert(
-                image.imageId as u64,
-                PreviewData {
-                    image_id: image.imageId as u64,
-                    digest: image.digest,
-                    uuid: image.uuid,
-                    orientation: image.orientation,
-                },
-            );
+    // a label's name only means a colour in the set the catalog was labelled with, which may be
+    // kept beside the live catalog rather than a snapshot of it
+    let labels: HashSet<String> = images
+        .iter()
+        .filter_map(|image| image.color_label.clone())
+        .filter(|label| !label.is_empty())
+        .collect();
+    let label_sets = color_labels::known_label_sets(Path::new(&conf_dirs.cat_path));
+    let label_set = color_labels::choose_label_set(&label_sets, &labels);
+    for image in images.iter_mut() {
+        if image.color_label.is_some() {
+            image.label_color = Some(label_set.color_for(image.color_label.as_ref().unwrap()).to_owned());
+        }
+    }
+    // collections are an extra too, with smart ones testing the label colours
+    let collections = catalog_collections::load_catalog_collections(&readable_dirs.cat_path, &images)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("{:#}", anyhow::Error::from(err));
+            return catalog_collections::CatalogCollections::default();
+        });
+    for image in images.iter_mut() {
+        if image.id.is_some() {
+            image.collections = Some(collections.image_collections.get(&image.id.unwrap()).cloned().unwrap_or_default());
+        }
+    }
+    // develop settings are only for edit analytics, so carry on without them too
+    let mut develop_settings = develop_settings::load_develop_settings(&readable_dirs.cat_path)
+        .await
+        .unwrap_or_else(|err| {
+            warn!("{:#}", anyhow::Error::from(err));
+            return HashMap::new();
+        });
+    for image in images.iter_mut() {
+        if image.id.is_some() {
+            image.develop = develop_settings.remove(&image.id.unwrap());
         }
-    } else {
-        warn!("{}", "all is not wel");
     }
-    return image_id_to_image;
+    let shared = SharedAppState {
+        conf_dirs: Some(readable_dirs),
+        root_dir: None,
+        total_images: Some(images.len()),
+        snapshot
+    };
+    return Ok(LoadedCatalog {
+        shared,
+        schema,
+        image_id_to_uuid,
+        images,
+        keyword_tree: keywords.tree,
+        collection_tree: collections.tree
+    });
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -164,6 +274,8 @@ struct LightroomConfDirs {
     metadata_db_path: String,
     preview_db_path: String,
     preview_root: String,
+    // "<CatalogName> Smart Previews.lrdata", if the catalog has one
+    smart_previews_root: Option<String>,
 }
 
 impl Clone for LightroomConfDirs {
@@ -174,158 +286,84 @@ impl Clone for LightroomConfDirs {
             metadata_db_path: self.metadata_db_path.clone(),
             preview_db_path: self.preview_db_path.clone(),
             preview_root: self.preview_root.clone(),
+            smart_previews_root: self.smart_previews_root.clone(),
         }
     }
 }
 
-fn find_configuration_relative_to_catalog(cat_path_value: &String) -> Option<LightroomConfDirs>
+fn find_configuration_relative_to_catalog(
+    cat_path_value: &String,
+    overrides: &lr_catalogs::CompanionOverrides
+) -> Result<LightroomConfDirs, lr_catalogs::CompanionReport>
 {
     info!("library_path = {}", cat_path_value);
-    let cat_parent = Path::new(&cat_path_value).parent();
-    if cat_parent.is_none() {
-        return None;
-    }
-    let cat_directory = cat_parent.unwrap();
-    // todo: I'm a bit lazy here, forcing the path to unwrap
-    let helper_glob = glob(cat_directory.join("**/metadatahelper.db").to_str().unwrap());
-    if !helper_glob.is_ok() {
-        return None;
-    }
-    let helper_paths = helper_glob.unwrap();
-    let mut metadata_db_path: Option<String> = None;
-    for entry in helper_paths {
-        if let Ok(candidate_metadata_path) = entry {
-            info!(
-                "candidate_metadata_path = {}",
-                candidate_metadata_path.display()
-            );
-            metadata_db_path = Some(
-                candidate_metadata_path
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-            );
-        }
-    }
-
-    let preview_glob = glob(
-        cat_directory
-            .join("*Previews*/previews.db")
-            .to_str()
-            .unwrap(),
-    );
-    if !preview_glob.is_ok() {
-        return None;
-    }
-
-    let preview_paths = preview_glob.unwrap();
-    let mut preview_db_path: Option<String> = None;
-    for entry in preview_paths {
-        if let Ok(candidate_preview_path) = entry {
-            info!(
-                "candidate_preview_path = {}",
-                candidate_preview_path.display()
-            );
-            preview_db_path = Some(
-                candidate_preview_path
-                    .into_os_string()
-                    .into_string()
-                    .unwrap(),
-            );
-        }
+    let cat_path = Path::new(&cat_path_value);
+    let report = lr_catalogs::check_companions(cat_path, overrides);
+    if !report.is_complete() {
+        warn!("{}", report);
+        return Err(report);
     }
+    info!("candidate_metadata_path = {}", report.helper.expected_path);
+    info!("candidate_preview_path = {}", report.previews.expected_path);
 
-    let preview_db_path_value: String = preview_db_path.unwrap();
-    let preview_root = Path::new(&preview_db_path_value)
+    let preview_root = Path::new(&report.previews.expected_path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_string_lossy()
+        .into_owned();
+    let root = cat_path
         .parent()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_owned();
+        .unwrap_or(Path::new(""))
+        .to_string_lossy()
+        .into_owned();
 
-    return Some(LightroomConfDirs {
-        root: cat_directory.to_str().unwrap().to_owned(),
+    return Ok(LightroomConfDirs {
+        root,
         cat_path: cat_path_value.clone(),
-        metadata_db_path: metadata_db_path.unwrap(),
-        preview_db_path: preview_db_path_value,
-        preview_root: preview_root,
+        metadata_db_path: report.helper.expected_path,
+        preview_db_path: report.previews.expected_path,
+        preview_root,
+        smart_previews_root: lr_catalogs::smart_previews_dir(cat_path)
+            .map(|dir| dir.to_string_lossy().into_owned()),
     });
 }
 
 fn find_configuration() -> Option<LightroomConfDirs> {
-    let app_data_result = env::var("APPDATA");
-    if !app_data_result.is_ok() {
-        error!("{}", "unimp 1");
-        return None;
-    }
-
-    let app_data_dir = app_data_result.unwrap();
-    let preferences_relpath =
-        "Adobe/Lightroom/Preferences/Lightroom Classic CC 7 Preferences.agprefs";
-    let expected_adobe_prefs = app_data_dir + "/" + preferences_relpath;
-    let cat_path = get_library_path_from_config_file(&expected_adobe_prefs);
-    if !cat_path.is_ok() {
-        warn!("library_path was not defined");
-        return None;
+    let candidates = lr_discovery::find_preferences_files();
+    for candidate in candidates {
+        let adobe_prefs = candidate.path.display();
+        let cat_path_value = match get_library_path_from_config_file(&candidate.path) {
+            Ok(cat_path) => cat_path,
+            Err(err) => {
+                warn!("{:#}", anyhow::Error::from(err));
+                continue;
+            }
+        };
+        let host_cat_path = lr_discovery::translate_lightroom_path(&cat_path_value, &candidate.origin);
+        if !host_cat_path.as_ref().is_some_and(|p| p.exists()) {
+            warn!("library_path {} from {} is not accessible", cat_path_value, adobe_prefs);
+            continue;
+        }
+        let host_cat_path_value = host_cat_path.unwrap().to_string_lossy().into_owned();
+        let conf_dirs = find_configuration_relative_to_catalog(
+            &host_cat_path_value,
+            &lr_catalogs::CompanionOverrides::default()
+        );
+        if conf_dirs.is_ok() {
+            return conf_dirs.ok();
+        }
     }
-    let cat_path_value = cat_path.unwrap();
-    return find_configuration_relative_to_catalog(&cat_path_value);
-}
-
-fn format_preview_filepath(preview_root: &str, image: &PreviewData) -> String {
-    return Path::new(&preview_root)
-        .join(&image.uuid[0..1])
-        .join(&image.uuid[0..4])
-        .join(image.uuid.clone() + "-" + &image.digest + ".lrprev")
-        .as_os_str()
-        .to_str()
-        .unwrap()
-        .to_owned();
+    return None;
 }
 
 #[tauri::command]
 fn get_available_images(state: tauri::State<Mutex<AppState>>, offset: usize, limit: usize) -> Vec<image_data::ImageMetadataFields> {
-    let locked_state = state.lock().unwrap();
-    if offset > locked_state.image_db_from_files.len()
-    {
-        return Vec::new();
-    }
-    else
-    {
-        let end_point = min( offset + limit, locked_state.image_db_from_files.len() );
-        return locked_state.image_db_from_files[offset.clone()..end_point].to_vec();
-    }
+    return current_source(&state).images(offset, limit);
 }
 
 #[tauri::command]
 fn get_total_available_images(state: tauri::State<Mutex<AppState>>) -> usize {
-    let locked_state = state.lock().unwrap();
-    return locked_state.image_db_to_index.len();
-}
-
-
-#[tauri::command]
-fn get_preview_path_for_image_id(state: tauri::State<Mutex<AppState>>, image_id: &str) -> Option<String> {
-    // fixme: this code needs to handle errors!
-    let image_id_int = image_id.parse::<u64>();
-    if !image_id_int.is_ok() {
-        // todo: log
-        return None;
-    }
-    let locked_state = state.lock().unwrap();
-    if !locked_state.image_id_to_image.is_some() && locked_state.shared.conf_dirs.is_some() {
-        // todo: log
-        return None;
-    }
-    let maybe_image = locked_state.image_id_to_image.as_ref().unwrap().get(&image_id_int.unwrap());
-    if !maybe_image.is_some() {
-        return None;
-    }
-
-    return Some(format_preview_filepath(
-        &locked_state.shared.conf_dirs.as_ref().unwrap().preview_root,
-        maybe_image.unwrap(),
-    ));
+    return current_source(&state).total_images();
 }
 
 // this is pinched from tauri-fs's implementation
@@ -334,6 +372,12 @@ fn get_preview_path_for_image_id(state: tauri::State<Mutex<AppState>>, image_id:
 pub enum ReflexCommandError {
     #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
+    #[error(transparent)]
+    MissingCompanions(#[from] lr_catalogs::CompanionReport),
+    #[error(transparent)]
+    Catalog(#[from] catalog_db::CatalogError),
+    #[error(transparent)]
+    Snapshot(#[from] lr_snapshot::SnapshotError),
 }
 
 impl From<&str> for ReflexCommandError {
@@ -347,110 +391,66 @@ impl Serialize for ReflexCommandError {
     where
         S: Serializer,
     {
-        // TODO: this is currently a warning, because ReflexCommandError is currently *only*
-        // a wrapper around anyhow::Error, this is unlikely to be true forever
-        if let Self::Anyhow(err) = self {
-            serializer.serialize_str(format!("{err:#}").as_ref())
-        } else {
-            serializer.serialize_str(self.to_string().as_ref())
+        match self {
+            Self::Anyhow(err) => serializer.serialize_str(format!("{err:#}").as_ref()),
+            // the frontend gets the full report, so it can say which companion is missing
+            Self::MissingCompanions(report) => {
+                let mut state = serializer.serialize_struct("MissingCompanions", 2)?;
+                state.serialize_field("message", &report.to_string())?;
+                state.serialize_field("report", report)?;
+                state.end()
+            }
+            _ => {
+                // same format as anyhow's "{:#}", the error followed by its causes
+                let mut message = self.to_string();
+                let mut source = std::error::Error::source(self);
+                while let Some(cause) = source {
+                    message = format!("{message}: {cause}");
+                    source = cause.source();
+                }
+                serializer.serialize_str(message.as_ref())
+            }
         }
     }
 }
 pub type CommandResult<T> = std::result::Result<T, ReflexCommandError>;
 
-fn get_preview_image_response_for_adobe_image_id(
-    state: tauri::State<Mutex<AppState>>,
-    image_id: &str,
-    image_path: &str,
-    mode: &str
-) -> CommandResult<tauri::ipc::Response> {
-    let preview_path = get_preview_path_for_image_id(state.clone(), &image_id);
-    if !preview_path.is_some() {
-        // fallback to loading raw
-        // let raw_exists = fs::exists(&image_path).unwrap_or(false);
-        // if raw_exists
-        // {
-        //     let raw_result = rawloader::decode_file(&image_path);
-        // }
-        // else {
-        return Err(ReflexCommandError::from(
-            anyhow::anyhow!("Preview does not exist and raw file not accessible")
-                .context(format!("for image_id {}", image_id))
-                .context(format!("for image_path {}", image_path))
-        ));
-        // }
-    }
-
-    let pps = preview_path.unwrap();
-    let image_result = lrprev::get_jpeg_byte_segments_from_file(&pps);
-    if image_result.is_ok() {
-        let loaded_image_bytes = image_result.unwrap();
-        if loaded_image_bytes.len() == 0 {
-            return Err(ReflexCommandError::from(
-                anyhow::anyhow!("Found no images in preview file")
-                    .context(format!("for image_id {}", image_id))
-            ));
-        }
-        if mode == "lo" {
-            let bytes: Vec<u8> = loaded_image_bytes.get(0).unwrap().to_owned();
-            return Ok(Response::new(bytes));
-        }
-        if mode == "hi" {
-            let bytes = loaded_image_bytes
-                .get(loaded_image_bytes.len() - 1)
-                .unwrap()
-                .to_owned();
-            return Ok(Response::new(bytes));
-        }
-        return Err(ReflexCommandError::from(
-            anyhow::anyhow!("Failed to provide image (despite image loaded)")
-                .context(format!("for image_id {}", image_id)),
-        ));
-    } else {
-        return Err(ReflexCommandError::from(
-            anyhow::anyhow!("Failed to load image").context(format!("for image_id {}", image_id)),
-        ));
-    }
-}
-
+// either mode ("hi" or "lo", for the largest or smallest preview available)
+// or max_dimension (the smallest preview that covers it) must be given
 #[tauri::command]
 fn get_image_for_id(
     state: tauri::State<Mutex<AppState>>,
+    raw_cache: tauri::State<raw_decode::RawPreviewCache>,
+    thumbnails: tauri::State<thumbnail_cache::ThumbnailCache>,
     image_id: String,
     image_path: String,
     image_type: String,
-    mode: String,
-) -> CommandResult<tauri::ipc::Response> {
-    if mode != "hi" && mode != "lo" {
+    mode: Option<String>,
+    max_dimension: Option<u32>,
+) -> CommandResult<image_pipeline::ServedImage> {
+    let requested = image_pipeline::RequestedSize::from_args(mode.as_deref(), max_dimension);
+    if requested.is_none() {
         return Err(ReflexCommandError::from(
-            anyhow::anyhow!("ArgumentError: mode must be 'hi' or 'lo'")
-                .context(format!("received {}", mode)),
+            anyhow::anyhow!("ArgumentError: mode must be 'hi' or 'lo', or max_dimension must be set")
+                .context(format!("received {:?}", mode)),
         ));
     }
-    if image_type == "adobe"
-    {
-        return get_preview_image_response_for_adobe_image_id(
-            state.clone(),
-            &image_id,
-            &image_path,
-            &mode
-        );
-    }
-    else if image_type == "exif"
-    {
-        let read_result = fs::read(&image_path);
-        if read_result.is_ok()
-        {
-            return Ok(Response::new(read_result.unwrap()));
-        }
-        else
-        {
-            return Err(ReflexCommandError::from(
-                anyhow::anyhow!("Failed to load image").context(format!("for image_path {}", image_path)),
-            ));
-        }
-    }
-    else
+    return get_image_response(state, raw_cache, thumbnails, &image_id, &image_path, &image_type, requested.unwrap());
+}
+
+// the preview of an image, shared by get_image_for_id and the reflex:// protocol
+fn get_image_response(
+    state: tauri::State<Mutex<AppState>>,
+    raw_cache: tauri::State<raw_decode::RawPreviewCache>,
+    thumbnails: tauri::State<thumbnail_cache::ThumbnailCache>,
+    image_id: &str,
+    image_path: &str,
+    image_type: &str,
+    requested: image_pipeline::RequestedSize
+) -> CommandResult<image_pipeline::ServedImage> {
+    let source = current_source(&state);
+    // an image the frontend still has from a catalog or folder that's since been closed
+    if image_type != source.image_type()
     {
         return Err(ReflexCommandError::from(
             anyhow::anyhow!("Bad image_type")
@@ -458,6 +458,120 @@ fn get_image_for_id(
                 .context(format!("for image_path {}", image_path))
         ));
     }
+    let caches = image_source::ImageCaches {
+        raw_previews: &raw_cache,
+        thumbnails: &thumbnails,
+    };
+    let served = source.preview(&caches, image_id, image_path, requested)?;
+    return Ok(served);
+}
+
+// what the served image is made from, so the webview only fetches it again when that changes
+fn get_image_version(state: tauri::State<Mutex<AppState>>, request: &image_protocol::ImageRequest) -> Option<String> {
+    let source = current_source(&state);
+    if request.route == image_protocol::ImageRoute::Preview {
+        return source.preview_version(&request.image_id, &request.image_path);
+    }
+    let original = source.original(&request.image_id, &request.image_path)?;
+    return image_pipeline::file_cache_key(&original);
+}
+
+// serves reflex:// requests, see image_protocol for the url layout
+fn handle_image_protocol_request(app: &AppHandle, request: &tauri::http::Request<Vec<u8>>) -> tauri::http::Response<Vec<u8>> {
+    let parsed = image_protocol::parse_request(&request.uri().to_string());
+    if parsed.is_err() {
+        let err = parsed.unwrap_err();
+        warn!("{}", err);
+        return image_protocol::error_response(tauri::http::StatusCode::BAD_REQUEST, &err.to_string());
+    }
+    let image_request = parsed.unwrap();
+    let state = app.state::<Mutex<AppState>>();
+    let etag = get_image_version(state.clone(), &image_request)
+        .map(|version| image_protocol::etag(&image_request, &version));
+    if etag.is_some() && image_protocol::is_not_modified(request.headers(), etag.as_ref().unwrap()) {
+        return image_protocol::not_modified_response(etag.as_ref().unwrap());
+    }
+
+    let raw_cache = app.state::<raw_decode::RawPreviewCache>();
+    let served = match image_request.route {
+        image_protocol::ImageRoute::Preview => get_image_response(
+            state,
+            raw_cache,
+            app.state::<thumbnail_cache::ThumbnailCache>(),
+            &image_request.image_id,
+            &image_request.image_path,
+            &image_request.image_type,
+            image_request.requested
+        ).map_err(anyhow::Error::from),
+        image_protocol::ImageRoute::Original => {
+            let source = current_source(&state);
+            // as with previews, only the open catalog or folder's own images are served
+            let original = match image_request.image_type == source.image_type() {
+                true => source.original(&image_request.image_id, &image_request.image_path),
+                false => None,
+            };
+            let served = match original {
+                Some(original) => image_source::serve_file(&raw_cache, &original.to_string_lossy(), image_request.requested),
+                None => Err(anyhow::anyhow!("The original is not accessible")),
+            };
+            served.map_err(|err| err.context(format!("for image_path {}", image_request.image_path)))
+        }
+    };
+    if served.is_err() {
+        let err = served.unwrap_err();
+        warn!("{:#}", err);
+        return image_protocol::error_response(tauri::http::StatusCode::NOT_FOUND, &format!("{:#}", err));
+    }
+    return image_protocol::image_response(served.unwrap(), etag.as_deref());
+}
+
+// walks the whole preview folder, so it runs off the async runtime's worker threads
+#[tauri::command]
+async fn audit_preview_cache(state: tauri::State<'_, Mutex<AppState>>) -> CommandResult<preview_audit::PreviewAuditReport> {
+    let source = current_source(&state);
+    let report = tauri::async_runtime::spawn_blocking(move || {
+        return source.audit_previews();
+    })
+        .await
+        .map_err(|err| anyhow::Error::from(err).context("Preview audit failed"))??;
+    return Ok(report);
+}
+
+#[tauri::command]
+fn get_keyword_tree(state: tauri::State<Mutex<AppState>>) -> Vec<catalog_keywords::KeywordNode> {
+    return current_source(&state).keyword_tree();
+}
+
+#[tauri::command]
+fn get_edit_report(state: tauri::State<Mutex<AppState>>) -> CommandResult<develop_settings::EditReport> {
+    return Ok(current_source(&state).edit_report()?);
+}
+
+#[tauri::command]
+fn get_collection_tree(state: tauri::State<Mutex<AppState>>) -> Vec<catalog_collections::CollectionNode> {
+    return current_source(&state).collection_tree();
+}
+
+#[tauri::command]
+async fn list_known_catalogs() -> CommandResult<Vec<lr_catalogs::KnownCatalog>> {
+    return Ok(lr_catalogs::list_known_catalogs().await);
+}
+
+#[tauri::command]
+fn get_snapshot_age(state: tauri::State<Mutex<AppState>>) -> Option<lr_snapshot::SnapshotAge> {
+    let locked_state = state.lock().unwrap();
+    return locked_state.shared.snapshot.as_ref().map(|snapshot| snapshot.age());
+}
+
+#[tauri::command]
+fn get_thumbnail_cache_stats(thumbnails: tauri::State<thumbnail_cache::ThumbnailCache>) -> thumbnail_cache::ThumbnailCacheStats {
+    return thumbnails.stats();
+}
+
+#[tauri::command]
+fn clear_thumbnail_cache(thumbnails: tauri::State<thumbnail_cache::ThumbnailCache>) -> thumbnail_cache::ThumbnailCacheStats {
+    info!("clearing thumbnail cache");
+    return thumbnails.clear();
 }
 
 #[tauri::command]
@@ -501,163 +615,247 @@ fn maybe_image_data(tup: &(image_folder::ImagePaths, Option<image_folder::ImageD
     }
 }
 
-fn get_app_state_from_image_folder(folder: &String, _additive: bool) -> AppState
+// the folder's state, and its files for generate_folder_thumbnails
+fn get_app_state_from_image_folder(folder: &String, _additive: bool) -> (AppState, Vec<String>)
 {
     let image_index = image_folder::index_folder(folder, folder);
     if image_index.is_err()
     {
-        return AppState {
-            shared: SharedAppState {
-                conf_dirs: None,
-                root_dir: None,
-                total_images: None
-            },
-            image_id_to_image: None,
-            image_db_from_files: Vec::new(),
-            image_db_to_index: HashMap::new()
-        };
+        return (AppState::empty(), Vec::new());
     }
     else {
-        let image_db = image_index.unwrap();
-        // there's probably a far-more-efficient way of doing this
-        let image_db_key_to_index = image_db
-            .keys()
-            .enumerate()
-            .map(|(i, k)| (k.clone(), i))
-            .collect::<HashMap<String, usize>>();
-        let image_db_values: Vec<ImageMetadataFields> = image_db
+        let image_db_values: Vec<ImageMetadataFields> = image_index
+            .unwrap()
             .values()
             .map(maybe_image_data)
             .collect();
-        return AppState {
+        let source = image_source::FolderSource::new(image_db_values);
+        let originals = source.originals();
+        let app_state = AppState {
             shared: SharedAppState {
                 conf_dirs: None,
                 root_dir: Some(folder.clone()),
-                total_images: Some(image_db_key_to_index.len())
+                total_images: Some(source.total_images()),
+                snapshot: None
             },
-            image_id_to_image: None,
-            image_db_from_files: image_db_values,
-            image_db_to_index: image_db_key_to_index
+            source: Arc::new(source)
         };
+        return (app_state, originals);
     }
 }
 
 
-fn update_app_state_for_folder(app_state: tauri::State<'_, Mutex<AppState>>, folder: &String, additive: bool)
+fn update_app_state_for_folder(app_state: tauri::State<'_, Mutex<AppState>>, folder: &String, additive: bool) -> Vec<String>
 {
     info!("Starting update_app_state_for_folder");
-    let updated_app_state = get_app_state_from_image_folder(folder, additive);
+    let (updated_app_state, originals) = get_app_state_from_image_folder(folder, additive);
     let mut mutable_app_state = app_state.lock().unwrap();
     *mutable_app_state = updated_app_state;
     info!("Ended update_app_state_for_folder");
+    return originals;
 }
 
 
+// fill the thumbnail cache for the folder's files in the background, so browsing
+// doesn't have to wait on decoding full size files
+fn generate_folder_thumbnails(app_handle: &tauri::AppHandle, originals: Vec<String>)
+{
+    let generation = app_handle.state::<thumbnail_cache::ThumbnailCache>().next_generation();
+    let app_handle = app_handle.clone();
+    rayon::spawn(move || {
+        let thumbnails = app_handle.state::<thumbnail_cache::ThumbnailCache>();
+        let raw_cache = app_handle.state::<raw_decode::RawPreviewCache>();
+        thumbnails.generate(generation, &originals, |original, long_edge| {
+            return image_source::serve_file(
+                &raw_cache,
+                &original.to_string_lossy(),
+                image_pipeline::RequestedSize::MaxDimension(long_edge)
+            );
+        });
+    });
+}
+
 #[tauri::command]
 async fn update_app_state_for_folder_and_emit_state(app_handle: tauri::AppHandle, state: tauri::State<'_, Mutex<AppState>>, folder: String, additive: bool) -> CommandResult<tauri::ipc::Response>
 {
-    update_app_state_for_folder(state, &folder, additive);
+    // a folder has no preview index, stop loading or watching the last catalog's
+    app_handle.state::<preview_index::PreviewIndexWatcher>().next_generation();
+    let originals = update_app_state_for_folder(state.clone(), &folder, additive);
+    generate_folder_thumbnails(&app_handle, originals);
     info!("emitting event {}", "shared-app-state-set");
     let _ = app_handle.emit("shared-app-state-set", {}).unwrap();
     Ok(Response::new(Vec::new()))
 }
 
 
-fn update_app_state_for_config(app_state: &tauri::State<'_, Mutex<AppState>>, conf_dirs: &LightroomConfDirs, _additive: &bool)
+// once the catalog's open, its previews are loaded on the async runtime. Any index we already
+// have keeps being served until the new one is complete, and previews.db is then watched
+// so the index is reloaded whenever lightroom changes it.
+fn start_preview_index(
+    app: &AppHandle,
+    generation: u64,
+    source: Arc<image_source::LightroomSource>,
+    live_dirs: LightroomConfDirs,
+    readable_preview_db_path: String,
+    schema: catalog_db::CatalogSchema
+)
 {
-    // TODO: BLOCKING IS BAD
-    // block_on(do_sql(&preview_db_path.unwrap()));
-    let image_id_to_image = block_on(do_sql(&conf_dirs.preview_db_path));
-    let mut mutable_app_state = app_state.lock().unwrap();
-    *mutable_app_state =  AppState {
-        shared: SharedAppState {
-            conf_dirs: Some(conf_dirs.clone()),
-            root_dir: None,
-            total_images: None
-        },
-        image_id_to_image: Some(image_id_to_image),
-        image_db_from_files: Vec::new(),
-        image_db_to_index: HashMap::new()
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let mut reload = false;
+        loop {
+            let last_modified = lr_snapshot::last_modified(&live_dirs.preview_db_path);
+            let loaded = if reload {
+                reload_preview_index(&app, generation, &source, &live_dirs, &schema).await
+            } else {
+                load_preview_index_into_state(&app, generation, &source, &readable_preview_db_path, &schema, false).await
+            };
+            if loaded.is_err() {
+                let err = anyhow::Error::from(loaded.unwrap_err()).context("Failed to load the preview index");
+                error!("{:#}", err);
+                let _ = app.emit(preview_index::FAILED_EVENT, format!("{:#}", err));
+            }
+            reload = true;
+            let watcher = app.state::<preview_index::PreviewIndexWatcher>();
+            if !watcher.wait_for_change(generation, &live_dirs.preview_db_path, last_modified).await {
+                return;
+            }
+        }
+    });
+}
+
+async fn load_preview_index_into_state(
+    app: &AppHandle,
+    generation: u64,
+    source: &image_source::LightroomSource,
+    preview_db_path: &str,
+    schema: &catalog_db::CatalogSchema,
+    reload: bool
+) -> CommandResult<()>
+{
+    info!("loading preview index from {}", preview_db_path);
+    let image_id_to_image = preview_index::load(preview_db_path, schema, |progress| {
+        let _ = app.emit(preview_index::PROGRESS_EVENT, progress);
+    }).await?;
+    let entries = image_id_to_image.len();
+    // a catalog or folder opened since has its own source, so there's nobody to tell
+    if !app.state::<preview_index::PreviewIndexWatcher>().is_current(generation) {
+        return Ok(());
+    }
+    source.set_preview_index(image_id_to_image);
+    info!("loaded {} preview index entries", entries);
+    let _ = app.emit(preview_index::LOADED_EVENT, preview_index::PreviewIndexLoaded { entries, reload });
+    return Ok(());
+}
+
+// while lightroom has the catalog open we read previews.db from a fresh copy of it
+async fn reload_preview_index(
+    app: &AppHandle,
+    generation: u64,
+    source: &image_source::LightroomSource,
+    live_dirs: &LightroomConfDirs,
+    schema: &catalog_db::CatalogSchema
+) -> CommandResult<()>
+{
+    let markers = lr_snapshot::in_use_markers(&live_dirs.cat_path, &[live_dirs.preview_db_path.as_str()]);
+    if markers.is_empty() {
+        return load_preview_index_into_state(app, generation, source, &live_dirs.preview_db_path, schema, true).await;
+    }
+    let snapshot = app.state::<Mutex<AppState>>().lock().unwrap().shared.snapshot.clone();
+    let preview_db_path = match snapshot {
+        Some(mut snapshot) => {
+            let copy = lr_snapshot::refresh_in_snapshot(&mut snapshot, &live_dirs.preview_db_path).await?;
+            // unless another catalog's been opened meanwhile, with a snapshot of its own
+            let app_state = app.state::<Mutex<AppState>>();
+            let mut locked_state = app_state.lock().unwrap();
+            let shared_snapshot = locked_state.shared.snapshot.as_mut();
+            if shared_snapshot.as_ref().is_some_and(|shared| shared.workspace == snapshot.workspace) {
+                *shared_snapshot.unwrap() = snapshot;
+            }
+            copy
+        }
+        None => {
+            let (copies, _) = lr_snapshot::take_snapshot(&[live_dirs.preview_db_path.as_str()], markers).await?;
+            copies[0].clone()
+        }
     };
+    return load_preview_index_into_state(app, generation, source, &preview_db_path, schema, true).await;
 }
 
+// the source is returned too, for start_preview_index to fill in
+fn app_state_for_catalog(catalog: LoadedCatalog) -> (AppState, Arc<image_source::LightroomSource>)
+{
+    let conf_dirs = catalog.shared.conf_dirs.as_ref().unwrap();
+    let source = Arc::new(image_source::LightroomSource::new(
+        conf_dirs.preview_root.clone(),
+        conf_dirs.smart_previews_root.clone(),
+        catalog.images,
+        catalog.image_id_to_uuid,
+        catalog.keyword_tree,
+        catalog.collection_tree
+    ));
+    let app_state = AppState {
+        shared: catalog.shared,
+        source: source.clone()
+    };
+    return (app_state, source);
+}
 
-#[tauri::command]
-async fn update_app_state_for_cat_and_emit_state(app_handle: tauri::AppHandle, state: tauri::State<'_, Mutex<AppState>>, cat: String, additive: bool) -> CommandResult<tauri::ipc::Response>
+async fn update_app_state_for_config(app: &AppHandle, app_state: &tauri::State<'_, Mutex<AppState>>, conf_dirs: &LightroomConfDirs, _additive: &bool) -> CommandResult<()>
 {
-    let conf_dirs = find_configuration_relative_to_catalog(&cat);
-    if conf_dirs.is_some()
+    let catalog = load_catalog(conf_dirs).await?;
+    let readable_preview_db_path = catalog.shared.conf_dirs.as_ref().unwrap().preview_db_path.clone();
+    let schema = catalog.schema.clone();
+    let generation = app.state::<preview_index::PreviewIndexWatcher>().next_generation();
+    let (updated_app_state, source) = app_state_for_catalog(catalog);
     {
-        let conf_val = conf_dirs.unwrap();
-        update_app_state_for_config(&state, &conf_val, &additive);
-        let _ = app_handle.emit("shared-app-state-set", {});
-        return Ok(Response::new(Vec::new()))
-    }
-    else
-    {
-        // emit error!
-        // let _ = app_handle.emit("shared-app-state-set", {}).unwrap();
-        return Err(ReflexCommandError::from(
-            anyhow::anyhow!("Failed to find the necessary components of the catalogue")
-        ));
+        let mut mutable_app_state = app_state.lock().unwrap();
+        *mutable_app_state = updated_app_state;
     }
+    start_preview_index(app, generation, source, conf_dirs.clone(), readable_preview_db_path, schema);
+    return Ok(());
 }
 
 
-fn initialise_app_state_for_config(app: &AppHandle, conf_dirs: &LightroomConfDirs)
+#[tauri::command]
+async fn update_app_state_for_cat_and_emit_state(
+    app_handle: tauri::AppHandle,
+    state: tauri::State<'_, Mutex<AppState>>,
+    cat: String,
+    additive: bool,
+    previews_dir: Option<String>,
+    helper_dir: Option<String>
+) -> CommandResult<tauri::ipc::Response>
 {
-    let image_id_to_image = block_on(do_sql(&conf_dirs.preview_db_path));
-    let app_state = AppState {
-        shared: SharedAppState {
-            conf_dirs: Some(conf_dirs.clone()),
-            root_dir: None,
-            total_images: None
-        },
-        image_id_to_image: Some(image_id_to_image),
-        image_db_from_files: Vec::new(),
-        image_db_to_index: HashMap::new()
-    };
-    app.manage(Mutex::new(app_state));
+    let overrides = lr_catalogs::CompanionOverrides { previews_dir, helper_dir };
+    let conf_val = find_configuration_relative_to_catalog(&cat, &overrides)?;
+    update_app_state_for_config(&app_handle, &state, &conf_val, &additive).await?;
+    let _ = app_handle.emit("shared-app-state-set", {});
+    return Ok(Response::new(Vec::new()))
 }
 
-fn reset_app_state_for_config(app: &AppHandle, conf_dirs: &LightroomConfDirs)
-{
-    let image_id_to_image = block_on(do_sql(&conf_dirs.preview_db_path));
-    let app_state = AppState {
-        shared: SharedAppState {
-            conf_dirs: Some(conf_dirs.clone()),
-            root_dir: None,
-            total_images: None
-        },
-        image_id_to_image: Some(image_id_to_image),
-        image_db_from_files: Vec::new(),
-        image_db_to_index: HashMap::new()
-    };
-    app.manage(Mutex::new(app_state));
-}
 
+// loading a catalog can take a while, so the app starts empty and the frontend is told once
+// the catalog we found is ready, just as when one is opened from the menu
 fn initialise_app_state(app: &AppHandle)
 {
+    app.manage(Mutex::new(AppState::empty()));
     let conf_dirs_maybe = find_configuration();
-    if conf_dirs_maybe.is_some()
-    {
-        let conf_dirs = conf_dirs_maybe.unwrap();
-        initialise_app_state_for_config(app, &conf_dirs);
-    }
-    else
+    if conf_dirs_maybe.is_none()
     {
-        let app_state = AppState {
-            shared: SharedAppState {
-                conf_dirs: None,
-                root_dir: None,
-                total_images: None
-            },
-            image_id_to_image: None,
-            image_db_from_files: Vec::new(),
-            image_db_to_index: HashMap::new()
-        };
-        app.manage(Mutex::new(app_state));
-    }
+        return;
+    }
+    let conf_dirs = conf_dirs_maybe.unwrap();
+    let app_handle = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let app_state = app_handle.state::<Mutex<AppState>>();
+        let loaded = update_app_state_for_config(&app_handle, &app_state, &conf_dirs, &false).await;
+        if let Err(err) = loaded {
+            error!("{}", err);
+            return;
+        }
+        let _ = app_handle.emit("shared-app-state-set", {});
+    });
     // let folder = "C:\\selected".to_string();
     // let additive = false;
     // let app_state = get_app_state_from_image_folder(&folder, additive);
@@ -688,9 +886,27 @@ pub fn run() {
         .plugin(tauri_plugin_dialog::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_system_info::init())
+        // decoding and resizing can take a while, so requests are answered off the webview's thread
+        .register_asynchronous_uri_scheme_protocol(image_protocol::SCHEME, |ctx, request, responder| {
+            let app_handle = ctx.app_handle().clone();
+            tauri::async_runtime::spawn_blocking(move || {
+                responder.respond(handle_image_protocol_request(&app_handle, &request));
+            });
+        })
         .setup(|app| {
             allow_detected_drives(app);
+            app.manage(preview_index::PreviewIndexWatcher::new());
             initialise_app_state(app.handle());
+            let cache_dir = app
+                .path()
+                .app_cache_dir()
+                .unwrap_or_else(|_| std::env::temp_dir().join("reflex"));
+            app.manage(raw_decode::RawPreviewCache::new(cache_dir.join("raw-previews")));
+            let data_dir = app
+                .path()
+                .app_data_dir()
+                .unwrap_or_else(|_| std::env::temp_dir().join("reflex"));
+            app.manage(thumbnail_cache::ThumbnailCache::new(data_dir.join("thumbnails")));
 
             // allowed the given directory
             // allow_all_ascii_drives(app);
@@ -768,7 +984,7 @@ pub fn run() {
             // dbg!(scope.allowed());
             Ok(())
         })
-        .invoke_handler(tauri::generate_handler![get_shared_app_state, get_image_for_id, get_total_available_images, get_available_images, update_app_state_for_folder_and_emit_state, update_app_state_for_cat_and_emit_state])
+        .invoke_handler(tauri::generate_handler![get_shared_app_state, get_image_for_id, get_total_available_images, get_available_images, update_app_state_for_folder_and_emit_state, update_app_state_for_cat_and_emit_state, list_known_catalogs, get_snapshot_age, get_thumbnail_cache_stats, clear_thumbnail_cache, audit_preview_cache, get_keyword_tree, get_collection_tree, get_edit_report])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,207 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Local};
+use glob::glob;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::ConnectOptions;
+use crate::agprefs;
+use crate::lr_discovery;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KnownCatalog {
+    pub path: String,
+    pub last_modified: Option<String>,
+    pub size_bytes: u64,
+    pub image_count: Option<u64>,
+    pub has_previews: bool,
+    pub has_helper: bool,
+}
+
+const PREVIEWS_SUFFIX : &str = "Previews.lrdata";
+const PREVIEWS_DB : &str = "previews.db";
+const HELPER_SUFFIX : &str = "Helper.lrdata";
+const HELPER_DB : &str = "metadatahelper.db";
+const SMART_PREVIEWS_SUFFIX : &str = "Smart Previews.lrdata";
+
+/// Alternative locations for a catalog's companions, e.g. previews kept on another drive.
+/// Each may name the `.lrdata` directory itself, or the directory containing it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CompanionOverrides {
+    pub previews_dir: Option<String>,
+    pub helper_dir: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompanionStatus {
+    pub expected_path: String,
+    pub found: bool,
+}
+
+/// Where we expect each of a catalog's companion databases, and whether they're there.
+#[derive(Clone, Debug, Serialize, Deserialize, thiserror::Error)]
+#[error("catalog {catalog} is missing {}", self.missing().join(" and "))]
+pub struct CompanionReport {
+    pub catalog: String,
+    pub catalog_found: bool,
+    pub previews: CompanionStatus,
+    pub helper: CompanionStatus,
+}
+
+impl CompanionReport {
+    pub fn missing(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        if !self.catalog_found {
+            missing.push(format!("the catalog file ({})", self.catalog));
+        }
+        if !self.previews.found {
+            missing.push(format!("the previews database ({})", self.previews.expected_path));
+        }
+        if !self.helper.found {
+            missing.push(format!("the helper database ({})", self.helper.expected_path));
+        }
+        return missing;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.catalog_found && self.previews.found && self.helper.found;
+    }
+}
+
+// lightroom names a catalog's companion directories after the catalog itself,
+// "<CatalogName> Previews.lrdata" alongside "<CatalogName>.lrcat"
+fn companion_dir(cat_path: &Path, suffix: &str, override_dir: Option<&String>) -> PathBuf {
+    let stem = cat_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let lrdata_name = format!("{} {}", stem, suffix);
+    return match override_dir {
+        Some(dir) if Path::new(dir).extension().is_some_and(|ext| ext == "lrdata") => PathBuf::from(dir),
+        Some(dir) => Path::new(dir).join(lrdata_name),
+        None => cat_path.parent().unwrap_or(Path::new("")).join(lrdata_name),
+    };
+}
+
+fn companion_status(path: PathBuf) -> CompanionStatus {
+    return CompanionStatus {
+        found: path.is_file(),
+        expected_path: path.to_string_lossy().into_owned(),
+    };
+}
+
+pub fn check_companions(cat_path: &Path, overrides: &CompanionOverrides) -> CompanionReport {
+    let previews = companion_dir(cat_path, PREVIEWS_SUFFIX, overrides.previews_dir.as_ref()).join(PREVIEWS_DB);
+    let helper = companion_dir(cat_path, HELPER_SUFFIX, overrides.helper_dir.as_ref()).join(HELPER_DB);
+    return CompanionReport {
+        catalog: cat_path.to_string_lossy().into_owned(),
+        catalog_found: cat_path.is_file(),
+        previews: companion_status(previews),
+        helper: companion_status(helper),
+    };
+}
+
+/// Smart previews are optional, so unlike the other companions a missing directory isn't reported.
+pub fn smart_previews_dir(cat_path: &Path) -> Option<PathBuf> {
+    let dir = companion_dir(cat_path, SMART_PREVIEWS_SUFFIX, None);
+    if dir.is_dir() {
+        return Some(dir);
+    }
+    return None;
+}
+
+async fn count_images(cat_path: &Path) -> Option<u64> {
+    let connection = SqliteConnectOptions::new()
+        .read_only(true)
+        .filename(cat_path);
+    let mut db = match connection.connect().await {
+        Ok(db) => db,
+        Err(err) => {
+            warn!("failed to open catalog {}: {}", cat_path.display(), err);
+            return None;
+        }
+    };
+    let count: Result<i64, _> = sqlx::query_scalar("select count(*) from Adobe_images")
+        .fetch_one(&mut db)
+        .await;
+    return match count {
+        Ok(count) => Some(count as u64),
+        Err(err) => {
+            warn!("failed to count images in {}: {}", cat_path.display(), err);
+            None
+        }
+    };
+}
+
+async fn describe_catalog(cat_path: &Path) -> Option<KnownCatalog> {
+    let metadata = fs::metadata(cat_path).ok()?;
+    let last_modified = metadata
+        .modified()
+        .ok()
+        .map(|time| DateTime::<Local>::from(time).to_rfc3339());
+    let companions = check_companions(cat_path, &CompanionOverrides::default());
+    return Some(KnownCatalog {
+        path: cat_path.to_string_lossy().into_owned(),
+        last_modified,
+        size_bytes: metadata.len(),
+        image_count: count_images(cat_path).await,
+        has_previews: companions.previews.found,
+        has_helper: companions.helper.found,
+    });
+}
+
+// the default and recent catalogs named by every preferences file we can find
+fn catalogs_from_preferences() -> Vec<PathBuf> {
+    let mut catalogs = Vec::new();
+    for candidate in lr_discovery::find_preferences_files() {
+        let prefs = match agprefs::read_preferences(&candidate.path) {
+            Ok(prefs) => prefs,
+            Err(err) => {
+                warn!("{:#}", anyhow::Error::from(err));
+                continue;
+            }
+        };
+        let named = prefs.default_catalog.into_iter().chain(prefs.recent_catalogs);
+        for lightroom_path in named {
+            if let Some(path) = lr_discovery::translate_lightroom_path(&lightroom_path, &candidate.origin) {
+                catalogs.push(path);
+            }
+        }
+    }
+    return catalogs;
+}
+
+fn catalogs_in_folder(folder: &Path) -> Vec<PathBuf> {
+    let pattern = folder.join("**").join("*.lrcat");
+    return match pattern.to_str().map(glob) {
+        Some(Ok(paths)) => paths.filter_map(|entry| entry.ok()).collect(),
+        _ => Vec::new(),
+    };
+}
+
+/// Every catalog we can find, from lightroom's preferences and the configured catalog folders.
+pub async fn list_known_catalogs() -> Vec<KnownCatalog> {
+    let mut candidates = catalogs_from_preferences();
+    for folder in lr_discovery::configured_catalog_folders() {
+        info!("scanning {} for catalogs", folder.display());
+        candidates.extend(catalogs_in_folder(&folder));
+    }
+
+    let mut seen = HashSet::new();
+    let mut catalogs = Vec::new();
+    for candidate in candidates {
+        if !candidate.is_file() {
+            continue;
+        }
+        let key = fs::canonicalize(&candidate).unwrap_or(candidate.clone());
+        if !seen.insert(key) {
+            continue;
+        }
+        if let Some(catalog) = describe_catalog(&candidate).await {
+            catalogs.push(catalog);
+        }
+    }
+    return catalogs;
+}
@@ -0,0 +1,262 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use glob::glob;
+use log::info;
+
+// Lightroom has renamed its preferences file a few times over the years,
+// ordered here from newest to oldest, so that the most recent install wins
+const PREFERENCES_FILENAMES : [&str; 6] = [
+    "Lightroom Classic CC 7 Preferences.agprefs",
+    "Lightroom Classic Preferences.agprefs",
+    "Lightroom 6 Preferences.agprefs",
+    "Lightroom 5 Preferences.agprefs",
+    "Lightroom 4 Preferences.agprefs",
+    "Lightroom 3 Preferences.agprefs",
+];
+
+// relative to a windows "roaming" profile directory (what APPDATA points at)
+const WINDOWS_PREFERENCES_RELPATH : &str = "Adobe/Lightroom/Preferences";
+
+// relative to a macOS home directory
+const MACOS_PREFERENCES_RELPATHS : [&str; 2] = [
+    "Library/Preferences/Adobe/Lightroom",
+    "Library/Application Support/Adobe/Lightroom/Preferences",
+];
+
+// wine has used both of these names for the roaming profile
+const WINE_ROAMING_RELPATHS : [&str; 2] = [
+    "AppData/Roaming",
+    "Application Data",
+];
+
+// additional wine prefixes to search, in the platform's PATH-list format
+pub const WINE_PREFIXES_ENV : &str = "REFLEX_WINE_PREFIXES";
+// folders to scan for catalogs, in the platform's PATH-list format
+pub const CATALOG_FOLDERS_ENV : &str = "REFLEX_CATALOG_FOLDERS";
+
+/// Where a preferences file was found, which decides how the windows-style
+/// paths stored inside it map onto this machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PreferencesOrigin {
+    Windows,
+    Wine { prefix: PathBuf },
+    MacOs { home: PathBuf },
+}
+
+#[derive(Clone, Debug)]
+pub struct PreferencesCandidate {
+    pub path: PathBuf,
+    pub origin: PreferencesOrigin,
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    return Some(PathBuf::from(home));
+}
+
+// the default prefix, $WINEPREFIX, and anything the user has configured
+pub fn wine_prefixes() -> Vec<PathBuf> {
+    let mut prefixes: Vec<PathBuf> = Vec::new();
+    if let Some(home) = home_dir() {
+        prefixes.push(home.join(".wine"));
+    }
+    if let Some(prefix) = env::var_os("WINEPREFIX") {
+        prefixes.push(PathBuf::from(prefix));
+    }
+    if let Some(configured) = env::var_os(WINE_PREFIXES_ENV) {
+        prefixes.extend(env::split_paths(&configured));
+    }
+    let mut unique: Vec<PathBuf> = Vec::new();
+    for prefix in prefixes {
+        if prefix.is_dir() && !unique.contains(&prefix) {
+            unique.push(prefix);
+        }
+    }
+    return unique;
+}
+
+pub fn configured_catalog_folders() -> Vec<PathBuf> {
+    return match env::var_os(CATALOG_FOLDERS_ENV) {
+        Some(configured) => env::split_paths(&configured)
+            .filter(|folder| folder.is_dir())
+            .collect(),
+        None => Vec::new(),
+    };
+}
+
+// every preferences file in a directory, known names first, then anything else
+// that looks like lightroom preferences (newest first)
+fn preferences_files_in(directory: &Path) -> Vec<PathBuf> {
+    let mut found: Vec<PathBuf> = PREFERENCES_FILENAMES
+        .iter()
+        .map(|name| directory.join(name))
+        .filter(|path| path.is_file())
+        .collect();
+
+    let pattern = directory.join("Lightroom*Preferences.agprefs");
+    let mut others: Vec<PathBuf> = match pattern.to_str().map(glob) {
+        Some(Ok(paths)) => paths
+            .filter_map(|entry| entry.ok())
+            .filter(|path| !found.contains(path))
+            .collect(),
+        _ => Vec::new(),
+    };
+    others.sort_by_key(|path| {
+        std::cmp::Reverse(fs::metadata(path).and_then(|m| m.modified()).ok())
+    });
+    found.extend(others);
+    return found;
+}
+
+fn push_candidates(candidates: &mut Vec<PreferencesCandidate>, directory: &Path, origin: &PreferencesOrigin) {
+    for path in preferences_files_in(directory) {
+        info!("found lightroom preferences {}", path.display());
+        candidates.push(PreferencesCandidate {
+            path,
+            origin: origin.clone(),
+        });
+    }
+}
+
+fn wine_user_dirs(prefix: &Path) -> Vec<PathBuf> {
+    let users = match resolve_case_insensitive(prefix, &["drive_c", "users"]) {
+        Some(users) => users,
+        None => return Vec::new(),
+    };
+    return match fs::read_dir(users) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+}
+
+/// Find every lightroom preferences file we know how to look for:
+/// `%APPDATA%` on windows, the roaming profiles of each wine prefix,
+/// and macOS-style `~/Library` trees.
+pub fn find_preferences_files() -> Vec<PreferencesCandidate> {
+    let mut candidates = Vec::new();
+
+    if let Some(app_data) = env::var_os("APPDATA") {
+        let directory = PathBuf::from(app_data).join(WINDOWS_PREFERENCES_RELPATH);
+        push_candidates(&mut candidates, &directory, &PreferencesOrigin::Windows);
+    }
+
+    for prefix in wine_prefixes() {
+        let origin = PreferencesOrigin::Wine { prefix: prefix.clone() };
+        for user_dir in wine_user_dirs(&prefix) {
+            for roaming in WINE_ROAMING_RELPATHS {
+                let relpath = Path::new(roaming).join(WINDOWS_PREFERENCES_RELPATH);
+                let components: Vec<String> = relpath
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                let component_refs: Vec<&str> = components.iter().map(|c| c.as_str()).collect();
+                if let Some(directory) = resolve_case_insensitive(&user_dir, &component_refs) {
+                    push_candidates(&mut candidates, &directory, &origin);
+                }
+            }
+        }
+    }
+
+    if let Some(home) = home_dir() {
+        let origin = PreferencesOrigin::MacOs { home: home.clone() };
+        for relpath in MACOS_PREFERENCES_RELPATHS {
+            push_candidates(&mut candidates, &home.join(relpath), &origin);
+        }
+    }
+
+    if candidates.is_empty() {
+        info!("no lightroom preferences files were found");
+    }
+    return candidates;
+}
+
+/// Join `components` onto `base`, matching each component case-insensitively
+/// when there is no exact match (windows paths are case-insensitive, but the
+/// disks they've been copied to usually aren't).
+pub fn resolve_case_insensitive(base: &Path, components: &[&str]) -> Option<PathBuf> {
+    let mut current = base.to_path_buf();
+    for component in components {
+        if component.is_empty() {
+            continue;
+        }
+        let exact = current.join(component);
+        if exact.exists() {
+            current = exact;
+            continue;
+        }
+        let matched = fs::read_dir(&current)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .find(|entry| entry.file_name().to_string_lossy().eq_ignore_ascii_case(component))?;
+        current = matched.path();
+    }
+    return Some(current);
+}
+
+// "C:\Users\me\Pictures" -> Some(('c', ["Users", "me", "Pictures"]))
+fn split_windows_path(windows_path: &str) -> Option<(char, Vec<&str>)> {
+    let mut chars = windows_path.chars();
+    let drive = chars.next()?;
+    if !drive.is_ascii_alphabetic() || chars.next()? != ':' {
+        return None;
+    }
+    let components = windows_path[2..]
+        .split(['\\', '/'])
+        .filter(|c| !c.is_empty())
+        .collect();
+    return Some((drive.to_ascii_lowercase(), components));
+}
+
+fn translate_for_wine(prefix: &Path, drive: char, components: &[&str]) -> Option<PathBuf> {
+    // dosdevices holds a symlink per drive letter, which is exactly the mapping wine uses
+    let device = prefix.join("dosdevices").join(format!("{}:", drive));
+    let drive_root = if device.exists() {
+        device
+    } else if drive == 'c' {
+        prefix.join("drive_c")
+    } else if drive == 'z' {
+        PathBuf::from("/")
+    } else {
+        return None;
+    };
+    return resolve_case_insensitive(&drive_root, components);
+}
+
+/// Translate a path written by lightroom into a path on this machine.
+/// Paths that aren't windows-style (e.g. from a macOS install) are returned unchanged.
+pub fn translate_lightroom_path(lightroom_path: &str, origin: &PreferencesOrigin) -> Option<PathBuf> {
+    let (drive, components) = match split_windows_path(lightroom_path) {
+        Some(split) => split,
+        None => return Some(PathBuf::from(lightroom_path)),
+    };
+    return match origin {
+        PreferencesOrigin::Windows => Some(PathBuf::from(lightroom_path)),
+        PreferencesOrigin::Wine { prefix } => translate_for_wine(prefix, drive, &components),
+        PreferencesOrigin::MacOs { .. } => {
+            // a windows path in a macOS tree, most likely a copied catalog,
+            // so try every wine prefix we know about
+            wine_prefixes()
+                .iter()
+                .find_map(|prefix| translate_for_wine(prefix, drive, &components))
+        }
+    };
+}
+
+/// Find an image (or other file) lightroom refers to, when we don't know which install wrote
+/// the path: as written if it exists, otherwise through each wine prefix we know about.
+pub fn locate_lightroom_path(lightroom_path: &str) -> Option<PathBuf> {
+    let as_written = PathBuf::from(lightroom_path);
+    if as_written.exists() {
+        return Some(as_written);
+    }
+    let (drive, components) = split_windows_path(lightroom_path)?;
+    return wine_prefixes()
+        .iter()
+        .find_map(|prefix| translate_for_wine(prefix, drive, &components))
+        .filter(|path| path.exists());
+}
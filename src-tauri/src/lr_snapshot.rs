@@ -0,0 +1,296 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Local};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::ConnectOptions;
+
+// sqlite's journal files, which sit alongside any database that's mid-write
+const SQLITE_COMPANION_SUFFIXES : [&str; 3] = ["-wal", "-shm", "-journal"];
+const REFRESH_DIR_NAME : &str = "refreshed";
+// lightroom's own marker that it has a catalog open
+const LIGHTROOM_LOCK_SUFFIX : &str = ".lock";
+// other instances of reflex share the snapshots root, so their workspaces are only removed once
+// they're old enough that nothing can still be reading them
+const STALE_SNAPSHOT_AGE_SECONDS : u64 = 24 * 60 * 60;
+// a copy of the files can catch lightroom mid-write, so is checked and taken again if it's torn
+const FILE_COPY_ATTEMPTS : usize = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error("failed to copy {path} into the snapshot workspace")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("failed to consolidate the snapshot of {path}")]
+    Database {
+        path: String,
+        #[source]
+        source: sqlx::Error,
+    },
+    #[error("every copy of {path} was caught mid-write, try again once lightroom is idle")]
+    Inconsistent { path: String },
+}
+
+/// Details of a snapshot, for the UI to show how stale the data might be.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub workspace: String,
+    pub taken_at: String,
+    pub taken_at_epoch_seconds: u64,
+    // the companion files that told us the catalog was in use
+    pub in_use_markers: Vec<String>,
+    // previews.db is copied again whenever lightroom changes it, the rest never is
+    pub previews_refreshed_at: Option<String>,
+    pub previews_refreshed_at_epoch_seconds: Option<u64>,
+}
+
+/// How old the copies being read are, in seconds.
+#[derive(Clone, Debug, Serialize)]
+pub struct SnapshotAge {
+    pub catalog_seconds: u64,
+    pub previews_seconds: u64,
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    return time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+}
+
+impl SnapshotInfo {
+    pub fn age(&self) -> SnapshotAge {
+        let now = epoch_seconds(SystemTime::now());
+        let previews_taken_at = self.previews_refreshed_at_epoch_seconds.unwrap_or(self.taken_at_epoch_seconds);
+        return SnapshotAge {
+            catalog_seconds: now.saturating_sub(self.taken_at_epoch_seconds),
+            previews_seconds: now.saturating_sub(previews_taken_at),
+        };
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    return PathBuf::from(name);
+}
+
+/// The lock and journal files that show lightroom has the catalog (or its companions) open.
+pub fn in_use_markers(cat_path: &str, db_paths: &[&str]) -> Vec<String> {
+    let mut markers = Vec::new();
+    let lock = with_suffix(Path::new(cat_path), LIGHTROOM_LOCK_SUFFIX);
+    if lock.exists() {
+        markers.push(lock.to_string_lossy().into_owned());
+    }
+    for db_path in db_paths {
+        for suffix in SQLITE_COMPANION_SUFFIXES {
+            let companion = with_suffix(Path::new(db_path), suffix);
+            if companion.exists() {
+                markers.push(companion.to_string_lossy().into_owned());
+            }
+        }
+    }
+    return markers;
+}
+
+fn snapshots_root() -> PathBuf {
+    return env::temp_dir().join("reflex-snapshots");
+}
+
+// workspaces are named "<epoch seconds>-<process id>"
+fn parse_workspace_name(name: &str) -> Option<(u64, u32)> {
+    let (epoch_seconds, process_id) = name.split_once('-')?;
+    return Some((epoch_seconds.parse().ok()?, process_id.parse().ok()?));
+}
+
+// this process's older snapshots, which it has moved on from, and anyone's long abandoned ones
+fn remove_stale_snapshots(keep: &Path, now_epoch_seconds: u64) {
+    let entries = match fs::read_dir(snapshots_root()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path == keep {
+            continue;
+        }
+        let workspace = parse_workspace_name(&entry.file_name().to_string_lossy());
+        if workspace.is_none() {
+            continue;
+        }
+        let (epoch_seconds, process_id) = workspace.unwrap();
+        let is_ours = process_id == std::process::id();
+        let is_abandoned = now_epoch_seconds.saturating_sub(epoch_seconds) > STALE_SNAPSHOT_AGE_SECONDS;
+        if !is_ours && !is_abandoned {
+            continue;
+        }
+        if let Err(err) = fs::remove_dir_all(&path) {
+            warn!("failed to remove stale snapshot {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn copy_file(from: &Path, to: &Path) -> Result<(), SnapshotError> {
+    fs::copy(from, to).map_err(|source| SnapshotError::Io {
+        path: from.to_string_lossy().into_owned(),
+        source,
+    })?;
+    return Ok(());
+}
+
+fn remove_copy(copy: &Path) -> Result<(), SnapshotError> {
+    let mut paths = vec![copy.to_path_buf()];
+    paths.extend(SQLITE_COMPANION_SUFFIXES.iter().map(|suffix| with_suffix(copy, suffix)));
+    for path in paths {
+        if path.exists() {
+            fs::remove_file(&path).map_err(|source| SnapshotError::Io {
+                path: path.to_string_lossy().into_owned(),
+                source,
+            })?;
+        }
+    }
+    return Ok(());
+}
+
+// sqlite writes a consistent copy itself, as of a single transaction, however busy lightroom is.
+// This needs a read lock, which lightroom doesn't give up while it holds a catalog exclusively
+async fn vacuum_into(original: &Path, copy: &Path) -> Result<(), sqlx::Error> {
+    let mut db = SqliteConnectOptions::new()
+        .read_only(true)
+        .filename(original)
+        .connect()
+        .await?;
+    sqlx::query("vacuum into ?")
+        .bind(copy.to_string_lossy().into_owned())
+        .execute(&mut db)
+        .await?;
+    return Ok(());
+}
+
+// fold any copied wal or hot journal back into the database file, so the copy can be
+// opened read-only without needing any of its companions. Returns whether the result is intact,
+// which a copy that caught a write halfway through may not be
+async fn consolidate(db_copy: &Path) -> Result<bool, SnapshotError> {
+    let map_err = |source| SnapshotError::Database {
+        path: db_copy.to_string_lossy().into_owned(),
+        source,
+    };
+    let mut db = SqliteConnectOptions::new()
+        .filename(db_copy)
+        .connect()
+        .await
+        .map_err(map_err)?;
+    sqlx::query("pragma wal_checkpoint(TRUNCATE)")
+        .execute(&mut db)
+        .await
+        .map_err(map_err)?;
+    sqlx::query("pragma journal_mode = DELETE")
+        .execute(&mut db)
+        .await
+        .map_err(map_err)?;
+    let check: String = sqlx::query_scalar("pragma quick_check")
+        .fetch_one(&mut db)
+        .await
+        .map_err(map_err)?;
+    return Ok(check == "ok");
+}
+
+// the files as they are, with their journals, for when sqlite can't read the original
+async fn copy_files(original: &Path, copy: &Path) -> Result<(), SnapshotError> {
+    for attempt in 1..=FILE_COPY_ATTEMPTS {
+        remove_copy(copy)?;
+        copy_file(original, copy)?;
+        for suffix in SQLITE_COMPANION_SUFFIXES {
+            let companion = with_suffix(original, suffix);
+            if companion.exists() {
+                copy_file(&companion, &with_suffix(copy, suffix))?;
+            }
+        }
+        // a torn copy can fail to open at all, as well as fail the check
+        match consolidate(copy).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => warn!("copy {} of {} failed its integrity check", attempt, original.display()),
+            Err(err) if attempt < FILE_COPY_ATTEMPTS => warn!("copy {} of {} is unreadable: {}", attempt, original.display(), err),
+            Err(err) => return Err(err),
+        }
+    }
+    return Err(SnapshotError::Inconsistent { path: original.to_string_lossy().into_owned() });
+}
+
+/// The latest modification time of a database or its journals, as writes in WAL mode only
+/// touch the -wal file until it's checkpointed. The -shm file changes on reads too, so is skipped.
+pub fn last_modified(db_path: &str) -> Option<SystemTime> {
+    let original = Path::new(db_path);
+    let mut paths = vec![original.to_path_buf()];
+    paths.extend(["-wal", "-journal"].iter().map(|suffix| with_suffix(original, suffix)));
+    return paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .max();
+}
+
+// copy a database and its journal files into `workspace`/`dir_name`, returning the copy's path
+async fn copy_into(workspace: &Path, dir_name: &str, db_path: &str) -> Result<String, SnapshotError> {
+    let original = Path::new(db_path);
+    let copy_dir = workspace.join(dir_name);
+    fs::create_dir_all(&copy_dir).map_err(|source| SnapshotError::Io {
+        path: copy_dir.to_string_lossy().into_owned(),
+        source,
+    })?;
+    let copy = copy_dir.join(original.file_name().unwrap_or(original.as_os_str()));
+    // vacuum won't write over an earlier copy, e.g. the last refresh
+    remove_copy(&copy)?;
+    match vacuum_into(original, &copy).await {
+        Ok(()) => {}
+        Err(err) => {
+            info!("copying the files of {}, as sqlite can't read it: {}", original.display(), err);
+            copy_files(original, &copy).await?;
+        }
+    }
+    return Ok(copy.to_string_lossy().into_owned());
+}
+
+/// Take a fresh copy of one database into an existing snapshot, leaving the copies already
+/// in it alone as they may still be open. Each refresh replaces the last one, and is recorded
+/// as when the snapshot's previews were taken.
+pub async fn refresh_in_snapshot(snapshot: &mut SnapshotInfo, db_path: &str) -> Result<String, SnapshotError> {
+    info!("refreshing {} in snapshot {}", db_path, snapshot.workspace);
+    let now = SystemTime::now();
+    let copy = copy_into(Path::new(&snapshot.workspace), REFRESH_DIR_NAME, db_path).await?;
+    snapshot.previews_refreshed_at = Some(DateTime::<Local>::from(now).to_rfc3339());
+    snapshot.previews_refreshed_at_epoch_seconds = Some(epoch_seconds(now));
+    return Ok(copy);
+}
+
+/// Copy each database, with its journal files, into a fresh temporary workspace.
+/// Returns the paths of the copies, in the same order as `db_paths`.
+pub async fn take_snapshot(db_paths: &[&str], in_use_markers: Vec<String>) -> Result<(Vec<String>, SnapshotInfo), SnapshotError> {
+    let now = SystemTime::now();
+    let taken_at_epoch_seconds = epoch_seconds(now);
+    let workspace = snapshots_root().join(format!("{}-{}", taken_at_epoch_seconds, std::process::id()));
+    fs::create_dir_all(&workspace).map_err(|source| SnapshotError::Io {
+        path: workspace.to_string_lossy().into_owned(),
+        source,
+    })?;
+    info!("taking snapshot into {}", workspace.display());
+
+    let mut copies = Vec::new();
+    for (index, db_path) in db_paths.iter().enumerate() {
+        // databases from different companions can share a file name, so keep them apart
+        copies.push(copy_into(&workspace, &index.to_string(), db_path).await?);
+    }
+
+    remove_stale_snapshots(&workspace, taken_at_epoch_seconds);
+    let info = SnapshotInfo {
+        workspace: workspace.to_string_lossy().into_owned(),
+        taken_at: DateTime::<Local>::from(now).to_rfc3339(),
+        taken_at_epoch_seconds,
+        in_use_markers,
+        previews_refreshed_at: None,
+        previews_refreshed_at_epoch_seconds: None,
+    };
+    return Ok((copies, info));
+}
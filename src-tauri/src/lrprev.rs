@@ -1,16 +1,396 @@
 use image::codecs::jpeg::JpegDecoder;
 use image::DynamicImage;
-use std::fs;
-use std::io::Cursor;
+use std::fs::{self, File};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::image_pipeline::{self, RequestedSize};
+use crate::lua_table::{self, LuaParseError, LuaValue};
 
-fn find_subsequence<T>(container: &[T], search_term: &[T]) -> Option<usize>
-where
-    for<'a> &'a [T]: PartialEq,
-{
-    // pinched from https://stackoverflow.com/questions/35901547/how-can-i-find-a-subsequence-in-a-u8-slice
-    container
-        .windows(search_term.len())
-        .position(|window| window == search_term)
+// An lrprev file is a sequence of sections, each of them
+//   "AgHg"                  4 bytes, magic
+//   header length           u16, big endian, counted from the start of the magic
+//   version                 u8
+//   kind                    u8
+//   data length             u64, big endian
+//   padding length          u64, big endian
+//   name                    (header length - 24) bytes, nul padded, e.g. "header" or "level_3"
+// followed by the data and then the padding.
+// The "header" section holds a lua table describing the pyramid, and each "level_N"
+// section holds a jpeg, with level_1 the smallest.
+const SECTION_MAGIC : &[u8; 4] = b"AgHg";
+const FIXED_HEADER_LENGTH : usize = 24;
+const HEADER_SECTION_NAME : &str = "header";
+const LEVEL_SECTION_PREFIX : &str = "level_";
+
+// previews are kept in "<CatalogName> Previews.lrdata" as
+// <first character of uuid>/<first four characters of uuid>/<uuid>-<digest>.lrprev
+pub const LRPREV_EXTENSION : &str = "lrprev";
+
+const JPEG_SOI : [u8; 2] = [0xFF, 0xD8];
+const JPEG_EOI : [u8; 2] = [0xFF, 0xD9];
+
+#[derive(Debug, thiserror::Error)]
+pub enum LrPrevError {
+    #[error("failed to read preview file {path}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("expected a section header at offset {offset}")]
+    BadMagic { offset: u64 },
+    #[error("section header at offset {offset} has an invalid length {header_length}")]
+    BadHeaderLength { offset: u64, header_length: u16 },
+    #[error("section {name} at offset {offset} runs past the end of the file")]
+    Truncated { name: String, offset: u64 },
+    #[error("section at offset {offset} has lengths too large to be real")]
+    Malformed { offset: u64 },
+    #[error("failed to parse the pyramid description")]
+    Metadata(#[from] LuaParseError),
+    #[error("level {level} is not a valid jpeg: {reason}")]
+    InvalidJpeg { level: usize, reason: &'static str },
+    #[error("failed to read preview file")]
+    Read(#[from] std::io::Error),
+    #[error("preview file contains no levels")]
+    NoLevels,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LrPrevSection {
+    pub name: String,
+    pub header_length: u16,
+    pub version: u8,
+    pub kind: u8,
+    // offset of the section's data, from the start of the file
+    pub data_offset: u64,
+    pub data_length: u64,
+    pub padding_length: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PyramidLevel {
+    // 1-based, as in the section names
+    pub level: usize,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    // where the level's jpeg sits in the file
+    pub data_offset: u64,
+    pub data_length: u64,
+}
+
+/// The index of an lrprev file: the pyramid's description, and where each level's jpeg sits.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LrPrev {
+    pub uuid: Option<String>,
+    pub digest: Option<String>,
+    pub quality: Option<String>,
+    pub color_profile: Option<String>,
+    pub cropped_width: Option<u32>,
+    pub cropped_height: Option<u32>,
+    pub sections: Vec<LrPrevSection>,
+    // ordered smallest to largest
+    pub levels: Vec<PyramidLevel>,
+}
+
+/// The description held in the "header" section.
+#[derive(Clone, Debug, Default)]
+pub struct PyramidInfo {
+    pub uuid: Option<String>,
+    pub digest: Option<String>,
+    pub quality: Option<String>,
+    pub color_profile: Option<String>,
+    pub cropped_width: Option<u32>,
+    pub cropped_height: Option<u32>,
+    // (width, height) per level, smallest first
+    pub level_dimensions: Vec<(Option<u32>, Option<u32>)>,
+}
+
+fn read_u64_be(bytes: &[u8]) -> u64 {
+    let mut buffer = [0u8; 8];
+    buffer.copy_from_slice(&bytes[..8]);
+    return u64::from_be_bytes(buffer);
+}
+
+/// Parse the fixed part of a section header (and its name) from `bytes`,
+/// which must start at the section's magic.
+pub fn parse_section_header(bytes: &[u8], offset: u64) -> Result<LrPrevSection, LrPrevError> {
+    if bytes.len() < FIXED_HEADER_LENGTH || &bytes[0..4] != SECTION_MAGIC {
+        return Err(LrPrevError::BadMagic { offset });
+    }
+    let header_length = u16::from_be_bytes([bytes[4], bytes[5]]);
+    if (header_length as usize) < FIXED_HEADER_LENGTH {
+        return Err(LrPrevError::BadHeaderLength { offset, header_length });
+    }
+    if bytes.len() < header_length as usize {
+        return Err(LrPrevError::Truncated { name: "section header".to_owned(), offset });
+    }
+    let name_bytes = &bytes[FIXED_HEADER_LENGTH..header_length as usize];
+    let name_end = name_bytes.iter().position(|b| *b == 0).unwrap_or(name_bytes.len());
+    let data_offset = offset
+        .checked_add(header_length as u64)
+        .ok_or(LrPrevError::Malformed { offset })?;
+    return Ok(LrPrevSection {
+        name: String::from_utf8_lossy(&name_bytes[..name_end]).into_owned(),
+        header_length,
+        version: bytes[6],
+        kind: bytes[7],
+        data_offset,
+        data_length: read_u64_be(&bytes[8..16]),
+        padding_length: read_u64_be(&bytes[16..24]),
+    });
+}
+
+impl LrPrevSection {
+    /// Where the section's data ends. The lengths come from the file, so may not add up.
+    pub fn data_end_offset(&self) -> Result<u64, LrPrevError> {
+        return self.data_offset
+            .checked_add(self.data_length)
+            .ok_or(LrPrevError::Malformed { offset: self.data_offset - self.header_length as u64 });
+    }
+
+    /// Where the next section starts.
+    pub fn end_offset(&self) -> Result<u64, LrPrevError> {
+        return self.data_end_offset()?
+            .checked_add(self.padding_length)
+            .ok_or(LrPrevError::Malformed { offset: self.data_offset - self.header_length as u64 });
+    }
+
+    /// For "level_N" sections, N.
+    pub fn level(&self) -> Option<usize> {
+        return self.name.strip_prefix(LEVEL_SECTION_PREFIX)?.parse::<usize>().ok();
+    }
+}
+
+fn as_dimension(value: Option<&LuaValue>) -> Option<u32> {
+    return value.and_then(|v| v.as_f64()).map(|n| n as u32);
+}
+
+/// Parse the lua description in the "header" section, e.g.
+/// `pyramid = { quality = "standard", levels = { { width = 160, height = 107 }, ... }, ... }`
+pub fn parse_pyramid_info(header_data: &[u8]) -> Result<PyramidInfo, LrPrevError> {
+    let text = String::from_utf8_lossy(header_data);
+    let text = text.trim_end_matches('\0');
+    let assignments = lua_table::parse_assignments(text)?;
+    let pyramid = match assignments.iter().find_map(|(_, value)| value.as_table()) {
+        Some(pyramid) => pyramid,
+        None => return Ok(PyramidInfo::default()),
+    };
+    let as_string = |key: &str| pyramid.get(key).and_then(|v| v.as_str()).map(|s| s.to_owned());
+    let level_dimensions = pyramid
+        .get("levels")
+        .and_then(|v| v.as_table())
+        .map(|levels| {
+            levels
+                .array()
+                .into_iter()
+                .map(|level| {
+                    let level = level.as_table();
+                    (
+                        as_dimension(level.and_then(|l| l.get("width"))),
+                        as_dimension(level.and_then(|l| l.get("height"))),
+                    )
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    return Ok(PyramidInfo {
+        uuid: as_string("uuid"),
+        digest: as_string("digest"),
+        quality: as_string("quality"),
+        color_profile: as_string("colorProfile"),
+        cropped_width: as_dimension(pyramid.get("croppedWidth")),
+        cropped_height: as_dimension(pyramid.get("croppedHeight")),
+        level_dimensions,
+    });
+}
+
+pub fn validate_jpeg(level: usize, jpeg: &[u8]) -> Result<(), LrPrevError> {
+    if jpeg.len() < 4 || jpeg[0..2] != JPEG_SOI {
+        return Err(LrPrevError::InvalidJpeg { level, reason: "missing start of image marker" });
+    }
+    if jpeg[jpeg.len() - 2..] != JPEG_EOI {
+        return Err(LrPrevError::InvalidJpeg { level, reason: "missing end of image marker" });
+    }
+    return Ok(());
+}
+
+// reads the next section header, or None at the end of the file
+// (files are sometimes padded out past the last section)
+fn read_section_header<R: Read + Seek>(reader: &mut R, offset: u64, file_length: u64) -> Result<Option<LrPrevSection>, LrPrevError> {
+    if offset >= file_length {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut fixed = [0u8; FIXED_HEADER_LENGTH];
+    let available = (file_length - offset).min(FIXED_HEADER_LENGTH as u64) as usize;
+    reader.read_exact(&mut fixed[..available])?;
+    if fixed[..available].iter().all(|b| *b == 0) {
+        return Ok(None);
+    }
+    if available < FIXED_HEADER_LENGTH {
+        return Err(LrPrevError::Truncated { name: "section header".to_owned(), offset });
+    }
+    let header_length = u16::from_be_bytes([fixed[4], fixed[5]]) as usize;
+    let mut header = fixed.to_vec();
+    if header_length > FIXED_HEADER_LENGTH {
+        // offset is below file_length, so this can't overflow
+        if header_length as u64 > file_length - offset {
+            return Err(LrPrevError::Truncated { name: "section header".to_owned(), offset });
+        }
+        header.resize(header_length, 0);
+        reader.read_exact(&mut header[FIXED_HEADER_LENGTH..])?;
+    }
+    let section = parse_section_header(&header, offset)?;
+    if section.data_end_offset()? > file_length {
+        return Err(LrPrevError::Truncated { name: section.name, offset });
+    }
+    return Ok(Some(section));
+}
+
+fn read_section_data<R: Read + Seek>(reader: &mut R, section: &LrPrevSection) -> Result<Vec<u8>, LrPrevError> {
+    reader.seek(SeekFrom::Start(section.data_offset))?;
+    let mut data = vec![0u8; section.data_length as usize];
+    reader.read_exact(&mut data)?;
+    return Ok(data);
+}
+
+/// Read the section headers and the pyramid description, seeking past the jpegs
+/// themselves, which can then be read individually with `read_level_from`.
+pub fn read_index_from<R: Read + Seek>(reader: &mut R) -> Result<LrPrev, LrPrevError> {
+    let file_length = reader.seek(SeekFrom::End(0))?;
+    let mut sections = Vec::new();
+    let mut offset: u64 = 0;
+    while let Some(section) = read_section_header(reader, offset, file_length)? {
+        offset = section.end_offset()?;
+        sections.push(section);
+    }
+
+    let info = match sections.iter().find(|s| s.name == HEADER_SECTION_NAME) {
+        Some(header) => parse_pyramid_info(&read_section_data(reader, header)?)?,
+        None => PyramidInfo::default(),
+    };
+
+    let mut levels = Vec::new();
+    for section in sections.iter() {
+        let level = match section.level() {
+            Some(level) => level,
+            None => continue,
+        };
+        let (width, height) = info
+            .level_dimensions
+            .get(level.wrapping_sub(1))
+            .copied()
+            .unwrap_or((None, None));
+        levels.push(PyramidLevel {
+            level,
+            width,
+            height,
+            data_offset: section.data_offset,
+            data_length: section.data_length,
+        });
+    }
+    if levels.is_empty() {
+        return Err(LrPrevError::NoLevels);
+    }
+    levels.sort_by_key(|level| level.level);
+
+    return Ok(LrPrev {
+        uuid: info.uuid,
+        digest: info.digest,
+        quality: info.quality,
+        color_profile: info.color_profile,
+        cropped_width: info.cropped_width,
+        cropped_height: info.cropped_height,
+        sections,
+        levels,
+    });
+}
+
+/// Read just one level's jpeg.
+pub fn read_level_from<R: Read + Seek>(reader: &mut R, level: &PyramidLevel) -> Result<Vec<u8>, LrPrevError> {
+    reader.seek(SeekFrom::Start(level.data_offset))?;
+    let mut jpeg = vec![0u8; level.data_length as usize];
+    reader.read_exact(&mut jpeg)?;
+    validate_jpeg(level.level, &jpeg)?;
+    return Ok(jpeg);
+}
+
+pub fn preview_path(preview_root: &Path, uuid: &str, digest: &str) -> PathBuf {
+    let first: String = uuid.chars().take(1).collect();
+    let prefix: String = uuid.chars().take(4).collect();
+    return preview_root
+        .join(first)
+        .join(prefix)
+        .join(format!("{}-{}.{}", uuid, digest, LRPREV_EXTENSION));
+}
+
+/// The uuid and digest from an lrprev's file name. Digests are hex, so the last
+/// hyphen is the one between them.
+pub fn parse_preview_file_name(path: &Path) -> Option<(String, String)> {
+    if path.extension().and_then(|extension| extension.to_str()) != Some(LRPREV_EXTENSION) {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let (uuid, digest) = stem.rsplit_once('-')?;
+    if uuid.is_empty() || digest.is_empty() {
+        return None;
+    }
+    return Some((uuid.to_owned(), digest.to_owned()));
+}
+
+fn open(name: &str) -> Result<BufReader<File>, LrPrevError> {
+    let file = File::open(name).map_err(|source| LrPrevError::Io {
+        path: name.to_owned(),
+        source,
+    })?;
+    return Ok(BufReader::new(file));
+}
+
+pub fn read_lrprev_index(name: &str) -> Result<LrPrev, LrPrevError> {
+    return read_index_from(&mut open(name)?);
+}
+
+pub fn read_level(name: &str, level: &PyramidLevel) -> Result<Vec<u8>, LrPrevError> {
+    return read_level_from(&mut open(name)?, level);
+}
+
+/// Read the index, and then only the level that best suits the request.
+pub fn read_level_for_request(name: &str, requested: RequestedSize) -> Result<(PyramidLevel, Vec<u8>), LrPrevError> {
+    let mut reader = open(name)?;
+    let index = read_index_from(&mut reader)?;
+    let level = choose_level(&index.levels, requested)
+        .ok_or(LrPrevError::NoLevels)?
+        .clone();
+    let jpeg = read_level_from(&mut reader, &level)?;
+    return Ok((level, jpeg));
+}
+
+impl PyramidLevel {
+    /// (width, height), when the pyramid description records them.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            return Some((width, height));
+        }
+        return None;
+    }
+}
+
+/// The smallest level that covers the requested size, or the largest level if none do.
+/// `levels` must be ordered smallest to largest.
+pub fn choose_level(levels: &[PyramidLevel], requested: RequestedSize) -> Option<&PyramidLevel> {
+    return match requested {
+        RequestedSize::Smallest => levels.first(),
+        RequestedSize::Largest => levels.last(),
+        RequestedSize::MaxDimension(max_dimension) => levels
+            .iter()
+            .find(|level| {
+                level
+                    .dimensions()
+                    .is_some_and(|(width, height)| image_pipeline::long_edge(width, height) >= max_dimension)
+            })
+            .or(levels.last()),
+    };
 }
 
 // todo: this should return Result<DynamicImage, err> with a custom error type
@@ -32,42 +412,26 @@ fn jpeg_from_bytes(bytes: &[u8], output_jpeg: bool) -> Option<DynamicImage> {
     return Some(final_image);
 }
 
+// reads the whole file into memory and copies out every level,
+// prefer read_level_for_request when only one level is needed
 pub fn get_jpeg_byte_segments_from_file(
     name: &str,
-) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
-    let data: Vec<u8> = fs::read(name)?;
-    let mut recorded_starts: Vec<usize> = Vec::new();
-    for i in 1..10 {
-        let level_s = format!("level_{i}");
-        let level_s_as_bytes = level_s.as_bytes().to_owned();
-        let last_start = recorded_starts.last().unwrap_or(&(0usize)).to_owned();
-        let found_search_term = find_subsequence(&data[last_start..], &level_s_as_bytes);
-        if found_search_term.is_some() {
-            let jpeg_start = last_start + found_search_term.unwrap() + level_s_as_bytes.len() + 1;
-            recorded_starts.push(jpeg_start);
-        } else {
-            break;
-        }
+) -> Result<Vec<Vec<u8>>, LrPrevError> {
+    let data: Vec<u8> = fs::read(name).map_err(|source| LrPrevError::Io {
+        path: name.to_owned(),
+        source,
+    })?;
+    let mut reader = Cursor::new(&data);
+    let index = read_index_from(&mut reader)?;
+    let mut segments = Vec::new();
+    for level in index.levels.iter() {
+        segments.push(read_level_from(&mut reader, level)?);
     }
-    let bytes_sequences = (0..recorded_starts.len())
-        .map(|range_index| {
-            if range_index != recorded_starts.len() - 1 {
-                return data[recorded_starts[range_index]..recorded_starts[range_index + 1]]
-                    .to_vec();
-            } else {
-                return data[recorded_starts[range_index]..].to_vec();
-            }
-        })
-        .collect();
-    return Ok(bytes_sequences);
+    return Ok(segments);
 }
 
-pub fn get_jpegs_from_file(name: &str) -> Result<Vec<DynamicImage>, Box<dyn std::error::Error>> {
-    let byte_sequence_result = get_jpeg_byte_segments_from_file(name);
-    if byte_sequence_result.is_err() {
-        return Err(byte_sequence_result.err().unwrap());
-    }
-    let byte_sequences = byte_sequence_result.unwrap();
+pub fn get_jpegs_from_file(name: &str) -> Result<Vec<DynamicImage>, LrPrevError> {
+    let byte_sequences = get_jpeg_byte_segments_from_file(name)?;
     let jpegs: Vec<Option<DynamicImage>> = byte_sequences
         .into_iter()
         .map(|s| jpeg_from_bytes(&s, false))
@@ -0,0 +1,597 @@
+// Lightroom serialises most of its structured data (agprefs files, smart collection rules,
+// develop settings) as Lua table literals. This is a parser for that literal subset of Lua:
+// strings (including long brackets), numbers, booleans, nil and nested tables, with
+// `name = value` assignments at the top level. It does not evaluate any code.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaKey {
+    Index(i64),
+    Name(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Table(LuaTable),
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LuaTable {
+    pub entries: Vec<(LuaKey, LuaValue)>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{message} at line {line}, column {column}")]
+pub struct LuaParseError {
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl LuaValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            LuaValue::String(s) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            LuaValue::Number(n) => Some(*n),
+            // lightroom is not consistent about quoting numbers
+            LuaValue::String(s) => s.trim().parse::<f64>().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            LuaValue::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&LuaTable> {
+        match self {
+            LuaValue::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+impl LuaTable {
+    pub fn get(&self, name: &str) -> Option<&LuaValue> {
+        self.entries.iter().find_map(|(key, value)| match key {
+            LuaKey::Name(n) if n == name => Some(value),
+            _ => None,
+        })
+    }
+
+    /// The positional (array-like) part of the table, in index order.
+    pub fn array(&self) -> Vec<&LuaValue> {
+        let mut indexed: Vec<(i64, &LuaValue)> = self
+            .entries
+            .iter()
+            .filter_map(|(key, value)| match key {
+                LuaKey::Index(i) => Some((*i, value)),
+                _ => None,
+            })
+            .collect();
+        indexed.sort_by_key(|(i, _)| *i);
+        return indexed.into_iter().map(|(_, value)| value).collect();
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> Self {
+        Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+        }
+    }
+
+    fn error(&self, message: &str) -> LuaParseError {
+        let consumed: String = self.chars[..self.pos.min(self.chars.len())].iter().collect();
+        let line = consumed.matches('\n').count() + 1;
+        let column = consumed.len() - consumed.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        return LuaParseError {
+            message: message.to_owned(),
+            line,
+            column,
+        };
+    }
+
+    fn peek(&self) -> Option<char> {
+        return self.chars.get(self.pos).copied();
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        return self.chars.get(self.pos + offset).copied();
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek();
+        if c.is_some() {
+            self.pos += 1;
+        }
+        return c;
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), LuaParseError> {
+        self.skip_trivia()?;
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            return Ok(());
+        }
+        return Err(self.error(&format!("expected '{}'", expected)));
+    }
+
+    fn at_end(&mut self) -> Result<bool, LuaParseError> {
+        self.skip_trivia()?;
+        return Ok(self.peek().is_none());
+    }
+
+    // whitespace and comments, both `-- line` and `--[[ block ]]`
+    fn skip_trivia(&mut self) -> Result<(), LuaParseError> {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.pos += 1;
+                }
+                Some('-') if self.peek_at(1) == Some('-') => {
+                    self.pos += 2;
+                    if self.peek() == Some('[') && self.long_bracket_level().is_some() {
+                        self.parse_long_string()?;
+                    } else {
+                        while let Some(c) = self.bump() {
+                            if c == '\n' {
+                                break;
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    // for `[==[` returns Some(2), without consuming anything
+    fn long_bracket_level(&self) -> Option<usize> {
+        if self.peek() != Some('[') {
+            return None;
+        }
+        let mut level = 0;
+        while self.peek_at(1 + level) == Some('=') {
+            level += 1;
+        }
+        if self.peek_at(1 + level) == Some('[') {
+            return Some(level);
+        }
+        return None;
+    }
+
+    fn parse_long_string(&mut self) -> Result<String, LuaParseError> {
+        let level = self
+            .long_bracket_level()
+            .ok_or_else(|| self.error("expected long string"))?;
+        self.pos += level + 2;
+        // a newline straight after the opening bracket is not part of the string
+        if self.peek() == Some('\r') {
+            self.pos += 1;
+        }
+        if self.peek() == Some('\n') {
+            self.pos += 1;
+        }
+        let mut value = String::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error("unterminated long string")),
+                Some(']') => {
+                    let closes = (0..level).all(|i| self.peek_at(i) == Some('='))
+                        && self.peek_at(level) == Some(']');
+                    if closes {
+                        self.pos += level + 1;
+                        return Ok(value);
+                    }
+                    value.push(']');
+                }
+                Some(c) => value.push(c),
+            }
+        }
+    }
+
+    fn parse_quoted_string(&mut self) -> Result<String, LuaParseError> {
+        let quote = self.bump().ok_or_else(|| self.error("expected string"))?;
+        let mut value = String::new();
+        loop {
+            let c = match self.bump() {
+                None => return Err(self.error("unterminated string")),
+                Some(c) => c,
+            };
+            if c == quote {
+                return Ok(value);
+            }
+            if c == '\n' {
+                return Err(self.error("unescaped newline in string"));
+            }
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let escaped = self.bump().ok_or_else(|| self.error("unterminated escape"))?;
+            match escaped {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                'r' => value.push('\r'),
+                'a' => value.push('\u{07}'),
+                'b' => value.push('\u{08}'),
+                'f' => value.push('\u{0C}'),
+                'v' => value.push('\u{0B}'),
+                '\\' => value.push('\\'),
+                '"' => value.push('"'),
+                '\'' => value.push('\''),
+                // a backslash before a line break continues the string onto the next line
+                '\n' => {
+                    value.push('\n');
+                    if self.peek() == Some('\r') {
+                        self.pos += 1;
+                    }
+                }
+                '\r' => {
+                    value.push('\n');
+                    if self.peek() == Some('\n') {
+                        self.pos += 1;
+                    }
+                }
+                'z' => {
+                    while self.peek().is_some_and(|c| c.is_whitespace()) {
+                        self.pos += 1;
+                    }
+                }
+                'x' => {
+                    let hex: String = (0..2).filter_map(|_| self.bump()).collect();
+                    let code = u8::from_str_radix(&hex, 16)
+                        .map_err(|_| self.error("invalid hex escape"))?;
+                    value.push(code as char);
+                }
+                'u' => {
+                    self.expect('{')?;
+                    let mut hex = String::new();
+                    while let Some(c) = self.bump() {
+                        if c == '}' {
+                            break;
+                        }
+                        hex.push(c);
+                    }
+                    let code = u32::from_str_radix(&hex, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| self.error("invalid unicode escape"))?;
+                    value.push(code);
+                }
+                d if d.is_ascii_digit() => {
+                    let mut digits = d.to_string();
+                    while digits.len() < 3 && self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        digits.push(self.bump().unwrap());
+                    }
+                    let code = digits
+                        .parse::<u8>()
+                        .map_err(|_| self.error("invalid decimal escape"))?;
+                    value.push(code as char);
+                }
+                _ => return Err(self.error(&format!("invalid escape '\\{}'", escaped))),
+            }
+        }
+    }
+
+    fn parse_name(&mut self) -> Result<String, LuaParseError> {
+        self.skip_trivia()?;
+        let mut name = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                name.push(c);
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error("expected name"));
+        }
+        return Ok(name);
+    }
+
+    fn parse_number(&mut self) -> Result<f64, LuaParseError> {
+        let start = self.pos;
+        if self.peek() == Some('-') || self.peek() == Some('+') {
+            self.pos += 1;
+        }
+        if self.peek() == Some('0') && matches!(self.peek_at(1), Some('x') | Some('X')) {
+            self.pos += 2;
+            let digits_start = self.pos;
+            while self.peek().is_some_and(|c| c.is_ascii_hexdigit()) {
+                self.pos += 1;
+            }
+            let digits: String = self.chars[digits_start..self.pos].iter().collect();
+            let magnitude = i64::from_str_radix(&digits, 16)
+                .map_err(|_| self.error("invalid hex number"))? as f64;
+            let negative = self.chars[start] == '-';
+            return Ok(if negative { -magnitude } else { magnitude });
+        }
+        while let Some(c) = self.peek() {
+            let exponent_sign = (c == '-' || c == '+')
+                && matches!(self.chars.get(self.pos - 1), Some('e') | Some('E'));
+            if c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || exponent_sign {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        return text
+            .parse::<f64>()
+            .map_err(|_| self.error(&format!("invalid number '{}'", text)));
+    }
+
+    fn parse_value(&mut self) -> Result<LuaValue, LuaParseError> {
+        self.skip_trivia()?;
+        match self.peek() {
+            None => Err(self.error("expected value")),
+            Some('{') => Ok(LuaValue::Table(self.parse_table()?)),
+            Some('"') | Some('\'') => Ok(LuaValue::String(self.parse_quoted_string()?)),
+            Some('[') if self.long_bracket_level().is_some() => {
+                Ok(LuaValue::String(self.parse_long_string()?))
+            }
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' => {
+                Ok(LuaValue::Number(self.parse_number()?))
+            }
+            Some(_) => {
+                let word = self.parse_name()?;
+                match word.as_str() {
+                    "nil" => Ok(LuaValue::Nil),
+                    "true" => Ok(LuaValue::Boolean(true)),
+                    "false" => Ok(LuaValue::Boolean(false)),
+                    _ => Err(self.error(&format!("unexpected identifier '{}'", word))),
+                }
+            }
+        }
+    }
+
+    fn parse_table(&mut self) -> Result<LuaTable, LuaParseError> {
+        self.expect('{')?;
+        let mut table = LuaTable::default();
+        let mut next_index: i64 = 1;
+        loop {
+            self.skip_trivia()?;
+            match self.peek() {
+                None => return Err(self.error("unterminated table")),
+                Some('}') => {
+                    self.pos += 1;
+                    return Ok(table);
+                }
+                _ => (),
+            }
+
+            let key = if self.peek() == Some('[') && self.long_bracket_level().is_none() {
+                // [expr] = value
+                self.pos += 1;
+                let key_value = self.parse_value()?;
+                self.expect(']')?;
+                self.expect('=')?;
+                match key_value {
+                    LuaValue::String(s) => Some(LuaKey::Name(s)),
+                    LuaValue::Number(n) if n.fract() == 0.0 => Some(LuaKey::Index(n as i64)),
+                    _ => return Err(self.error("unsupported table key")),
+                }
+            } else if self.is_name_assignment() {
+                let name = self.parse_name()?;
+                self.expect('=')?;
+                Some(LuaKey::Name(name))
+            } else {
+                None
+            };
+
+            let value = self.parse_value()?;
+            let key = match key {
+                Some(key) => key,
+                None => {
+                    let index = next_index;
+                    next_index += 1;
+                    LuaKey::Index(index)
+                }
+            };
+            table.entries.push((key, value));
+
+            self.skip_trivia()?;
+            match self.peek() {
+                Some(',') | Some(';') => {
+                    self.pos += 1;
+                }
+                Some('}') => (),
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn is_keyword_at(&self, keyword: &str) -> bool {
+        let matches = keyword
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(i) == Some(c));
+        let boundary = self
+            .peek_at(keyword.len())
+            .is_none_or(|c| !(c.is_alphanumeric() || c == '_'));
+        return matches && boundary;
+    }
+
+    // looks ahead for `name =` (but not `name ==`), without consuming anything
+    fn is_name_assignment(&self) -> bool {
+        let mut offset = 0;
+        while self
+            .peek_at(offset)
+            .is_some_and(|c| c.is_alphanumeric() || c == '_')
+        {
+            offset += 1;
+        }
+        if offset == 0 || self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            return false;
+        }
+        while self.peek_at(offset).is_some_and(|c| c.is_whitespace()) {
+            offset += 1;
+        }
+        return self.peek_at(offset) == Some('=') && self.peek_at(offset + 1) != Some('=');
+    }
+}
+
+/// Parse a single Lua literal, e.g. `{ "a", b = 2 }`. A leading `return` is accepted,
+/// as lightroom often serialises nested values as `return { ... }`.
+pub fn parse_value(source: &str) -> Result<LuaValue, LuaParseError> {
+    let mut parser = Parser::new(source);
+    parser.skip_trivia()?;
+    if parser.is_keyword_at("return") {
+        parser.pos += "return".len();
+    }
+    let value = parser.parse_value()?;
+    if !parser.at_end()? {
+        return Err(parser.error("unexpected trailing content"));
+    }
+    return Ok(value);
+}
+
+/// Parse a chunk of top-level `name = value` assignments, e.g. `prefs = { ... }` or `s = { ... }`.
+pub fn parse_assignments(source: &str) -> Result<Vec<(String, LuaValue)>, LuaParseError> {
+    let mut parser = Parser::new(source);
+    let mut assignments = Vec::new();
+    while !parser.at_end()? {
+        let name = parser.parse_name()?;
+        parser.expect('=')?;
+        let value = parser.parse_value()?;
+        assignments.push((name, value));
+        parser.skip_trivia()?;
+        if parser.peek() == Some(';') {
+            parser.pos += 1;
+        }
+    }
+    return Ok(assignments);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(text: &str) -> LuaValue {
+        return LuaValue::String(text.to_owned());
+    }
+
+    #[test]
+    fn quoted_strings_with_escapes() {
+        assert_eq!(parse_value(r#""a\"b\\c\n\t""#).unwrap(), string("a\"b\\c\n\t"));
+        assert_eq!(parse_value(r"'it\'s'").unwrap(), string("it's"));
+        assert_eq!(parse_value(r#""\65\066\x43\u{e9}""#).unwrap(), string("ABCé"));
+        assert_eq!(parse_value("\"one \\z\n    two\"").unwrap(), string("one two"));
+        assert_eq!(parse_value("\"first\\\nsecond\"").unwrap(), string("first\nsecond"));
+    }
+
+    #[test]
+    fn long_bracket_strings() {
+        assert_eq!(parse_value("[[plain]]").unwrap(), string("plain"));
+        // the newline straight after the opening bracket isn't part of it
+        assert_eq!(parse_value("[[\nline one\nline two]]").unwrap(), string("line one\nline two"));
+        assert_eq!(parse_value("[==[has ]] and ]=] in it]==]").unwrap(), string("has ]] and ]=] in it"));
+        assert_eq!(parse_value(r#"[[no \n escapes]]"#).unwrap(), string(r"no \n escapes"));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_value("42").unwrap(), LuaValue::Number(42.0));
+        assert_eq!(parse_value("-0.35").unwrap(), LuaValue::Number(-0.35));
+        assert_eq!(parse_value("1.5e3").unwrap(), LuaValue::Number(1500.0));
+        assert_eq!(parse_value("-2E-2").unwrap(), LuaValue::Number(-0.02));
+        assert_eq!(parse_value(".5").unwrap(), LuaValue::Number(0.5));
+        assert_eq!(parse_value("0x1F").unwrap(), LuaValue::Number(31.0));
+        assert_eq!(parse_value("-0x10").unwrap(), LuaValue::Number(-16.0));
+    }
+
+    #[test]
+    fn nested_and_array_tables() {
+        let value = parse_value(r#"{
+            "first",
+            name = "Studio", -- a comment
+            [3] = true,
+            ["quoted key"] = nil,
+            nested = { width = 640, height = 427; },
+            'second',
+            --[[ a block
+                 comment ]]
+        }"#).unwrap();
+        let table = value.as_table().unwrap();
+        assert_eq!(table.get("name"), Some(&string("Studio")));
+        assert_eq!(table.get("quoted key"), Some(&LuaValue::Nil));
+        let nested = table.get("nested").and_then(|nested| nested.as_table()).unwrap();
+        assert_eq!(nested.get("width").and_then(|width| width.as_f64()), Some(640.0));
+        assert_eq!(nested.get("height").and_then(|height| height.as_f64()), Some(427.0));
+        assert_eq!(table.array(), vec![&string("first"), &string("second"), &LuaValue::Boolean(true)]);
+    }
+
+    #[test]
+    fn trailing_commas_and_empty_tables() {
+        assert_eq!(parse_value("{ 1, 2, }").unwrap().as_table().unwrap().array().len(), 2);
+        assert_eq!(parse_value("{ a = 1; b = 2; }").unwrap().as_table().unwrap().entries.len(), 2);
+        assert_eq!(parse_value("{}").unwrap(), LuaValue::Table(LuaTable::default()));
+        assert_eq!(parse_value("return { }").unwrap(), LuaValue::Table(LuaTable::default()));
+    }
+
+    #[test]
+    fn top_level_assignments() {
+        let assignments = parse_assignments("s = { combine = \"intersect\", }\nprefs = { count = 3 };").unwrap();
+        let names: Vec<&str> = assignments.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["s", "prefs"]);
+        assert!(parse_assignments("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let malformed = [
+            "",
+            "{",
+            "{ a = 1",
+            "{ a = 1 b = 2 }",
+            "{ a = }",
+            "\"unterminated",
+            "\"line\nbreak\"",
+            "[[unterminated",
+            r#""\q""#,
+            r#""\300""#,
+            r#""\xZZ""#,
+            r#""\u{110000}""#,
+            "0x",
+            "-",
+            "1e",
+            "{ [{}] = 1 }",
+            "{ [1.5] = 1 }",
+            "print(1)",
+            "{} {}",
+        ];
+        for source in malformed {
+            assert!(parse_value(source).is_err(), "{:?} parsed", source);
+        }
+        assert!(parse_assignments("s = ").is_err());
+        assert!(parse_assignments("1 = 2").is_err());
+        assert!(parse_assignments("s { }").is_err());
+    }
+
+    #[test]
+    fn errors_give_the_position() {
+        let err = parse_value("{\n  a = 1,\n  b = ?\n}").unwrap_err();
+        assert_eq!((err.line, err.column), (3, 7));
+    }
+}
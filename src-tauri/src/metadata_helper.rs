@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveDateTime};
+use log::{info, warn};
+use sqlx::Row;
+use crate::catalog_db::{self, CatalogError};
+use crate::catalog_images;
+use crate::image_data::ImageMetadataFields;
+use crate::lua_table::{self, LuaValue};
+
+// metadatahelper.db keeps a row per image in AgImagesMetadata, with most values as the text
+// lightroom displays. Some are plain ("ISO 3200", "3648 x 6472"), others a serialised table,
+//   shutterSpeedValue = {
+//       withTypographic = "1/40 sec",
+//       withoutTypographic = "1/40 sec",
+//   }
+const TABLE : &str = "AgImagesMetadata";
+const COLUMNS : [&str; 16] = [
+    "imageid",
+    "com_adobe_absoluteFilepath",
+    "com_adobe_folder",
+    "com_adobe_rating",
+    "com_adobe_imageFileDimensions",
+    "com_adobe_imageCroppedDimensions",
+    "com_adobe_model",
+    "com_adobe_lens",
+    "com_adobe_shutterSpeedValue",
+    "com_adobe_apertureValue",
+    "com_adobe_focalLength",
+    "com_adobe_ISOSpeedRating",
+    "com_adobe_exposureProgram",
+    "com_adobe_meteringMode",
+    "com_adobe_flash",
+    "com_adobe_dateTime",
+];
+
+const DATE_TIME_FORMATS : [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y:%m:%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+];
+// how capture times are handed to the frontend, whatever form they were stored in
+const CAPTURE_TIME_FORMAT : &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An AgImagesMetadata row, with every column parsed. Anything missing or that doesn't parse is None.
+#[derive(Clone, Debug, Default)]
+pub struct HelperImageMetadata {
+    pub image_id: u64,
+    pub filepath: Option<String>,
+    pub folder: Option<String>,
+    pub rating: Option<f64>,
+    pub file_dimensions: Option<Dimensions>,
+    pub cropped_dimensions: Option<Dimensions>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub shutter_speed: Option<(u32, u32)>,
+    pub aperture: Option<f64>,
+    pub focal_length: Option<f64>,
+    pub iso: Option<u16>,
+    // as exif codes, so they're shown the same way as a folder image's
+    pub exposure_program: Option<u16>,
+    pub metering_mode: Option<u16>,
+    pub flash: Option<u16>,
+    pub capture_time: Option<NaiveDateTime>,
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    return Some(value);
+}
+
+/// The displayed text of a value, from inside its serialised table if it has one.
+/// The plain text version is preferred, lightroom's typographic one uses "ƒ" and thin spaces.
+pub fn display_text(raw: &str) -> Option<String> {
+    let raw = non_empty(raw)?;
+    if !raw.contains('{') {
+        return Some(raw.to_owned());
+    }
+    let assignments = lua_table::parse_assignments(raw)
+        .map(|assignments| assignments.into_iter().map(|(_, value)| value).collect::<Vec<LuaValue>>())
+        .or_else(|_| lua_table::parse_value(raw).map(|value| vec![value]));
+    if let Err(err) = &assignments {
+        warn!("unreadable metadata value {:?}: {}", raw, err);
+        return None;
+    }
+    let value = assignments.unwrap().into_iter().next()?;
+    let table = value.as_table()?;
+    return ["withoutTypographic", "withTypographic"]
+        .into_iter()
+        .find_map(|key| table.get(key).and_then(|value| value.as_str()).and_then(non_empty))
+        .map(|text| text.to_owned());
+}
+
+// the leading number of e.g. "40 mm" or "4.0"
+fn leading_number(text: &str) -> Option<f64> {
+    let number: String = text
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    return number.parse::<f64>().ok();
+}
+
+/// "3648 x 6472", as the width and height.
+pub fn parse_dimensions(text: &str) -> Option<Dimensions> {
+    let (width, height) = text.split_once(['x', 'X', '×'])?;
+    return Some(Dimensions {
+        width: width.trim().parse().ok()?,
+        height: height.trim().parse().ok()?,
+    });
+}
+
+/// "1/40 sec", "2 sec", "0.5 sec" or "0.4 sec", as a fraction of a second, with decimals
+/// read the way catalog_images reads lightroom's own values, so the two agree.
+pub fn parse_shutter_speed(text: &str) -> Option<(u32, u32)> {
+    let value = text.trim().trim_end_matches("sec").trim_end_matches(['s', '"']).trim();
+    if let Some((numerator, denominator)) = value.split_once('/') {
+        let numerator = numerator.trim().parse::<u32>().ok()?;
+        let denominator = denominator.trim().parse::<u32>().ok()?;
+        if denominator == 0 {
+            return None;
+        }
+        return Some((numerator, denominator));
+    }
+    return catalog_images::exposure_time_from_seconds(leading_number(value)?);
+}
+
+/// "f / 4.0", "ƒ / 4.0" or "f/4", as the f-number.
+pub fn parse_aperture(text: &str) -> Option<f64> {
+    let value = text.trim().trim_start_matches(['f', 'F', 'ƒ']).trim_start().trim_start_matches('/');
+    return leading_number(value);
+}
+
+/// "ISO 3200", or just "3200".
+pub fn parse_iso(text: &str) -> Option<u16> {
+    let value = text.trim();
+    let value = value.strip_prefix("ISO").unwrap_or(value);
+    return leading_number(value).map(|iso| iso.round().clamp(0.0, u16::MAX as f64) as u16);
+}
+
+/// lightroom's names for exposure programs, as exif codes.
+pub fn parse_exposure_program(text: &str) -> Option<u16> {
+    let text = text.to_lowercase();
+    let code = if text.contains("manual") {
+        1
+    } else if text.contains("aperture") {
+        3
+    } else if text.contains("shutter") {
+        4
+    } else if text.contains("creative") {
+        5
+    } else if text.contains("action") {
+        6
+    } else if text.contains("portrait") {
+        7
+    } else if text.contains("landscape") {
+        8
+    } else if text.contains("program") || text.contains("normal") {
+        2
+    } else if text.contains("not defined") {
+        0
+    } else {
+        return None;
+    };
+    return Some(code);
+}
+
+/// lightroom's names for metering modes, as exif codes.
+pub fn parse_metering_mode(text: &str) -> Option<u16> {
+    let text = text.to_lowercase();
+    let code = if text.contains("center") || text.contains("centre") {
+        2
+    } else if text.contains("multi") {
+        4
+    } else if text.contains("spot") {
+        3
+    } else if text.contains("average") {
+        1
+    } else if text.contains("pattern") || text.contains("matrix") || text.contains("evaluative") {
+        5
+    } else if text.contains("partial") {
+        6
+    } else if text.contains("other") {
+        255
+    } else if text.contains("unknown") {
+        0
+    } else {
+        return None;
+    };
+    return Some(code);
+}
+
+/// Flash descriptions run to e.g. "Did not fire, compulsory flash mode" or "Did fire, return
+/// light detected", we keep only whether it fired, which is the lowest bit of the exif value.
+pub fn parse_flash(text: &str) -> Option<u16> {
+    let text = text.to_lowercase();
+    if text.contains("did not fire") || text.contains("no flash") {
+        return Some(0);
+    }
+    // "did fire" from lightroom, "fired" from most exif tools
+    if text.contains("fire") {
+        return Some(1);
+    }
+    return None;
+}
+
+pub fn parse_capture_time(text: &str) -> Option<NaiveDateTime> {
+    let text = text.trim();
+    if let Ok(with_offset) = DateTime::parse_from_rfc3339(text) {
+        // as the photographer's clock read, like every other capture time we have
+        return Some(with_offset.naive_local());
+    }
+    return DATE_TIME_FORMATS
+        .into_iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok());
+}
+
+fn parse_row(row: &sqlx::sqlite::SqliteRow) -> Result<HelperImageMetadata, sqlx::Error> {
+    let text = |column: &str| -> Option<String> {
+        return row
+            .try_get::<Option<String>, _>(column)
+            .ok()
+            .flatten()
+            .and_then(|raw| display_text(&raw));
+    };
+    return Ok(HelperImageMetadata {
+        image_id: row.try_get::<i64, _>("imageid")? as u64,
+        filepath: text("com_adobe_absoluteFilepath"),
+        folder: text("com_adobe_folder"),
+        rating: text("com_adobe_rating").and_then(|rating| rating.parse::<f64>().ok()),
+        file_dimensions: text("com_adobe_imageFileDimensions").and_then(|text| parse_dimensions(&text)),
+        cropped_dimensions: text("com_adobe_imageCroppedDimensions").and_then(|text| parse_dimensions(&text)),
+        model: text("com_adobe_model"),
+        lens: text("com_adobe_lens"),
+        shutter_speed: text("com_adobe_shutterSpeedValue").and_then(|text| parse_shutter_speed(&text)),
+        aperture: text("com_adobe_apertureValue").and_then(|text| parse_aperture(&text)),
+        focal_length: text("com_adobe_focalLength").and_then(|text| leading_number(&text)),
+        iso: text("com_adobe_ISOSpeedRating").and_then(|text| parse_iso(&text)),
+        exposure_program: text("com_adobe_exposureProgram").and_then(|text| parse_exposure_program(&text)),
+        metering_mode: text("com_adobe_meteringMode").and_then(|text| parse_metering_mode(&text)),
+        flash: text("com_adobe_flash").and_then(|text| parse_flash(&text)),
+        capture_time: text("com_adobe_dateTime").and_then(|text| parse_capture_time(&text)),
+    });
+}
+
+/// Every row of AgImagesMetadata by image id. Columns this version of lightroom doesn't
+/// write are read as missing.
+pub async fn load_helper_metadata(metadata_db_path: &str) -> Result<HashMap<u64, HelperImageMetadata>, CatalogError> {
+    let mut db = catalog_db::connect_read_only(metadata_db_path).await?;
+    let present = catalog_db::table_columns(&mut db, metadata_db_path, TABLE).await?;
+    catalog_db::require_columns(&present, metadata_db_path, TABLE, &["imageid"])?;
+    // values are stored with whatever type lightroom had to hand, so everything is read as text
+    let select = COLUMNS
+        .iter()
+        .map(|column| {
+            if *column == "imageid" {
+                return "cast(imageid as integer) as imageid".to_owned();
+            }
+            if present.contains(*column) {
+                return format!("cast({0} as text) as {0}", column);
+            }
+            return format!("null as {}", column);
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    let rows = sqlx::query(&format!("select {} from {}", select, TABLE))
+        .fetch_all(&mut db)
+        .await
+        .map_err(|source| CatalogError::Database { path: metadata_db_path.to_owned(), source })?;
+    let mut metadata = HashMap::with_capacity(rows.len());
+    for row in &rows {
+        match parse_row(row) {
+            Ok(parsed) => {
+                metadata.insert(parsed.image_id, parsed);
+            }
+            Err(err) => warn!("skipping malformed {} row: {}", TABLE, err),
+        }
+    }
+    info!("read {} rows of {} from {}", metadata.len(), TABLE, metadata_db_path);
+    return Ok(metadata);
+}
+
+fn format_capture_time(capture_time: &NaiveDateTime) -> String {
+    return capture_time.format(CAPTURE_TIME_FORMAT).to_string();
+}
+
+impl HelperImageMetadata {
+    /// Fill in what the catalog didn't have. The catalog's own values are kept where it has
+    /// them, as they're the ones lightroom itself works from.
+    pub fn apply_to(&self, fields: &mut ImageMetadataFields) {
+        fields.folder = fields.folder.take().or_else(|| self.folder.clone());
+        fields.datetime_original = fields
+            .datetime_original
+            .take()
+            .or_else(|| self.capture_time.as_ref().map(format_capture_time));
+        fields.model = fields.model.take().or_else(|| self.model.clone());
+        fields.lens_model = fields.lens_model.take().or_else(|| self.lens.clone());
+        fields.shutter_speed_value = fields.shutter_speed_value.or(self.shutter_speed);
+        fields.aperture_value = fields.aperture_value.or(self.aperture);
+        fields.focal_length = fields.focal_length.or(self.focal_length);
+        fields.iso_speed_rating = fields.iso_speed_rating.or(self.iso);
+        fields.exposure_program = fields.exposure_program.or(self.exposure_program);
+        fields.metering_mode = fields.metering_mode.or(self.metering_mode);
+        fields.flash = fields.flash.or(self.flash);
+        fields.embedded_rating = fields.embedded_rating.or(self.rating.map(|rating| rating.round() as i16));
+        fields.file_dimensions = fields.file_dimensions.or(self.file_dimensions.map(|d| (d.width, d.height)));
+        fields.cropped_dimensions = fields.cropped_dimensions.or(self.cropped_dimensions.map(|d| (d.width, d.height)));
+    }
+
+    /// The image as served to the frontend, for when the catalog itself can't be read.
+    /// None without a file path, as there'd be nothing to show.
+    pub fn to_fields(&self) -> Option<ImageMetadataFields> {
+        let mut fields = ImageMetadataFields {
+            id: Some(self.image_id),
+            folder: None,
+            filename: self.filepath.clone()?,
+            datetime_original: None,
+            model: None,
+            lens_model: None,
+            shutter_speed_value: None,
+            aperture_value: None,
+            focal_length: None,
+            iso_speed_rating: None,
+            exposure_program: None,
+            metering_mode: None,
+            flash: None,
+            embedded_rating: None,
+            pick: None,
+            color_label: None,
+            label_color: None,
+            file_format: None,
+            touch_time: None,
+            has_gps: None,
+            file_dimensions: None,
+            cropped_dimensions: None,
+            keywords: None,
+            collections: None,
+            develop: None,
+        };
+        self.apply_to(&mut fields);
+        return Some(fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flash_reads_lightroom_descriptions() {
+        assert_eq!(parse_flash("Did fire"), Some(1));
+        assert_eq!(parse_flash("Did fire, return light detected"), Some(1));
+        assert_eq!(parse_flash("Did fire, compulsory flash mode, red-eye reduction mode"), Some(1));
+        assert_eq!(parse_flash("Fired"), Some(1));
+        assert_eq!(parse_flash("Did not fire"), Some(0));
+        assert_eq!(parse_flash("Did not fire, compulsory flash mode"), Some(0));
+        assert_eq!(parse_flash("No flash function"), Some(0));
+        assert_eq!(parse_flash("Unknown"), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use crate::catalog_db::PreviewData;
+use crate::lrprev;
+
+/// An ImageCacheEntry with no lrprev file for it.
+#[derive(Clone, Debug, Serialize)]
+pub struct MissingPreview {
+    pub image_id: u64,
+    pub path: String,
+}
+
+/// An lrprev file for an entry's uuid, but not with the entry's digest. Either the file is
+/// named for an older digest, or its own header records a different one.
+#[derive(Clone, Debug, Serialize)]
+pub struct DigestMismatch {
+    pub image_id: u64,
+    pub path: String,
+    pub expected_digest: String,
+    pub found_digest: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LevelUsage {
+    pub level: usize,
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreviewAuditReport {
+    pub preview_root: String,
+    pub entries: usize,
+    pub files: usize,
+    pub missing: Vec<MissingPreview>,
+    // lrprev files no entry accounts for, and the space they take up
+    pub orphaned: Vec<String>,
+    pub orphaned_bytes: u64,
+    pub digest_mismatches: Vec<DigestMismatch>,
+    // files we couldn't parse the index of
+    pub unreadable: Vec<String>,
+    // jpeg bytes per pyramid level, across every entry's file, smallest level first
+    pub levels: Vec<LevelUsage>,
+    pub images_without_preview: Vec<u64>,
+}
+
+// every lrprev under the root, wherever it sits, so misplaced files show up as orphans
+fn find_lrprev_files(dir: &Path, found: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("failed to read {}: {}", dir.display(), err);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_lrprev_files(&path, found);
+        } else if lrprev::parse_preview_file_name(&path).is_some() {
+            found.push(path);
+        }
+    }
+}
+
+fn file_length(path: &Path) -> u64 {
+    return fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0);
+}
+
+/// Cross check the lrprev files under `preview_root` against the ImageCacheEntry rows,
+/// and the catalog's images against both.
+pub fn audit_previews(preview_root: &Path, entries: &[PreviewData], catalog_image_ids: &[u64]) -> PreviewAuditReport {
+    info!("auditing {} preview entries against {}", entries.len(), preview_root.display());
+    let mut files = Vec::new();
+    find_lrprev_files(preview_root, &mut files);
+    let on_disk: HashSet<&PathBuf> = files.iter().collect();
+    let mut by_uuid: HashMap<String, Vec<(&PathBuf, String)>> = HashMap::new();
+    for path in &files {
+        let (uuid, digest) = lrprev::parse_preview_file_name(path).unwrap();
+        by_uuid.entry(uuid).or_default().push((path, digest));
+    }
+
+    let mut accounted: HashSet<&PathBuf> = HashSet::new();
+    let mut present: Vec<(&PreviewData, PathBuf)> = Vec::new();
+    let mut missing = Vec::new();
+    let mut digest_mismatches = Vec::new();
+    for entry in entries {
+        let expected = lrprev::preview_path(preview_root, &entry.uuid, &entry.digest);
+        if on_disk.contains(&expected) {
+            accounted.insert(*on_disk.get(&expected).unwrap());
+            present.push((entry, expected));
+            continue;
+        }
+        let stale: Vec<&(&PathBuf, String)> = by_uuid
+            .get(&entry.uuid)
+            .map(|candidates| candidates.iter().collect())
+            .unwrap_or_default();
+        if stale.is_empty() {
+            missing.push(MissingPreview {
+                image_id: entry.image_id,
+                path: expected.to_string_lossy().into_owned(),
+            });
+            continue;
+        }
+        for (path, digest) in stale {
+            accounted.insert(*path);
+            digest_mismatches.push(DigestMismatch {
+                image_id: entry.image_id,
+                path: path.to_string_lossy().into_owned(),
+                expected_digest: entry.digest.clone(),
+                found_digest: Some(digest.clone()),
+            });
+        }
+    }
+
+    // only the index of each file is read, not the jpegs themselves
+    let indexes: Vec<(&PreviewData, &PathBuf, Result<lrprev::LrPrev, lrprev::LrPrevError>)> = present
+        .par_iter()
+        .map(|(entry, path)| (*entry, path, lrprev::read_lrprev_index(&path.to_string_lossy())))
+        .collect();
+    let mut unreadable = Vec::new();
+    let mut levels: BTreeMap<usize, LevelUsage> = BTreeMap::new();
+    for (entry, path, index) in indexes {
+        let index = match index {
+            Ok(index) => index,
+            Err(err) => {
+                warn!("{:#}", anyhow::Error::from(err));
+                unreadable.push(path.to_string_lossy().into_owned());
+                continue;
+            }
+        };
+        if index.digest.as_ref().is_some_and(|digest| *digest != entry.digest) {
+            digest_mismatches.push(DigestMismatch {
+                image_id: entry.image_id,
+                path: path.to_string_lossy().into_owned(),
+                expected_digest: entry.digest.clone(),
+                found_digest: index.digest.clone(),
+            });
+        }
+        for level in &index.levels {
+            let usage = levels.entry(level.level).or_insert(LevelUsage { level: level.level, files: 0, bytes: 0 });
+            usage.files += 1;
+            usage.bytes += level.data_length;
+        }
+    }
+
+    let orphaned_paths: Vec<&PathBuf> = files.iter().filter(|path| !accounted.contains(path)).collect();
+    let orphaned_bytes = orphaned_paths.iter().map(|path| file_length(path)).sum();
+    let orphaned = orphaned_paths.iter().map(|path| path.to_string_lossy().into_owned()).collect();
+
+    let with_entry: HashSet<u64> = entries.iter().map(|entry| entry.image_id).collect();
+    let mut images_without_preview: Vec<u64> = catalog_image_ids
+        .iter()
+        .filter(|image_id| !with_entry.contains(image_id))
+        .copied()
+        .collect();
+    images_without_preview.sort();
+    missing.sort_by_key(|missing| missing.image_id);
+    digest_mismatches.sort_by_key(|mismatch| mismatch.image_id);
+
+    let report = PreviewAuditReport {
+        preview_root: preview_root.to_string_lossy().into_owned(),
+        entries: entries.len(),
+        files: files.len(),
+        missing,
+        orphaned,
+        orphaned_bytes,
+        digest_mismatches,
+        unreadable,
+        levels: levels.into_values().collect(),
+        images_without_preview,
+    };
+    info!(
+        "preview audit: {} entries, {} files, {} missing, {} orphaned, {} digest mismatches, {} images without a preview",
+        report.entries,
+        report.files,
+        report.missing.len(),
+        report.orphaned.len(),
+        report.digest_mismatches.len(),
+        report.images_without_preview.len()
+    );
+    return report;
+}
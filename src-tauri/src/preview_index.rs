@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use log::info;
+use serde::Serialize;
+use crate::catalog_db::{self, CatalogError, CatalogSchema, PreviewData};
+use crate::lr_snapshot;
+
+pub const PROGRESS_EVENT : &str = "preview-index-progress";
+pub const LOADED_EVENT : &str = "preview-index-loaded";
+pub const FAILED_EVENT : &str = "preview-index-failed";
+
+// lightroom writes previews.db in bursts as it renders, so there's no need to look often
+const POLL_INTERVAL : Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreviewIndexProgress {
+    pub loaded: usize,
+    pub total: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PreviewIndexLoaded {
+    pub entries: usize,
+    // false for the first load of a catalog, true when previews.db changed underneath us
+    pub reload: bool,
+}
+
+/// Loads the preview index off the command thread, with one generation per opened catalog.
+/// Loads and watches for an earlier catalog notice they've been superseded and stop.
+pub struct PreviewIndexWatcher {
+    generation: AtomicU64,
+}
+
+impl PreviewIndexWatcher {
+    pub fn new() -> PreviewIndexWatcher {
+        return PreviewIndexWatcher { generation: AtomicU64::new(0) };
+    }
+
+    pub fn next_generation(&self) -> u64 {
+        return self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    }
+
+    pub fn is_current(&self, generation: u64) -> bool {
+        return self.generation.load(Ordering::SeqCst) == generation;
+    }
+
+    /// Wait until `preview_db_path` is modified after `last_modified`, returning false
+    /// if this generation is superseded first.
+    pub async fn wait_for_change(&self, generation: u64, preview_db_path: &str, last_modified: Option<SystemTime>) -> bool {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            if !self.is_current(generation) {
+                return false;
+            }
+            let modified = lr_snapshot::last_modified(preview_db_path);
+            if modified.is_some() && modified != last_modified {
+                info!("{} changed, reloading the preview index", preview_db_path);
+                return true;
+            }
+        }
+    }
+}
+
+pub async fn load(preview_db_path: &str, schema: &CatalogSchema, progress: impl FnMut(PreviewIndexProgress)) -> Result<HashMap<u64, PreviewData>, CatalogError> {
+    let mut progress = progress;
+    let pool = catalog_db::connect_read_only_pool(preview_db_path).await?;
+    let loaded = catalog_db::load_preview_index(&pool, preview_db_path, schema, |loaded, total| {
+        progress(PreviewIndexProgress { loaded, total });
+    }).await;
+    pool.close().await;
+    return loaded;
+}
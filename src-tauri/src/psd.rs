@@ -0,0 +1,227 @@
+use image::{DynamicImage, ImageBuffer, Luma, Rgb};
+
+// A PSD (or PSB, its large document variant) is
+//   header              26 bytes: "8BPS", version u16 (1 PSD, 2 PSB), 6 reserved bytes,
+//                       channels u16, height u32, width u32, depth u16, color mode u16
+//   color mode data     u32 length, then data
+//   image resources     u32 length, then data
+//   layers and masks    u32 length (u64 in a PSB), then data
+//   image data          compression u16, then the flattened image one channel after another
+// Only the flattened image is read, which is photoshop's composite as of the last save.
+const SIGNATURE : &[u8; 4] = b"8BPS";
+const HEADER_LENGTH : usize = 26;
+const VERSION_PSD : u16 = 1;
+const VERSION_PSB : u16 = 2;
+
+const COMPRESSION_RAW : u16 = 0;
+// PackBits, with a table of each row's packed length up front
+const COMPRESSION_RLE : u16 = 1;
+
+const MODE_GRAYSCALE : u16 = 1;
+const MODE_RGB : u16 = 3;
+const MODE_CMYK : u16 = 4;
+
+// photoshop's own limit for a PSB
+const MAX_DIMENSION : u32 = 300000;
+// the image crate's default allocation limit, which everything else is decoded under
+const MAX_DECODED_BYTES : usize = 512 * 1024 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PsdError {
+    #[error("not a PSD file")]
+    BadHeader,
+    #[error("PSD runs past the end of the file")]
+    Truncated,
+    #[error("PSD {what} {value} is not supported")]
+    Unsupported { what: &'static str, value: u32 },
+    #[error("PSD of {width}x{height} is too large to decode")]
+    TooLarge { width: u32, height: u32 },
+}
+
+pub fn is_psd(bytes: &[u8]) -> bool {
+    return bytes.starts_with(SIGNATURE);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], PsdError> {
+        let end = self.offset.checked_add(length).ok_or(PsdError::Truncated)?;
+        if end > self.bytes.len() {
+            return Err(PsdError::Truncated);
+        }
+        let taken = &self.bytes[self.offset..end];
+        self.offset = end;
+        return Ok(taken);
+    }
+
+    fn remaining(&self) -> usize {
+        return self.bytes.len() - self.offset;
+    }
+
+    fn u16(&mut self) -> Result<u16, PsdError> {
+        let bytes = self.take(2)?;
+        return Ok(u16::from_be_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn u32(&mut self) -> Result<u32, PsdError> {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    fn u64(&mut self) -> Result<u64, PsdError> {
+        let high = self.u32()? as u64;
+        let low = self.u32()? as u64;
+        return Ok((high << 32) | low);
+    }
+
+    // a section we don't need, prefixed with its length
+    fn skip_section(&mut self, wide_length: bool) -> Result<(), PsdError> {
+        let length = if wide_length { self.u64()? } else { self.u32()? as u64 };
+        self.take(usize::try_from(length).map_err(|_| PsdError::Truncated)?)?;
+        return Ok(());
+    }
+}
+
+// PackBits: a header byte n of 0..=127 is followed by n + 1 literal bytes, -127..=-1 by one
+// byte repeated 1 - n times, and -128 is padding. A short or overlong row is cut or padded to fit.
+fn unpack_bits(packed: &[u8], expected: usize, out: &mut Vec<u8>) {
+    let start = out.len();
+    let mut i = 0;
+    while i < packed.len() && out.len() - start < expected {
+        let header = packed[i] as i8;
+        i += 1;
+        if header >= 0 {
+            let end = (i + header as usize + 1).min(packed.len());
+            out.extend_from_slice(&packed[i..end]);
+            i = end;
+        } else if header != -128 && i < packed.len() {
+            let count = 1 - header as isize;
+            out.extend(std::iter::repeat_n(packed[i], count as usize));
+            i += 1;
+        }
+    }
+    out.resize(start + expected, 0);
+}
+
+/// Decode the flattened image of a grayscale, RGB or CMYK PSD or PSB at 8 or 16 bits.
+/// Extra channels (alpha, spot colors) are ignored, so the result is always opaque.
+pub fn decode_psd(bytes: &[u8]) -> Result<DynamicImage, PsdError> {
+    if bytes.len() < HEADER_LENGTH || !is_psd(bytes) {
+        return Err(PsdError::BadHeader);
+    }
+    let mut reader = Reader { bytes, offset: SIGNATURE.len() };
+    let version = reader.u16()?;
+    if version != VERSION_PSD && version != VERSION_PSB {
+        return Err(PsdError::Unsupported { what: "version", value: version as u32 });
+    }
+    let is_psb = version == VERSION_PSB;
+    reader.take(6)?;
+    let channels = reader.u16()? as usize;
+    let height = reader.u32()?;
+    let width = reader.u32()?;
+    let depth = reader.u16()?;
+    let mode = reader.u16()?;
+
+    let colors = match mode {
+        MODE_GRAYSCALE => 1,
+        MODE_RGB => 3,
+        MODE_CMYK => 4,
+        _ => return Err(PsdError::Unsupported { what: "color mode", value: mode as u32 }),
+    };
+    if channels < colors {
+        return Err(PsdError::Unsupported { what: "channel count", value: channels as u32 });
+    }
+    if depth != 8 && depth != 16 {
+        return Err(PsdError::Unsupported { what: "bit depth", value: depth as u32 });
+    }
+    if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(PsdError::BadHeader);
+    }
+
+    reader.skip_section(false)?;
+    reader.skip_section(false)?;
+    reader.skip_section(is_psb)?;
+
+    // the sizes come from the file, so everything is checked before any of it is allocated
+    let too_large = || PsdError::TooLarge { width, height };
+    let bytes_per_sample = depth as usize / 8;
+    let pixels = (width as usize).checked_mul(height as usize).ok_or_else(too_large)?;
+    // the planes, and then the image they're widened into
+    let output_channels = if mode == MODE_GRAYSCALE { 1 } else { 3 };
+    let decoded_bytes = pixels
+        .checked_mul(bytes_per_sample * colors + 2 * output_channels)
+        .ok_or_else(too_large)?;
+    if decoded_bytes > MAX_DECODED_BYTES {
+        return Err(too_large());
+    }
+    let row_length = width as usize * bytes_per_sample;
+    let plane_length = pixels * bytes_per_sample;
+    let compression = reader.u16()?;
+    let mut planes: Vec<Vec<u8>> = Vec::with_capacity(colors);
+    match compression {
+        COMPRESSION_RAW => {
+            if plane_length * colors > reader.remaining() {
+                return Err(PsdError::Truncated);
+            }
+            for _ in 0..colors {
+                planes.push(reader.take(plane_length)?.to_vec());
+            }
+        }
+        COMPRESSION_RLE => {
+            // every channel's row lengths come first, even though we only read the color channels
+            let row_count = channels.checked_mul(height as usize).ok_or(PsdError::Truncated)?;
+            let row_entry_length = if is_psb { 4 } else { 2 };
+            if row_count.checked_mul(row_entry_length).is_none_or(|length| length > reader.remaining()) {
+                return Err(PsdError::Truncated);
+            }
+            let mut row_lengths = Vec::with_capacity(row_count);
+            for _ in 0..row_count {
+                row_lengths.push(if is_psb { reader.u32()? as usize } else { reader.u16()? as usize });
+            }
+            for channel in 0..colors {
+                let mut plane = Vec::with_capacity(plane_length);
+                for row in 0..height as usize {
+                    let packed = reader.take(row_lengths[channel * height as usize + row])?;
+                    unpack_bits(packed, row_length, &mut plane);
+                }
+                planes.push(plane);
+            }
+        }
+        _ => return Err(PsdError::Unsupported { what: "compression", value: compression as u32 }),
+    }
+
+    // everything is widened to 16 bits, then narrowed again for an 8 bit document
+    let sample = |plane: &[u8], index: usize| -> u16 {
+        if bytes_per_sample == 1 {
+            return plane[index] as u16 * 257;
+        }
+        return u16::from_be_bytes([plane[2 * index], plane[2 * index + 1]]);
+    };
+    let image = if mode == MODE_GRAYSCALE {
+        let data: Vec<u16> = (0..pixels).map(|index| sample(&planes[0], index)).collect();
+        DynamicImage::ImageLuma16(ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width, height, data).unwrap())
+    } else {
+        let mut data: Vec<u16> = Vec::with_capacity(pixels * 3);
+        for index in 0..pixels {
+            if mode == MODE_RGB {
+                data.extend((0..3).map(|channel| sample(&planes[channel], index)));
+                continue;
+            }
+            // photoshop stores CMYK inverted, so 0 is full ink and these are already 1 - ink
+            let black = sample(&planes[3], index) as u32;
+            data.extend((0..3).map(|channel| ((sample(&planes[channel], index) as u32 * black) / 65535) as u16));
+        }
+        DynamicImage::ImageRgb16(ImageBuffer::<Rgb<u16>, Vec<u16>>::from_raw(width, height, data).unwrap())
+    };
+    if depth == 8 {
+        return Ok(match image {
+            DynamicImage::ImageLuma16(_) => DynamicImage::ImageLuma8(image.to_luma8()),
+            _ => DynamicImage::ImageRgb8(image.to_rgb8()),
+        });
+    }
+    return Ok(image);
+}
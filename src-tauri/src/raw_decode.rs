@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+use image::metadata::Orientation;
+use image::{DynamicImage, RgbImage};
+use log::{info, warn};
+use rawloader::{RawImage, RawImageData};
+use crate::disk_cache::DiskCache;
+use crate::image_pipeline::{self, PipelineError, RequestedSize};
+
+// developed raws are cached at this size, big enough for the lightbox on most screens
+// while keeping the cache to a few hundred KB per image
+const CACHED_LONG_EDGE : u32 = 2560;
+// a few thousand developed raws
+const DEFAULT_MAX_CACHE_BYTES : u64 = 1024 * 1024 * 1024;
+// 4 colour CFAs (RGBE, CYGM) are rare enough that we treat the 4th as a second green
+const GREEN : usize = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RawDecodeError {
+    #[error("failed to decode raw file {path}")]
+    Decode {
+        path: String,
+        #[source]
+        source: rawloader::RawLoaderError,
+    },
+    #[error("raw file {path} has an unsupported layout: {reason}")]
+    Unsupported { path: String, reason: String },
+    #[error("failed to encode developed raw")]
+    Pipeline(#[from] PipelineError),
+}
+
+/// A raw developed into an sRGB image, as the sensor saw it, with the orientation its metadata gives.
+pub struct DevelopedRaw {
+    pub image: DynamicImage,
+    pub orientation: Orientation,
+}
+
+fn unsupported(path: &Path, reason: &str) -> RawDecodeError {
+    return RawDecodeError::Unsupported {
+        path: path.to_string_lossy().into_owned(),
+        reason: reason.to_owned(),
+    };
+}
+
+fn sample(data: &RawImageData, index: usize) -> f32 {
+    return match data {
+        RawImageData::Integer(values) => values[index] as f32,
+        RawImageData::Float(values) => values[index],
+    };
+}
+
+// the camera's white balance, or a daylight balance when it didn't record one,
+// scaled so green is 1
+fn white_balance(raw: &RawImage) -> [f32; 4] {
+    let recorded = raw.wb_coeffs;
+    let coeffs = if recorded[0].is_finite() && recorded[0] > 0.0 && recorded[GREEN] > 0.0 {
+        recorded
+    } else {
+        raw.neutralwb()
+    };
+    let mut balance = [0.0; 4];
+    for c in 0..4 {
+        balance[c] = if coeffs[c].is_finite() { coeffs[c] / coeffs[GREEN] } else { 1.0 };
+    }
+    return balance;
+}
+
+// white balanced camera rgb(e) to linear sRGB, with rows of the camera's XYZ to camera matrix
+// normalised so white balanced neutrals stay neutral under D65 (as dcraw does)
+fn camera_to_srgb(raw: &RawImage) -> [[f64; 4]; 3] {
+    let mut camera_from_srgb = [[0.0f32; 3]; 4];
+    for row in 0..4 {
+        for col in 0..3 {
+            camera_from_srgb[row][col] = (0..3)
+                .map(|k| raw.xyz_to_cam[row][k] * image_pipeline::SRGB_TO_XYZ[k][col] as f32)
+                .sum();
+        }
+        let sum: f32 = camera_from_srgb[row].iter().sum();
+        if sum != 0.0 {
+            for col in 0..3 {
+                camera_from_srgb[row][col] /= sum;
+            }
+        }
+    }
+    let srgb_from_camera = RawImage::pseudoinverse(camera_from_srgb);
+    let mut matrix = [[0.0; 4]; 3];
+    for row in 0..3 {
+        for col in 0..4 {
+            matrix[row][col] = srgb_from_camera[row][col] as f64;
+        }
+    }
+    return matrix;
+}
+
+// each channel scaled to 0..=1 between its black and white levels, then white balanced
+// and clipped, so blown highlights stay white rather than turning magenta
+fn normalise(raw: &RawImage, balance: &[f32; 4], channel: usize, value: f32) -> f64 {
+    let black = raw.blacklevels[channel] as f32;
+    let white = (raw.whitelevels[channel] as f32).max(black + 1.0);
+    return (((value - black) / (white - black)).max(0.0) * balance[channel]).min(1.0) as f64;
+}
+
+fn to_srgb(matrix: &[[f64; 4]; 3], camera: &[f64; 4]) -> [u8; 3] {
+    let mut rgb = [0u8; 3];
+    for row in 0..3 {
+        let linear: f64 = (0..4).map(|k| matrix[row][k] * camera[k]).sum();
+        rgb[row] = image_pipeline::srgb_encode(linear);
+    }
+    return rgb;
+}
+
+// a basic "superpixel" demosaic: each 2x2 block of the bayer pattern becomes one pixel,
+// halving the resolution, which is still more than we need for browsing
+fn develop_bayer(raw: &RawImage, path: &Path) -> Result<RgbImage, RawDecodeError> {
+    let [top, right, bottom, left] = raw.crops;
+    let active_width = raw.width.saturating_sub(left + right);
+    let active_height = raw.height.saturating_sub(top + bottom);
+    if active_width < 2 || active_height < 2 {
+        return Err(unsupported(path, "image is smaller than its crops"));
+    }
+    let balance = white_balance(raw);
+    let matrix = camera_to_srgb(raw);
+    let (width, height) = (active_width / 2, active_height / 2);
+    let mut developed = RgbImage::new(width as u32, height as u32);
+    for y in 0..height {
+        for x in 0..width {
+            let mut sums = [0.0f64; 4];
+            let mut counts = [0u32; 4];
+            for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+                let row = top + y * 2 + dy;
+                let col = left + x * 2 + dx;
+                let channel = raw.cfa.color_at(row, col).min(3);
+                let value = sample(&raw.data, row * raw.width + col);
+                sums[channel] += normalise(raw, &balance, channel, value);
+                counts[channel] += 1;
+            }
+            let mut camera = [0.0f64; 4];
+            for c in 0..4 {
+                if counts[c] > 0 {
+                    camera[c] = sums[c] / counts[c] as f64;
+                }
+            }
+            developed.put_pixel(x as u32, y as u32, image::Rgb(to_srgb(&matrix, &camera)));
+        }
+    }
+    return Ok(developed);
+}
+
+// already demosaiced data, e.g. a linear DNG
+fn develop_rgb(raw: &RawImage) -> RgbImage {
+    let [top, right, bottom, left] = raw.crops;
+    let width = raw.width.saturating_sub(left + right);
+    let height = raw.height.saturating_sub(top + bottom);
+    let balance = white_balance(raw);
+    let matrix = camera_to_srgb(raw);
+    let mut developed = RgbImage::new(width as u32, height as u32);
+    for y in 0..height {
+        for x in 0..width {
+            let index = ((top + y) * raw.width + left + x) * 3;
+            let mut camera = [0.0f64; 4];
+            for c in 0..3 {
+                camera[c] = normalise(raw, &balance, c, sample(&raw.data, index + c));
+            }
+            developed.put_pixel(x as u32, y as u32, image::Rgb(to_srgb(&matrix, &camera)));
+        }
+    }
+    return developed;
+}
+
+pub fn develop_raw(path: &Path) -> Result<DevelopedRaw, RawDecodeError> {
+    // rawloader catches its own panics on malformed files, and returns them as errors
+    let raw = rawloader::decode_file(path).map_err(|source| RawDecodeError::Decode {
+        path: path.to_string_lossy().into_owned(),
+        source,
+    })?;
+    let image = match raw.cpp {
+        1 if raw.cfa.is_valid() => develop_bayer(&raw, path)?,
+        1 => return Err(unsupported(path, "monochrome or unknown colour filter array")),
+        3 => develop_rgb(&raw),
+        _ => return Err(unsupported(path, &format!("{} components per pixel", raw.cpp))),
+    };
+    let orientation = Orientation::from_exif(raw.orientation.to_u16() as u8)
+        .unwrap_or(Orientation::NoTransforms);
+    return Ok(DevelopedRaw { image: DynamicImage::ImageRgb8(image), orientation });
+}
+
+/// A jpeg of a developed raw, unrotated, with the raw's own orientation.
+pub struct CachedRaw {
+    pub jpeg: Vec<u8>,
+    pub orientation: Orientation,
+}
+
+/// Developed raws, kept on disk as jpegs as developing them takes a second or more. As with
+/// thumbnails, the least recently used are the first to go once the cache is over its limit.
+pub struct RawPreviewCache {
+    cache: DiskCache,
+}
+
+impl RawPreviewCache {
+    pub fn new(dir: PathBuf) -> RawPreviewCache {
+        return RawPreviewCache { cache: DiskCache::new(dir, DEFAULT_MAX_CACHE_BYTES) };
+    }
+
+    // the raw's orientation is kept in the file name, "<key>-<exif orientation>.jpg",
+    // so serving a cached raw doesn't need the original at all
+    fn find_cached(&self, key: &str) -> Option<CachedRaw> {
+        for exif in 1..=8u8 {
+            if let Some(jpeg) = self.cache.read(&format!("{}-{}.jpg", key, exif)) {
+                let orientation = Orientation::from_exif(exif).unwrap_or(Orientation::NoTransforms);
+                return Some(CachedRaw { jpeg, orientation });
+            }
+        }
+        return None;
+    }
+
+    fn store(&self, key: &str, cached: &CachedRaw) {
+        let name = format!("{}-{}.jpg", key, cached.orientation.to_exif());
+        if let Err(err) = self.cache.write(&name, &cached.jpeg) {
+            warn!("failed to cache developed raw at {}: {}", self.cache.path(&name).display(), err);
+        }
+    }
+
+    /// Remove the least recently used developed raws until the cache is within its limit.
+    pub fn evict(&self) {
+        let (removed, bytes) = self.cache.evict();
+        if removed > 0 {
+            info!("evicted {} developed raws, cache is now {} bytes", removed, bytes);
+        }
+    }
+
+    pub fn get_or_develop(&self, original: &Path) -> Result<CachedRaw, RawDecodeError> {
+        let key = image_pipeline::file_cache_key(original);
+        if let Some(cached) = key.as_ref().and_then(|key| self.find_cached(key)) {
+            return Ok(cached);
+        }
+        info!("developing raw {}", original.display());
+        let developed = develop_raw(original)?;
+        let served = image_pipeline::serve_image(
+            developed.image,
+            RequestedSize::MaxDimension(CACHED_LONG_EDGE),
+            Orientation::NoTransforms,
+            image_pipeline::PreviewSource::DecodedRaw,
+        )?;
+        let cached = CachedRaw { jpeg: served.data, orientation: developed.orientation };
+        if let Some(key) = key {
+            self.store(&key, &cached);
+            self.evict();
+        }
+        return Ok(cached);
+    }
+}
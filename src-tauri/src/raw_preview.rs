@@ -0,0 +1,132 @@
+use std::path::Path;
+use image::metadata::Orientation;
+use crate::image_pipeline::{self, RequestedSize};
+use crate::tiff::{self, Ifd, TiffError};
+
+// TIFF based raws, which keep one or more camera rendered jpegs alongside the raw data.
+// RW2 and ORF are close to TIFF but use their own magic numbers, so are left to rawloader.
+const TIFF_RAW_EXTENSIONS : [&str; 14] = [
+    "dng", "cr2", "nef", "nrw", "arw", "srf", "sr2", "pef", "erf", "3fr", "srw", "iiq", "mef", "kdc",
+];
+
+const JPEG_SOI : [u8; 2] = [0xFF, 0xD8];
+// start of frame markers for baseline, extended and progressive jpegs, the ones we can decode.
+// Raw data is often a lossless jpeg (SOF3), which we must skip
+const DECODABLE_SOF_MARKERS : [u8; 3] = [0xC0, 0xC1, 0xC2];
+
+/// A jpeg embedded in a raw file, borrowed from the file's bytes.
+pub struct EmbeddedPreview<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub jpeg: &'a [u8],
+}
+
+/// The decodable jpegs in a raw, smallest first, and the orientation the camera recorded.
+pub struct RawPreviews<'a> {
+    pub previews: Vec<EmbeddedPreview<'a>>,
+    pub orientation: Orientation,
+}
+
+pub fn is_tiff_raw(path: &Path) -> bool {
+    return path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| TIFF_RAW_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()));
+}
+
+// walk the jpeg's markers to its frame header, for its dimensions and whether we can decode it
+fn jpeg_dimensions(jpeg: &[u8]) -> Option<(u32, u32)> {
+    if !jpeg.starts_with(&JPEG_SOI) {
+        return None;
+    }
+    let mut position = JPEG_SOI.len();
+    while position + 4 <= jpeg.len() {
+        if jpeg[position] != 0xFF {
+            return None;
+        }
+        let marker = jpeg[position + 1];
+        // fill bytes
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        let length = u16::from_be_bytes([jpeg[position + 2], jpeg[position + 3]]) as usize;
+        // SOF0 to SOF15, apart from DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            if !DECODABLE_SOF_MARKERS.contains(&marker) {
+                return None;
+            }
+            let frame = jpeg.get(position + 4..position + 9)?;
+            let height = u16::from_be_bytes([frame[1], frame[2]]) as u32;
+            let width = u16::from_be_bytes([frame[3], frame[4]]) as u32;
+            if width == 0 || height == 0 {
+                return None;
+            }
+            return Some((width, height));
+        }
+        // start of scan, with no frame header before it
+        if marker == 0xDA {
+            return None;
+        }
+        position += 2 + length;
+    }
+    return None;
+}
+
+// (offset, length) of any jpeg an IFD points to, either as a thumbnail or as its image data
+fn jpeg_locations(ifd: &Ifd) -> Vec<(usize, usize)> {
+    let mut locations = Vec::new();
+    let thumbnail_offset = ifd.get_u32(tiff::TAG_JPEG_INTERCHANGE_FORMAT);
+    let thumbnail_length = ifd.get_u32(tiff::TAG_JPEG_INTERCHANGE_FORMAT_LENGTH);
+    if thumbnail_offset.is_some() && thumbnail_length.is_some() {
+        locations.push((thumbnail_offset.unwrap() as usize, thumbnail_length.unwrap() as usize));
+    }
+    let compression = ifd.get_u32(tiff::TAG_COMPRESSION).unwrap_or(tiff::COMPRESSION_NONE);
+    if compression == tiff::COMPRESSION_OLD_JPEG || compression == tiff::COMPRESSION_JPEG {
+        let offsets = ifd.get_u32s(tiff::TAG_STRIP_OFFSETS);
+        let lengths = ifd.get_u32s(tiff::TAG_STRIP_BYTE_COUNTS);
+        // a single strip is a whole jpeg, several are pieces of one we'd have to stitch
+        if offsets.len() == 1 && lengths.len() == 1 {
+            locations.push((offsets[0] as usize, lengths[0] as usize));
+        }
+    }
+    return locations;
+}
+
+pub fn read_embedded_previews(data: &[u8]) -> Result<RawPreviews<'_>, TiffError> {
+    let tiff = tiff::parse_tiff(data)?;
+    let orientation = tiff
+        .ifds
+        .first()
+        .and_then(|ifd| ifd.get_u32(tiff::TAG_ORIENTATION))
+        .and_then(|value| Orientation::from_exif(value as u8))
+        .unwrap_or(Orientation::NoTransforms);
+
+    let mut locations: Vec<(usize, usize)> = tiff.ifds.iter().flat_map(jpeg_locations).collect();
+    // the same jpeg is often referenced both as a thumbnail and as strips
+    locations.sort();
+    locations.dedup_by_key(|(offset, _)| *offset);
+
+    let mut previews: Vec<EmbeddedPreview> = locations
+        .into_iter()
+        .filter_map(|(offset, length)| {
+            let jpeg = data.get(offset..offset.checked_add(length)?)?;
+            let (width, height) = jpeg_dimensions(jpeg)?;
+            return Some(EmbeddedPreview { width, height, jpeg });
+        })
+        .collect();
+    previews.sort_by_key(|preview| preview.width as u64 * preview.height as u64);
+    return Ok(RawPreviews { previews, orientation });
+}
+
+/// The smallest preview covering the request, or the largest there is when none do.
+pub fn choose_preview<'p, 'a>(previews: &'p [EmbeddedPreview<'a>], requested: RequestedSize) -> Option<&'p EmbeddedPreview<'a>> {
+    return match requested {
+        RequestedSize::Smallest => previews.first(),
+        RequestedSize::Largest => previews.last(),
+        RequestedSize::MaxDimension(max_dimension) => previews
+            .iter()
+            .find(|preview| image_pipeline::long_edge(preview.width, preview.height) >= max_dimension)
+            .or(previews.last()),
+    };
+}
@@ -0,0 +1,577 @@
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime};
+use crate::color_labels::LABEL_COLORS;
+use crate::lua_table::{self, LuaParseError, LuaTable, LuaValue};
+
+// A smart collection's AgLibraryCollectionContent is a lua table of rules, e.g.
+//   s = {
+//       {
+//           criteria = "rating",
+//           operation = ">=",
+//           value = 3,
+//           value2 = 0,
+//       },
+//       {
+//           criteria = "captureTime",
+//           operation = "inLast",
+//           value = 90,
+//           value2 = "days",
+//       },
+//       combine = "intersect",
+//   }
+// A rule without criteria is a nested group, with its own combine and rules.
+
+#[derive(Debug, thiserror::Error)]
+pub enum SmartCollectionError {
+    #[error("failed to parse smart collection rules")]
+    Parse(#[from] LuaParseError),
+    #[error("smart collection rules are not a table")]
+    NotATable,
+    #[error("smart collection combine {0:?} is not supported")]
+    UnsupportedCombine(String),
+    #[error("smart collection criteria {criteria:?} with operation {operation:?} is not supported")]
+    Unsupported { criteria: String, operation: String },
+    #[error("smart collection criteria {criteria:?} has an unreadable value")]
+    BadValue { criteria: String },
+}
+
+/// What the rules are tested against for each image, read from the catalog.
+#[derive(Clone, Debug, Default)]
+pub struct ImageFacts {
+    // unrated is 0, as lightroom treats it
+    pub rating: f64,
+    // 1 for picked, -1 for rejected
+    pub pick: f64,
+    // the label's name, e.g. "Approved"
+    pub color_label: String,
+    // and the colour the catalog's label set gives it, e.g. "Green", empty for no label
+    pub label_color: String,
+    pub capture_time: Option<NaiveDateTime>,
+    pub touch_time: Option<NaiveDateTime>,
+    pub filename: String,
+    pub folder: String,
+    pub file_format: String,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub iso: Option<f64>,
+    pub focal_length: Option<f64>,
+    // as an f-number
+    pub aperture: Option<f64>,
+    // in seconds
+    pub shutter_speed: Option<f64>,
+    pub has_gps: bool,
+    pub keywords: Vec<String>,
+    // the names of the ordinary collections the image is in
+    pub collections: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Combine {
+    All,
+    Any,
+    None,
+}
+
+#[derive(Clone, Debug)]
+enum NumberTest {
+    Equal(f64),
+    NotEqual(f64),
+    Greater(f64),
+    Less(f64),
+    AtLeast(f64),
+    AtMost(f64),
+    Between(f64, f64),
+}
+
+// words and text are lowercased, as lightroom's text rules ignore case
+#[derive(Clone, Debug)]
+enum TextTest {
+    ContainsAny(Vec<String>),
+    ContainsAll(Vec<String>),
+    // every word starts a word of the text
+    ContainsWords(Vec<String>),
+    ContainsNone(Vec<String>),
+    BeginsWith(String),
+    EndsWith(String),
+    Equal(String),
+    NotEqual(String),
+    Empty,
+    NotEmpty,
+}
+
+#[derive(Clone, Debug)]
+enum DateTest {
+    On(NaiveDate),
+    NotOn(NaiveDate),
+    After(NaiveDate),
+    Before(NaiveDate),
+    OnOrAfter(NaiveDate),
+    OnOrBefore(NaiveDate),
+    Between(NaiveDate, NaiveDate),
+    Since(NaiveDateTime),
+    NotSince(NaiveDateTime),
+}
+
+#[derive(Clone, Debug)]
+enum Test {
+    Number(fn(&ImageFacts) -> Option<f64>, NumberTest),
+    Text(fn(&ImageFacts) -> Vec<&str>, TextTest),
+    Date(fn(&ImageFacts) -> Option<NaiveDateTime>, DateTest),
+    Flag(fn(&ImageFacts) -> bool, bool),
+}
+
+#[derive(Clone, Debug)]
+enum Rule {
+    Test(Test),
+    Group(SmartRules),
+}
+
+/// A smart collection's rules, parsed and ready to test images against.
+#[derive(Clone, Debug)]
+pub struct SmartRules {
+    combine: Combine,
+    rules: Vec<Rule>,
+}
+
+fn number_value(criteria: &str, value: Option<&LuaValue>) -> Result<f64, SmartCollectionError> {
+    return value
+        .and_then(|value| value.as_f64())
+        .ok_or_else(|| SmartCollectionError::BadValue { criteria: criteria.to_owned() });
+}
+
+fn text_value(criteria: &str, value: Option<&LuaValue>) -> Result<String, SmartCollectionError> {
+    return match value {
+        Some(LuaValue::String(text)) => Ok(text.to_lowercase()),
+        Some(LuaValue::Number(number)) => Ok(number.to_string()),
+        _ => Err(SmartCollectionError::BadValue { criteria: criteria.to_owned() }),
+    };
+}
+
+fn date_value(criteria: &str, value: Option<&LuaValue>) -> Result<NaiveDate, SmartCollectionError> {
+    let text = value.and_then(|value| value.as_str()).unwrap_or("");
+    // dates are written as "2025-01-31", sometimes with a time after them
+    let date = text.get(..10).and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+    return date.ok_or_else(|| SmartCollectionError::BadValue { criteria: criteria.to_owned() });
+}
+
+fn number_test(criteria: &str, operation: &str, rule: &LuaTable) -> Result<Option<NumberTest>, SmartCollectionError> {
+    let value = || number_value(criteria, rule.get("value"));
+    let test = match operation {
+        "==" => NumberTest::Equal(value()?),
+        "!=" => NumberTest::NotEqual(value()?),
+        ">" => NumberTest::Greater(value()?),
+        "<" => NumberTest::Less(value()?),
+        ">=" => NumberTest::AtLeast(value()?),
+        "<=" => NumberTest::AtMost(value()?),
+        "in" => {
+            let low = value()?;
+            let high = number_value(criteria, rule.get("value2"))?;
+            NumberTest::Between(low.min(high), low.max(high))
+        }
+        _ => return Ok(None),
+    };
+    return Ok(Some(test));
+}
+
+fn text_test(criteria: &str, operation: &str, rule: &LuaTable) -> Result<Option<TextTest>, SmartCollectionError> {
+    let value = || text_value(criteria, rule.get("value"));
+    let words = || -> Result<Vec<String>, SmartCollectionError> {
+        return Ok(value()?.split_whitespace().map(|word| word.to_owned()).collect());
+    };
+    let test = match operation {
+        "any" => TextTest::ContainsAny(words()?),
+        "all" => TextTest::ContainsAll(words()?),
+        "words" => TextTest::ContainsWords(words()?),
+        "noneOf" => TextTest::ContainsNone(words()?),
+        "beginsWith" => TextTest::BeginsWith(value()?),
+        "endsWith" => TextTest::EndsWith(value()?),
+        "==" => TextTest::Equal(value()?),
+        "!=" => TextTest::NotEqual(value()?),
+        "empty" => TextTest::Empty,
+        "notEmpty" => TextTest::NotEmpty,
+        _ => return Ok(None),
+    };
+    return Ok(Some(test));
+}
+
+fn months_before(now: NaiveDateTime, months: u32) -> NaiveDateTime {
+    return now.checked_sub_months(Months::new(months)).unwrap_or(NaiveDateTime::MIN);
+}
+
+fn date_test(criteria: &str, operation: &str, rule: &LuaTable, now: NaiveDateTime) -> Result<Option<DateTest>, SmartCollectionError> {
+    let value = || date_value(criteria, rule.get("value"));
+    // "in the last 3 weeks", counted back from now
+    let since = || -> Result<NaiveDateTime, SmartCollectionError> {
+        // clamped, as chrono panics on durations past its range
+        let count = number_value(criteria, rule.get("value"))?.clamp(0.0, 100000.0) as i64;
+        let unit = rule.get("value2").and_then(|unit| unit.as_str()).unwrap_or("days");
+        let since = match unit {
+            "hours" => now - Duration::hours(count),
+            "days" => now - Duration::days(count),
+            "weeks" => now - Duration::weeks(count),
+            "months" => months_before(now, count as u32),
+            "years" => months_before(now, count as u32 * 12),
+            _ => return Err(SmartCollectionError::BadValue { criteria: criteria.to_owned() }),
+        };
+        return Ok(since);
+    };
+    let today = now.date();
+    let test = match operation {
+        "==" => DateTest::On(value()?),
+        "!=" => DateTest::NotOn(value()?),
+        ">" => DateTest::After(value()?),
+        "<" => DateTest::Before(value()?),
+        ">=" => DateTest::OnOrAfter(value()?),
+        "<=" => DateTest::OnOrBefore(value()?),
+        "in" => {
+            let start = value()?;
+            let end = date_value(criteria, rule.get("value2"))?;
+            DateTest::Between(start.min(end), start.max(end))
+        }
+        "inLast" => DateTest::Since(since()?),
+        "notInLast" => DateTest::NotSince(since()?),
+        "today" => DateTest::On(today),
+        "yesterday" => DateTest::On(today.pred_opt().unwrap_or(today)),
+        "thisMonth" => DateTest::OnOrAfter(today.with_day(1).unwrap_or(today)),
+        "thisYear" => DateTest::OnOrAfter(today.with_ordinal(1).unwrap_or(today)),
+        _ => return Ok(None),
+    };
+    return Ok(Some(test));
+}
+
+// the colour label rule names a colour by its number, 1 to 5 in the order of LABEL_COLORS, or by
+// name, "none" being no label
+fn color_label_test(criteria: &str, operation: &str, rule: &LuaTable) -> Result<Option<TextTest>, SmartCollectionError> {
+    let value = match rule.get("value") {
+        Some(LuaValue::Number(number)) => LABEL_COLORS
+            .get((*number as usize).wrapping_sub(1))
+            .map(|color| color.to_lowercase())
+            .unwrap_or_default(),
+        Some(LuaValue::String(text)) if text.eq_ignore_ascii_case("none") => String::new(),
+        Some(LuaValue::String(text)) => text.to_lowercase(),
+        _ => return Err(SmartCollectionError::BadValue { criteria: criteria.to_owned() }),
+    };
+    let test = match operation {
+        "==" => TextTest::Equal(value),
+        "!=" => TextTest::NotEqual(value),
+        _ => return Ok(None),
+    };
+    return Ok(Some(test));
+}
+
+fn flag_test(criteria: &str, operation: &str, rule: &LuaTable) -> Result<Option<bool>, SmartCollectionError> {
+    let value = match rule.get("value") {
+        Some(LuaValue::Boolean(flag)) => *flag,
+        Some(value) if value.as_f64().is_some() => value.as_f64().unwrap() != 0.0,
+        _ => return Err(SmartCollectionError::BadValue { criteria: criteria.to_owned() }),
+    };
+    let expected = match operation {
+        "==" => value,
+        "!=" => !value,
+        _ => return Ok(None),
+    };
+    return Ok(Some(expected));
+}
+
+fn optional_text(value: &Option<String>) -> Vec<&str> {
+    return value.iter().map(|text| text.as_str()).collect();
+}
+
+fn parse_test(rule: &LuaTable, criteria: &str, now: NaiveDateTime) -> Result<Test, SmartCollectionError> {
+    let operation = rule.get("operation").and_then(|operation| operation.as_str()).unwrap_or("");
+    let number = |value: fn(&ImageFacts) -> Option<f64>| -> Result<Option<Test>, SmartCollectionError> {
+        return Ok(number_test(criteria, operation, rule)?.map(|test| Test::Number(value, test)));
+    };
+    let text = |values: fn(&ImageFacts) -> Vec<&str>| -> Result<Option<Test>, SmartCollectionError> {
+        return Ok(text_test(criteria, operation, rule)?.map(|test| Test::Text(values, test)));
+    };
+    let date = |value: fn(&ImageFacts) -> Option<NaiveDateTime>| -> Result<Option<Test>, SmartCollectionError> {
+        return Ok(date_test(criteria, operation, rule, now)?.map(|test| Test::Date(value, test)));
+    };
+    let test = match criteria {
+        "rating" => number(|facts| Some(facts.rating))?,
+        "pick" => number(|facts| Some(facts.pick))?,
+        "labelColor" => color_label_test(criteria, operation, rule)?
+            .map(|test| Test::Text(|facts| vec![facts.label_color.as_str()], test)),
+        "labelText" => text(|facts| vec![facts.color_label.as_str()])?,
+        "captureTime" => date(|facts| facts.capture_time)?,
+        "touchTime" => date(|facts| facts.touch_time)?,
+        "filename" => text(|facts| vec![facts.filename.as_str()])?,
+        "folder" => text(|facts| vec![facts.folder.as_str()])?,
+        "fileFormat" => text(|facts| vec![facts.file_format.as_str()])?,
+        "camera" => text(|facts| optional_text(&facts.camera))?,
+        "lens" => text(|facts| optional_text(&facts.lens))?,
+        "isoSpeedRating" => number(|facts| facts.iso)?,
+        "focalLength" => number(|facts| facts.focal_length)?,
+        "aperture" => number(|facts| facts.aperture)?,
+        "shutterSpeed" => number(|facts| facts.shutter_speed)?,
+        "keywords" => text(|facts| facts.keywords.iter().map(|keyword| keyword.as_str()).collect())?,
+        "collection" => text(|facts| facts.collections.iter().map(|collection| collection.as_str()).collect())?,
+        "hasGPSData" => flag_test(criteria, operation, rule)?.map(|expected| Test::Flag(|facts| facts.has_gps, expected)),
+        _ => None,
+    };
+    return test.ok_or_else(|| SmartCollectionError::Unsupported {
+        criteria: criteria.to_owned(),
+        operation: operation.to_owned(),
+    });
+}
+
+fn parse_group(table: &LuaTable, now: NaiveDateTime) -> Result<SmartRules, SmartCollectionError> {
+    // lightroom writes "intersect" for all, "union" for any and "exclude" for none
+    let combine = match table.get("combine").and_then(|combine| combine.as_str()).unwrap_or("intersect") {
+        "intersect" => Combine::All,
+        "union" => Combine::Any,
+        "exclude" => Combine::None,
+        other => return Err(SmartCollectionError::UnsupportedCombine(other.to_owned())),
+    };
+    let mut rules = Vec::new();
+    for rule in table.array() {
+        let rule = rule.as_table().ok_or(SmartCollectionError::NotATable)?;
+        let criteria = rule.get("criteria").and_then(|criteria| criteria.as_str());
+        if criteria.is_some() {
+            rules.push(Rule::Test(parse_test(rule, criteria.unwrap(), now)?));
+        } else {
+            rules.push(Rule::Group(parse_group(rule, now)?));
+        }
+    }
+    return Ok(SmartRules { combine, rules });
+}
+
+/// Parse a smart collection's rules. Relative dates, e.g. "in the last 90 days", are fixed
+/// against `now`, so membership is as of when they're parsed.
+pub fn parse_rules(content: &str, now: NaiveDateTime) -> Result<SmartRules, SmartCollectionError> {
+    let assignments = lua_table::parse_assignments(content);
+    let value = match assignments {
+        Ok(assignments) => assignments.into_iter().next().map(|(_, value)| value),
+        Err(_) => Some(lua_table::parse_value(content)?),
+    };
+    let table = value.as_ref().and_then(|value| value.as_table()).ok_or(SmartCollectionError::NotATable)?;
+    return parse_group(table, now);
+}
+
+fn test_number(value: Option<f64>, test: &NumberTest) -> bool {
+    if value.is_none() {
+        return false;
+    }
+    let value = value.unwrap();
+    // exif values are often stored rounded differently to how they're shown
+    let close = |expected: f64| (value - expected).abs() < 1e-3 * expected.abs().max(1.0);
+    return match test {
+        NumberTest::Equal(expected) => close(*expected),
+        NumberTest::NotEqual(expected) => !close(*expected),
+        NumberTest::Greater(expected) => value > *expected && !close(*expected),
+        NumberTest::Less(expected) => value < *expected && !close(*expected),
+        NumberTest::AtLeast(expected) => value >= *expected || close(*expected),
+        NumberTest::AtMost(expected) => value <= *expected || close(*expected),
+        NumberTest::Between(low, high) => (value >= *low || close(*low)) && (value <= *high || close(*high)),
+    };
+}
+
+fn starts_a_word(text: &str, word: &str) -> bool {
+    return text
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|text_word| text_word.starts_with(word));
+}
+
+fn test_text(values: Vec<&str>, test: &TextTest) -> bool {
+    let values: Vec<String> = values.into_iter().map(|value| value.to_lowercase()).collect();
+    let contains = |word: &String| values.iter().any(|value| value.contains(word.as_str()));
+    // nothing at all, e.g. no lens, equals empty text
+    let equals = |expected: &String| values.iter().any(|value| value == expected) || (expected.is_empty() && values.is_empty());
+    return match test {
+        TextTest::ContainsAny(words) => words.iter().any(contains),
+        TextTest::ContainsAll(words) => words.iter().all(contains),
+        TextTest::ContainsWords(words) => words
+            .iter()
+            .all(|word| values.iter().any(|value| starts_a_word(value, word))),
+        TextTest::ContainsNone(words) => !words.iter().any(contains),
+        TextTest::BeginsWith(start) => values.iter().any(|value| value.starts_with(start.as_str())),
+        TextTest::EndsWith(end) => values.iter().any(|value| value.ends_with(end.as_str())),
+        TextTest::Equal(expected) => equals(expected),
+        TextTest::NotEqual(expected) => !equals(expected),
+        TextTest::Empty => values.iter().all(|value| value.is_empty()),
+        TextTest::NotEmpty => values.iter().any(|value| !value.is_empty()),
+    };
+}
+
+fn test_date(value: Option<NaiveDateTime>, test: &DateTest) -> bool {
+    if value.is_none() {
+        return false;
+    }
+    let value = value.unwrap();
+    let date = value.date();
+    return match test {
+        DateTest::On(expected) => date == *expected,
+        DateTest::NotOn(expected) => date != *expected,
+        DateTest::After(expected) => date > *expected,
+        DateTest::Before(expected) => date < *expected,
+        DateTest::OnOrAfter(expected) => date >= *expected,
+        DateTest::OnOrBefore(expected) => date <= *expected,
+        DateTest::Between(start, end) => date >= *start && date <= *end,
+        DateTest::Since(since) => value >= *since,
+        DateTest::NotSince(since) => value < *since,
+    };
+}
+
+impl Test {
+    fn matches(&self, facts: &ImageFacts) -> bool {
+        return match self {
+            Test::Number(value, test) => test_number(value(facts), test),
+            Test::Text(values, test) => test_text(values(facts), test),
+            Test::Date(value, test) => test_date(value(facts), test),
+            Test::Flag(value, expected) => value(facts) == *expected,
+        };
+    }
+}
+
+impl SmartRules {
+    pub fn matches(&self, facts: &ImageFacts) -> bool {
+        let rule_matches = |rule: &Rule| match rule {
+            Rule::Test(test) => test.matches(facts),
+            Rule::Group(group) => group.matches(facts),
+        };
+        return match self.combine {
+            Combine::All => self.rules.iter().all(rule_matches),
+            Combine::Any => self.rules.iter().any(rule_matches),
+            Combine::None => !self.rules.iter().any(rule_matches),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> NaiveDateTime {
+        return NaiveDate::from_ymd_opt(2024, 6, 15).unwrap().and_hms_opt(12, 0, 0).unwrap();
+    }
+
+    fn days_ago(days: i64) -> Option<NaiveDateTime> {
+        return Some(now() - Duration::days(days));
+    }
+
+    #[test]
+    fn rating_and_colour_label() {
+        let content = r#"s = {
+	{
+		criteria = "rating",
+		operation = ">=",
+		value = 3,
+		value2 = 0,
+	},
+	{
+		criteria = "labelColor",
+		operation = "==",
+		value = 1,
+	},
+	combine = "intersect",
+}
+"#;
+        let rules = parse_rules(content, now()).unwrap();
+        let facts = ImageFacts { rating: 4.0, label_color: "Red".to_owned(), ..Default::default() };
+        assert!(rules.matches(&facts));
+        assert!(!rules.matches(&ImageFacts { rating: 2.0, ..facts.clone() }));
+        assert!(!rules.matches(&ImageFacts { label_color: "Yellow".to_owned(), ..facts.clone() }));
+        // it's the colour the label set gives the label that counts, not the label's name
+        assert!(!rules.matches(&ImageFacts { color_label: "Red".to_owned(), label_color: "Green".to_owned(), ..facts.clone() }));
+        assert!(rules.matches(&ImageFacts { color_label: "Select".to_owned(), label_color: "Red".to_owned(), ..facts }));
+    }
+
+    #[test]
+    fn recent_captures_with_keywords() {
+        let content = r#"s = {
+	{
+		criteria = "captureTime",
+		operation = "inLast",
+		value = 90,
+		value2 = "days",
+	},
+	{
+		criteria = "keywords",
+		operation = "words",
+		value = "bird",
+		value2 = "",
+	},
+	combine = "intersect",
+}
+"#;
+        let rules = parse_rules(content, now()).unwrap();
+        let facts = ImageFacts {
+            capture_time: days_ago(10),
+            keywords: vec!["Birds of prey".to_owned()],
+            ..Default::default()
+        };
+        assert!(rules.matches(&facts));
+        assert!(!rules.matches(&ImageFacts { capture_time: days_ago(100), ..facts.clone() }));
+        assert!(!rules.matches(&ImageFacts { capture_time: None, ..facts.clone() }));
+        assert!(!rules.matches(&ImageFacts { keywords: vec!["Hummingbird".to_owned()], ..facts }));
+    }
+
+    #[test]
+    fn nested_group_of_any() {
+        let content = r#"s = {
+	{
+		criteria = "fileFormat",
+		operation = "==",
+		value = "RAW",
+		value2 = "",
+	},
+	{
+		{
+			criteria = "camera",
+			operation = "any",
+			value = "X-T4 X-T5",
+			value2 = "",
+		},
+		{
+			criteria = "hasGPSData",
+			operation = "==",
+			value = true,
+		},
+		combine = "union",
+	},
+	combine = "intersect",
+}
+"#;
+        let rules = parse_rules(content, now()).unwrap();
+        let facts = ImageFacts {
+            file_format: "RAW".to_owned(),
+            camera: Some("X-T5".to_owned()),
+            ..Default::default()
+        };
+        assert!(rules.matches(&facts));
+        assert!(rules.matches(&ImageFacts { camera: None, has_gps: true, ..facts.clone() }));
+        assert!(!rules.matches(&ImageFacts { camera: None, ..facts.clone() }));
+        assert!(!rules.matches(&ImageFacts { file_format: "JPG".to_owned(), ..facts }));
+    }
+
+    #[test]
+    fn excluded_rejects() {
+        let content = r#"s = {
+	{
+		criteria = "pick",
+		operation = "==",
+		value = -1,
+		value2 = 0,
+	},
+	combine = "exclude",
+}
+"#;
+        let rules = parse_rules(content, now()).unwrap();
+        assert!(rules.matches(&ImageFacts::default()));
+        assert!(!rules.matches(&ImageFacts { pick: -1.0, ..Default::default() }));
+    }
+
+    #[test]
+    fn unsupported_criteria_is_an_error() {
+        let content = r#"s = {
+	{
+		criteria = "faceCount",
+		operation = ">",
+		value = 0,
+	},
+	combine = "intersect",
+}
+"#;
+        assert!(matches!(parse_rules(content, now()), Err(SmartCollectionError::Unsupported { .. })));
+    }
+}
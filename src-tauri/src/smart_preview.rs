@@ -0,0 +1,234 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use image::metadata::Orientation;
+use image::{DynamicImage, GenericImageView, ImageFormat, RgbImage};
+use crate::image_pipeline;
+use crate::tiff::{self, Ifd, Tiff, TiffError};
+
+// Smart Previews are lossy DNGs, kept in "<CatalogName> Smart Previews.lrdata" as
+// <first character of uuid>/<first four characters of uuid>/<uuid>.dng
+const SMART_PREVIEW_EXTENSION : &str = "dng";
+
+const PHOTOMETRIC_RGB : u32 = 2;
+const PHOTOMETRIC_YCBCR : u32 = 6;
+// scene referred camera rgb, which we have to develop ourselves
+const PHOTOMETRIC_LINEAR_RAW : u32 = 34892;
+
+
+#[derive(Debug, thiserror::Error)]
+pub enum SmartPreviewError {
+    #[error("failed to read smart preview {path}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("smart preview is not a valid DNG")]
+    Tiff(#[from] TiffError),
+    #[error("smart preview has no main image")]
+    NoImage,
+    #[error("smart preview uses unsupported compression {compression} with photometric interpretation {photometric}")]
+    Unsupported { compression: u32, photometric: u32 },
+    #[error("tile {index} of the smart preview is missing or corrupt")]
+    BadTile { index: usize },
+    #[error("failed to decode tile {index} of the smart preview")]
+    Decode {
+        index: usize,
+        #[source]
+        source: image::ImageError,
+    },
+}
+
+/// A developed smart preview, as stored, along with the orientation recorded in the DNG.
+pub struct SmartPreview {
+    pub image: DynamicImage,
+    pub orientation: Orientation,
+}
+
+pub fn smart_preview_path(smart_previews_root: &Path, uuid: &str) -> PathBuf {
+    let first: String = uuid.chars().take(1).collect();
+    let prefix: String = uuid.chars().take(4).collect();
+    return smart_previews_root
+        .join(first)
+        .join(prefix)
+        .join(format!("{}.{}", uuid, SMART_PREVIEW_EXTENSION));
+}
+
+pub fn find_smart_preview(smart_previews_root: &str, uuid: &str) -> Option<PathBuf> {
+    let path = smart_preview_path(Path::new(smart_previews_root), uuid);
+    if path.is_file() {
+        return Some(path);
+    }
+    return None;
+}
+
+// the full size image, rather than any reduced resolution previews alongside it
+fn main_ifd(tiff: &Tiff) -> Option<&Ifd> {
+    return tiff
+        .ifds
+        .iter()
+        .filter(|ifd| ifd.subfile_type() == 0)
+        .filter_map(|ifd| ifd.dimensions().map(|(width, height)| (ifd, width as u64 * height as u64)))
+        .max_by_key(|(_, pixels)| *pixels)
+        .map(|(ifd, _)| ifd);
+}
+
+// tiles or strips, as (x, y, offset, length) with strips treated as full width tiles
+fn tile_layout(ifd: &Ifd, width: u32, height: u32) -> Vec<(u32, u32, usize, usize)> {
+    let (tile_width, tile_height, offsets, lengths) = match ifd.get_u32(tiff::TAG_TILE_WIDTH) {
+        Some(tile_width) => (
+            tile_width,
+            ifd.get_u32(tiff::TAG_TILE_LENGTH).unwrap_or(tile_width),
+            ifd.get_u32s(tiff::TAG_TILE_OFFSETS),
+            ifd.get_u32s(tiff::TAG_TILE_BYTE_COUNTS),
+        ),
+        None => (
+            width,
+            ifd.get_u32(tiff::TAG_ROWS_PER_STRIP).unwrap_or(height),
+            ifd.get_u32s(tiff::TAG_STRIP_OFFSETS),
+            ifd.get_u32s(tiff::TAG_STRIP_BYTE_COUNTS),
+        ),
+    };
+    let tiles_across = width.div_ceil(tile_width.max(1));
+    return offsets
+        .iter()
+        .zip(lengths.iter())
+        .enumerate()
+        .map(|(index, (offset, length))| {
+            let x = (index as u32 % tiles_across) * tile_width;
+            let y = (index as u32 / tiles_across) * tile_height;
+            return (x, y, *offset as usize, *length as usize);
+        })
+        .collect();
+}
+
+// each tile is a complete jpeg, lay them out on one canvas, cropping the ones that overhang
+fn assemble_tiles(data: &[u8], ifd: &Ifd, width: u32, height: u32) -> Result<RgbImage, SmartPreviewError> {
+    let mut canvas = RgbImage::new(width, height);
+    for (index, (x, y, offset, length)) in tile_layout(ifd, width, height).into_iter().enumerate() {
+        let bytes = data
+            .get(offset..offset.saturating_add(length))
+            .ok_or(SmartPreviewError::BadTile { index })?;
+        let tile = image::load_from_memory_with_format(bytes, ImageFormat::Jpeg)
+            .map_err(|source| SmartPreviewError::Decode { index, source })?
+            .to_rgb8();
+        let visible_width = tile.width().min(width.saturating_sub(x));
+        let visible_height = tile.height().min(height.saturating_sub(y));
+        let visible = tile.view(0, 0, visible_width, visible_height).to_image();
+        image::imageops::replace(&mut canvas, &visible, x as i64, y as i64);
+    }
+    return Ok(canvas);
+}
+
+fn invert_3x3(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < f64::EPSILON {
+        return None;
+    }
+    let mut inverse = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            // cofactor of (col, row), i.e. transposed
+            let (r1, r2) = ((col + 1) % 3, (col + 2) % 3);
+            let (c1, c2) = ((row + 1) % 3, (row + 2) % 3);
+            inverse[row][col] = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+        }
+    }
+    return Some(inverse);
+}
+
+// camera rgb to linear sRGB, from the DNG's XYZ to camera ColorMatrix1, with rows
+// normalised so white balanced neutrals stay neutral (as dcraw does)
+fn camera_to_srgb(ifd: &Ifd) -> Option<[[f64; 3]; 3]> {
+    let matrix = ifd.get_f64s(tiff::TAG_COLOR_MATRIX_1);
+    if matrix.len() != 9 {
+        return None;
+    }
+    let mut camera_from_srgb = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            camera_from_srgb[row][col] = (0..3).map(|k| matrix[row * 3 + k] * image_pipeline::SRGB_TO_XYZ[k][col]).sum();
+        }
+        let sum: f64 = camera_from_srgb[row].iter().sum();
+        if sum.abs() < f64::EPSILON {
+            return None;
+        }
+        for col in 0..3 {
+            camera_from_srgb[row][col] /= sum;
+        }
+    }
+    return invert_3x3(camera_from_srgb);
+}
+
+// turn scene referred camera values into something displayable: linearise, white balance,
+// convert to sRGB primaries and apply the sRGB curve. No tone curve or develop settings.
+fn develop_linear_raw(raw: &RgbImage, ifd: &Ifd) -> RgbImage {
+    let linearization = ifd.get_u32s(tiff::TAG_LINEARIZATION_TABLE);
+    let default_white = if linearization.is_empty() { 255.0 } else { 65535.0 };
+    let black = ifd.get_f64s(tiff::TAG_BLACK_LEVEL).first().copied().unwrap_or(0.0);
+    let white = ifd.get_f64s(tiff::TAG_WHITE_LEVEL).first().copied().unwrap_or(default_white);
+    let range = (white - black).max(1.0);
+    let levels: Vec<f64> = (0..256usize)
+        .map(|value| {
+            let linear = linearization.get(value).map(|v| *v as f64).unwrap_or(value as f64);
+            return ((linear - black) / range).clamp(0.0, 1.0);
+        })
+        .collect();
+
+    let neutral = ifd.get_f64s(tiff::TAG_AS_SHOT_NEUTRAL);
+    let multipliers: Vec<f64> = if neutral.len() == 3 && neutral.iter().all(|n| *n > 0.0) {
+        let smallest = neutral.iter().map(|n| 1.0 / n).fold(f64::MAX, f64::min);
+        neutral.iter().map(|n| (1.0 / n) / smallest).collect()
+    } else {
+        vec![1.0, 1.0, 1.0]
+    };
+    let matrix = camera_to_srgb(ifd).unwrap_or([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    let mut developed = RgbImage::new(raw.width(), raw.height());
+    for (pixel, out) in raw.pixels().zip(developed.pixels_mut()) {
+        let camera: Vec<f64> = (0..3).map(|c| (levels[pixel[c] as usize] * multipliers[c]).min(1.0)).collect();
+        for c in 0..3 {
+            let linear: f64 = (0..3).map(|k| matrix[c][k] * camera[k]).sum();
+            out[c] = image_pipeline::srgb_encode(linear);
+        }
+    }
+    return developed;
+}
+
+pub fn decode_smart_preview_bytes(data: &[u8]) -> Result<SmartPreview, SmartPreviewError> {
+    let tiff = tiff::parse_tiff(data)?;
+    // the orientation lives in IFD0, even when the image data is in a SubIFD
+    let orientation = tiff
+        .ifds
+        .first()
+        .and_then(|ifd| ifd.get_u32(tiff::TAG_ORIENTATION))
+        .and_then(|value| Orientation::from_exif(value as u8))
+        .unwrap_or(Orientation::NoTransforms);
+    let ifd = main_ifd(&tiff).ok_or(SmartPreviewError::NoImage)?;
+    let (width, height) = ifd.dimensions().ok_or(SmartPreviewError::NoImage)?;
+    let compression = ifd.get_u32(tiff::TAG_COMPRESSION).unwrap_or(tiff::COMPRESSION_NONE);
+    let photometric = ifd.get_u32(tiff::TAG_PHOTOMETRIC_INTERPRETATION).unwrap_or(0);
+    let jpeg_tiles = compression == tiff::COMPRESSION_LOSSY_JPEG || compression == tiff::COMPRESSION_JPEG;
+    let samples = ifd.get_u32(tiff::TAG_SAMPLES_PER_PIXEL).unwrap_or(1);
+    if !jpeg_tiles || samples != 3 {
+        return Err(SmartPreviewError::Unsupported { compression, photometric });
+    }
+
+    let raw = assemble_tiles(data, ifd, width, height)?;
+    let image = match photometric {
+        PHOTOMETRIC_LINEAR_RAW => DynamicImage::ImageRgb8(develop_linear_raw(&raw, ifd)),
+        PHOTOMETRIC_RGB | PHOTOMETRIC_YCBCR => DynamicImage::ImageRgb8(raw),
+        _ => return Err(SmartPreviewError::Unsupported { compression, photometric }),
+    };
+    return Ok(SmartPreview { image, orientation });
+}
+
+pub fn decode_smart_preview(path: &Path) -> Result<SmartPreview, SmartPreviewError> {
+    let data = fs::read(path).map_err(|source| SmartPreviewError::Io {
+        path: path.to_string_lossy().into_owned(),
+        source,
+    })?;
+    return decode_smart_preview_bytes(&data);
+}
@@ -0,0 +1,191 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use crate::disk_cache::DiskCache;
+use crate::image_pipeline::{self, PreviewSource, RequestedSize, ServedImage};
+
+// enough for a few tens of thousands of images at both sizes
+const DEFAULT_MAX_BYTES : u64 = 1024 * 1024 * 1024;
+const THUMBNAIL_EXTENSION : &str = "jpg";
+
+/// The sizes we keep, small for the grid and medium for the drawer and larger grid sizes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbnailSize {
+    Small,
+    Medium,
+}
+
+impl ThumbnailSize {
+    pub const ALL : [ThumbnailSize; 2] = [ThumbnailSize::Small, ThumbnailSize::Medium];
+
+    pub fn long_edge(&self) -> u32 {
+        return match self {
+            ThumbnailSize::Small => 256,
+            ThumbnailSize::Medium => 1024,
+        };
+    }
+
+    fn name(&self) -> &'static str {
+        return match self {
+            ThumbnailSize::Small => "s",
+            ThumbnailSize::Medium => "m",
+        };
+    }
+
+    /// The smallest thumbnail covering the request, None when only the full image will do.
+    pub fn for_request(requested: RequestedSize) -> Option<ThumbnailSize> {
+        return match requested {
+            RequestedSize::Smallest => Some(ThumbnailSize::Small),
+            RequestedSize::Largest => None,
+            RequestedSize::MaxDimension(max_dimension) => ThumbnailSize::ALL
+                .into_iter()
+                .find(|size| size.long_edge() >= max_dimension),
+        };
+    }
+}
+
+const SOURCES : [PreviewSource; 5] = [
+    PreviewSource::OriginalFile,
+    PreviewSource::EmbeddedPreview,
+    PreviewSource::DecodedRaw,
+    PreviewSource::LightroomPreview,
+    PreviewSource::SmartPreview,
+];
+
+// where the thumbnail was made from is kept in its file name, so the UI can still say
+fn source_name(source: PreviewSource) -> &'static str {
+    return match source {
+        PreviewSource::LightroomPreview => "lightroom",
+        PreviewSource::SmartPreview => "smart",
+        PreviewSource::OriginalFile => "original",
+        PreviewSource::DecodedRaw => "raw",
+        PreviewSource::EmbeddedPreview => "embedded",
+    };
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ThumbnailCacheStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// Jpeg thumbnails of folder images, already oriented for display, kept on disk as
+/// "<file key>-<size>-<source>.jpg", the least recently used going first once the cache is
+/// over its limit.
+pub struct ThumbnailCache {
+    cache: DiskCache,
+    // bumped for each folder opened, so generation for a folder we've left stops early
+    generation: AtomicU64,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf) -> ThumbnailCache {
+        return ThumbnailCache { cache: DiskCache::new(dir, DEFAULT_MAX_BYTES), generation: AtomicU64::new(0) };
+    }
+
+    fn name_for(&self, key: &str, size: ThumbnailSize, source: PreviewSource) -> String {
+        return format!("{}-{}-{}.{}", key, size.name(), source_name(source), THUMBNAIL_EXTENSION);
+    }
+
+    fn find(&self, key: &str, size: ThumbnailSize) -> Option<(String, PreviewSource)> {
+        return SOURCES
+            .into_iter()
+            .map(|source| (self.name_for(key, size, source), source))
+            .find(|(name, _)| self.cache.contains(name));
+    }
+
+    /// The cached thumbnail of `original`, as a jpeg along with where it was made from.
+    pub fn get(&self, original: &Path, size: ThumbnailSize) -> Option<(Vec<u8>, PreviewSource)> {
+        let key = image_pipeline::file_cache_key(original)?;
+        let (name, source) = self.find(&key, size)?;
+        let jpeg = self.cache.read(&name)?;
+        return Some((jpeg, source));
+    }
+
+    pub fn contains(&self, original: &Path, size: ThumbnailSize) -> bool {
+        return image_pipeline::file_cache_key(original)
+            .is_some_and(|key| self.find(&key, size).is_some());
+    }
+
+    /// Keep a thumbnail, only jpegs are kept as anything else was passed along untouched.
+    pub fn store(&self, original: &Path, size: ThumbnailSize, served: &ServedImage) {
+        if served.mime_type != image_pipeline::mime_type_for(image::ImageFormat::Jpeg) {
+            return;
+        }
+        let key = image_pipeline::file_cache_key(original);
+        if key.is_none() {
+            return;
+        }
+        let name = self.name_for(&key.unwrap(), size, served.source);
+        if let Err(err) = self.cache.write(&name, &served.data) {
+            warn!("failed to cache thumbnail at {}: {}", self.cache.path(&name).display(), err);
+        }
+    }
+
+    /// Start a new round of generation, ending any earlier one.
+    pub fn next_generation(&self) -> u64 {
+        return self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    }
+
+    /// Make any missing thumbnails of `originals` on the rayon pool, with `make` producing
+    /// an image no larger than the given long edge. Stops early if a newer generation starts.
+    pub fn generate<F>(&self, generation: u64, originals: &[String], make: F)
+    where
+        F: Fn(&Path, u32) -> anyhow::Result<ServedImage> + Sync,
+    {
+        info!("generating thumbnails for {} files", originals.len());
+        originals.par_iter().for_each(|original| {
+            for size in ThumbnailSize::ALL {
+                if self.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let original = Path::new(original);
+                if self.contains(original, size) {
+                    continue;
+                }
+                match make(original, size.long_edge()) {
+                    Ok(served) => self.store(original, size, &served),
+                    Err(err) => {
+                        warn!("{:#}", err.context(format!("failed to make thumbnail of {}", original.display())));
+                        // the other size would fail the same way
+                        return;
+                    }
+                }
+            }
+        });
+        self.evict();
+        info!("finished generating thumbnails");
+    }
+
+    /// Remove the least recently used thumbnails until the cache is within its limit.
+    pub fn evict(&self) {
+        let (removed, bytes) = self.cache.evict();
+        if removed > 0 {
+            info!("evicted {} thumbnails, cache is now {} bytes", removed, bytes);
+        }
+    }
+
+    pub fn stats(&self) -> ThumbnailCacheStats {
+        let entries = self.cache.entries();
+        return ThumbnailCacheStats {
+            files: entries.len(),
+            bytes: entries.iter().map(|entry| entry.length).sum(),
+            max_bytes: self.cache.max_bytes(),
+        };
+    }
+
+    /// Remove every thumbnail, stopping any generation under way.
+    pub fn clear(&self) -> ThumbnailCacheStats {
+        self.next_generation();
+        for entry in self.cache.entries() {
+            if let Err(err) = fs::remove_file(&entry.path) {
+                warn!("failed to remove thumbnail {}: {}", entry.path.display(), err);
+            }
+        }
+        return self.stats();
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+
+// A TIFF file (and so a DNG, and most raw formats) starts with
+//   byte order              "II" (little endian) or "MM" (big endian)
+//   magic                   u16, 42
+//   first IFD offset        u32
+// and each IFD is
+//   entry count             u16
+//   entries                 12 bytes each: tag u16, type u16, count u32, value or offset u32
+//   next IFD offset         u32, 0 for the last
+// Values that fit in 4 bytes are stored inline, anything larger is at the offset.
+const TIFF_MAGIC : u16 = 42;
+const ENTRY_LENGTH : usize = 12;
+// more than this and we're almost certainly following garbage
+const MAX_IFDS : usize = 64;
+
+pub const TAG_NEW_SUBFILE_TYPE : u16 = 254;
+pub const TAG_IMAGE_WIDTH : u16 = 256;
+pub const TAG_IMAGE_LENGTH : u16 = 257;
+pub const TAG_BITS_PER_SAMPLE : u16 = 258;
+pub const TAG_COMPRESSION : u16 = 259;
+pub const TAG_PHOTOMETRIC_INTERPRETATION : u16 = 262;
+pub const TAG_MAKE : u16 = 271;
+pub const TAG_STRIP_OFFSETS : u16 = 273;
+pub const TAG_ORIENTATION : u16 = 274;
+pub const TAG_SAMPLES_PER_PIXEL : u16 = 277;
+pub const TAG_ROWS_PER_STRIP : u16 = 278;
+pub const TAG_STRIP_BYTE_COUNTS : u16 = 279;
+pub const TAG_TILE_WIDTH : u16 = 322;
+pub const TAG_TILE_LENGTH : u16 = 323;
+pub const TAG_TILE_OFFSETS : u16 = 324;
+pub const TAG_TILE_BYTE_COUNTS : u16 = 325;
+pub const TAG_SUB_IFDS : u16 = 330;
+pub const TAG_JPEG_INTERCHANGE_FORMAT : u16 = 513;
+pub const TAG_JPEG_INTERCHANGE_FORMAT_LENGTH : u16 = 514;
+pub const TAG_EXIF_IFD : u16 = 34665;
+pub const TAG_LINEARIZATION_TABLE : u16 = 50712;
+pub const TAG_BLACK_LEVEL : u16 = 50714;
+pub const TAG_WHITE_LEVEL : u16 = 50717;
+pub const TAG_COLOR_MATRIX_1 : u16 = 50721;
+pub const TAG_AS_SHOT_NEUTRAL : u16 = 50728;
+
+pub const COMPRESSION_NONE : u32 = 1;
+pub const COMPRESSION_OLD_JPEG : u32 = 6;
+pub const COMPRESSION_JPEG : u32 = 7;
+// the DNG 1.4 lossy jpeg compression used by smart previews
+pub const COMPRESSION_LOSSY_JPEG : u32 = 34892;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TiffError {
+    #[error("not a TIFF file")]
+    BadHeader,
+    #[error("IFD at offset {offset} runs past the end of the file")]
+    Truncated { offset: usize },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    pub fn u16(&self, bytes: &[u8]) -> u16 {
+        let bytes = [bytes[0], bytes[1]];
+        return match self {
+            ByteOrder::LittleEndian => u16::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u16::from_be_bytes(bytes),
+        };
+    }
+
+    pub fn u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        return match self {
+            ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        };
+    }
+}
+
+/// One IFD entry, with its value bytes copied out of the file.
+#[derive(Clone, Debug)]
+pub struct TiffEntry {
+    pub field_type: u16,
+    pub count: u32,
+    pub data: Vec<u8>,
+    byte_order: ByteOrder,
+}
+
+fn type_size(field_type: u16) -> Option<usize> {
+    return match field_type {
+        // BYTE, ASCII, SBYTE, UNDEFINED
+        1 | 2 | 6 | 7 => Some(1),
+        // SHORT, SSHORT
+        3 | 8 => Some(2),
+        // LONG, SLONG, FLOAT, IFD
+        4 | 9 | 11 | 13 => Some(4),
+        // RATIONAL, SRATIONAL, DOUBLE
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    };
+}
+
+fn rational(numerator: f64, denominator: f64) -> f64 {
+    if denominator == 0.0 {
+        return 0.0;
+    }
+    return numerator / denominator;
+}
+
+impl TiffEntry {
+    /// Integer values (BYTE, SHORT, LONG and IFD), widened to u32.
+    pub fn as_u32s(&self) -> Vec<u32> {
+        let order = self.byte_order;
+        return match self.field_type {
+            1 | 7 => self.data.iter().map(|b| *b as u32).collect(),
+            3 => self.data.chunks_exact(2).map(|c| order.u16(c) as u32).collect(),
+            4 | 13 => self.data.chunks_exact(4).map(|c| order.u32(c)).collect(),
+            _ => Vec::new(),
+        };
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        return self.as_u32s().first().copied();
+    }
+
+    /// Numeric values of any type, including rationals, as f64.
+    pub fn as_f64s(&self) -> Vec<f64> {
+        let order = self.byte_order;
+        return match self.field_type {
+            5 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| rational(order.u32(&c[0..4]) as f64, order.u32(&c[4..8]) as f64))
+                .collect(),
+            10 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| rational(order.u32(&c[0..4]) as i32 as f64, order.u32(&c[4..8]) as i32 as f64))
+                .collect(),
+            8 => self.data.chunks_exact(2).map(|c| order.u16(c) as i16 as f64).collect(),
+            9 => self.data.chunks_exact(4).map(|c| order.u32(c) as i32 as f64).collect(),
+            11 => self.data.chunks_exact(4).map(|c| f32::from_bits(order.u32(c)) as f64).collect(),
+            12 => self
+                .data
+                .chunks_exact(8)
+                .map(|c| {
+                    let (high, low) = match order {
+                        ByteOrder::LittleEndian => (order.u32(&c[4..8]), order.u32(&c[0..4])),
+                        ByteOrder::BigEndian => (order.u32(&c[0..4]), order.u32(&c[4..8])),
+                    };
+                    return f64::from_bits(((high as u64) << 32) | low as u64);
+                })
+                .collect(),
+            _ => self.as_u32s().into_iter().map(|v| v as f64).collect(),
+        };
+    }
+
+    pub fn as_string(&self) -> String {
+        let end = self.data.iter().position(|b| *b == 0).unwrap_or(self.data.len());
+        return String::from_utf8_lossy(&self.data[..end]).trim().to_owned();
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Ifd {
+    pub offset: usize,
+    pub entries: HashMap<u16, TiffEntry>,
+}
+
+impl Ifd {
+    pub fn get(&self, tag: u16) -> Option<&TiffEntry> {
+        return self.entries.get(&tag);
+    }
+
+    pub fn get_u32(&self, tag: u16) -> Option<u32> {
+        return self.get(tag).and_then(|entry| entry.as_u32());
+    }
+
+    pub fn get_u32s(&self, tag: u16) -> Vec<u32> {
+        return self.get(tag).map(|entry| entry.as_u32s()).unwrap_or_default();
+    }
+
+    pub fn get_f64s(&self, tag: u16) -> Vec<f64> {
+        return self.get(tag).map(|entry| entry.as_f64s()).unwrap_or_default();
+    }
+
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        return Some((self.get_u32(TAG_IMAGE_WIDTH)?, self.get_u32(TAG_IMAGE_LENGTH)?));
+    }
+
+    /// 0 for the main image, 1 for a reduced resolution preview.
+    pub fn subfile_type(&self) -> u32 {
+        return self.get_u32(TAG_NEW_SUBFILE_TYPE).unwrap_or(0);
+    }
+}
+
+/// The IFDs of a TIFF file: the main chain, followed by any SubIFDs they point to.
+#[derive(Clone, Debug)]
+pub struct Tiff {
+    pub byte_order: ByteOrder,
+    pub ifds: Vec<Ifd>,
+}
+
+fn parse_ifd(data: &[u8], offset: usize, byte_order: ByteOrder) -> Result<(Ifd, usize), TiffError> {
+    if offset + 2 > data.len() {
+        return Err(TiffError::Truncated { offset });
+    }
+    let count = byte_order.u16(&data[offset..]) as usize;
+    let entries_end = offset + 2 + count * ENTRY_LENGTH;
+    if entries_end + 4 > data.len() {
+        return Err(TiffError::Truncated { offset });
+    }
+    let mut ifd = Ifd { offset, entries: HashMap::new() };
+    for index in 0..count {
+        let entry = &data[offset + 2 + index * ENTRY_LENGTH..];
+        let tag = byte_order.u16(&entry[0..2]);
+        let field_type = byte_order.u16(&entry[2..4]);
+        let value_count = byte_order.u32(&entry[4..8]);
+        let size = match type_size(field_type) {
+            Some(size) => size * value_count as usize,
+            // unknown types can't be measured, so can't be read
+            None => continue,
+        };
+        let value = if size <= 4 {
+            &entry[8..8 + size]
+        } else {
+            let value_offset = byte_order.u32(&entry[8..12]) as usize;
+            match data.get(value_offset..value_offset.saturating_add(size)) {
+                Some(value) => value,
+                // a broken pointer in one tag shouldn't lose us the rest of the IFD
+                None => continue,
+            }
+        };
+        ifd.entries.insert(tag, TiffEntry {
+            field_type,
+            count: value_count,
+            data: value.to_vec(),
+            byte_order,
+        });
+    }
+    let next = byte_order.u32(&data[entries_end..]) as usize;
+    return Ok((ifd, next));
+}
+
+// follow the next IFD pointers from `offset`, skipping any IFD we've already seen
+fn walk_chain(data: &[u8], offset: usize, byte_order: ByteOrder, ifds: &mut Vec<Ifd>) -> Result<(), TiffError> {
+    let mut offset = offset;
+    while offset != 0 && ifds.len() < MAX_IFDS && !ifds.iter().any(|ifd| ifd.offset == offset) {
+        let (ifd, next) = parse_ifd(data, offset, byte_order)?;
+        ifds.push(ifd);
+        offset = next;
+    }
+    return Ok(());
+}
+
+pub fn parse_tiff(data: &[u8]) -> Result<Tiff, TiffError> {
+    return parse_tiff_at(data, 0);
+}
+
+/// Parse a TIFF structure starting `base` bytes into `data`. Offsets within it are relative
+/// to `base`, as in the TIFF block inside a jpeg's exif segment.
+pub fn parse_tiff_at(data: &[u8], base: usize) -> Result<Tiff, TiffError> {
+    let data = data.get(base..).ok_or(TiffError::BadHeader)?;
+    if data.len() < 8 {
+        return Err(TiffError::BadHeader);
+    }
+    let byte_order = match &data[0..2] {
+        b"II" => ByteOrder::LittleEndian,
+        b"MM" => ByteOrder::BigEndian,
+        _ => return Err(TiffError::BadHeader),
+    };
+    if byte_order.u16(&data[2..4]) != TIFF_MAGIC {
+        return Err(TiffError::BadHeader);
+    }
+
+    let mut ifds = Vec::new();
+    walk_chain(data, byte_order.u32(&data[4..8]) as usize, byte_order, &mut ifds)?;
+    // SubIFDs hold the raw data (and sometimes previews) in a DNG
+    let mut index = 0;
+    while index < ifds.len() {
+        for offset in ifds[index].get_u32s(TAG_SUB_IFDS) {
+            walk_chain(data, offset as usize, byte_order, &mut ifds)?;
+        }
+        index += 1;
+    }
+    return Ok(Tiff { byte_order, ifds });
+}
@@ -1,12 +1,9 @@
 'use client'
 
 import React from 'react'
-import { readFile } from '@tauri-apps/plugin-fs';
 import Skeleton from '@mui/material/Skeleton';
-// polyfill for toBase64
-import "core-js/modules/esnext.uint8-array.to-base64.js";
-import { invoke } from '@tauri-apps/api/core';
-import Paper from '@mui/material/Paper';
+import { convertFileSrc } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import CircularProgress from '@mui/material/CircularProgress';
 
 const getImageTypeFromImage = (image) => {
@@ -25,74 +22,115 @@ const getImageTypeFromImage = (image) => {
   }
 }
 
-export default function AsyncImageFromApi({image, imageStyle, orientation, width, height, loadingVariant})
+// images are served over the reflex:// protocol, see image_protocol.rs
+// this is reflex://localhost/ on mac and linux, http://reflex.localhost/ on windows
+const reflexBase = () => convertFileSrc("", "reflex");
+
+// source is "preview" (lightroom's preview, or the nearest thing we have) or "original"
+export const reflexImageUrl = (image, sizeArgs, source = "preview", attempt = 0) => {
+  // TODO: We should have an id here in the exif case, but it hasn't been populated
+  const imageId = image.id !== undefined ? image.id.toString() : "0";
+  const params = new URLSearchParams({path: image["filename"]});
+  if (sizeArgs.maxDimension !== undefined)
+  {
+    params.set("max_dimension", sizeArgs.maxDimension.toString());
+  }
+  else
+  {
+    params.set("mode", sizeArgs.mode);
+  }
+  // only there to make the webview ask again
+  if (attempt > 0)
+  {
+    params.set("attempt", attempt.toString());
+  }
+  return `${reflexBase()}${source}/${getImageTypeFromImage(image)}/${encodeURIComponent(imageId)}?${params}`;
+}
+
+// images arrive already rotated to lightroom's orientation, so are displayed as they are
+export default function AsyncImageFromApi({image, imageStyle, width, height, loadingVariant})
 {
+  // null while loading, then {error: bool}
   const [imageState, setImageState] = React.useState(null);
+  // bumped to ask again, after a failure that a newly loaded preview index may fix
+  const [reloadCount, setReloadCount] = React.useState(0);
+
+  const imageSrc = React.useMemo(
+    () => {
+      try
+      {
+        // note that if we don't have an image set, this will throw
+        // and we'll get our LoadingError.jpg
+        // ask for roughly the pixels we'll display, when we know how big that is
+        const displayedDimension = Math.max(
+          Number.isFinite(width) ? width : 0,
+          Number.isFinite(height) ? height : 0
+        );
+        const sizeArgs = displayedDimension > 0
+          ? {maxDimension: Math.ceil(displayedDimension * (window.devicePixelRatio ?? 1))}
+          : {mode: "hi"};
+        return reflexImageUrl(image, sizeArgs, "preview", reloadCount);
+      }
+      catch(error)
+      {
+        console.log({error});
+        return null;
+      }
+    },
+    [image, width, height, reloadCount]
+  );
+
   React.useEffect(
     () => {
+      setImageState(imageSrc === null ? {error: true} : null);
+    },
+    [imageSrc]
+  );
+
+  React.useEffect(
+    () => {
+      if (imageState === null || !imageState.error)
+      {
+        return;
+      }
       let mounted = true;
-      let loadingError = false;
-      const awaitable = async () => {
-        let imageSrc = null;
-        try
+      let unlisten = null;
+      listen("preview-index-loaded", () => {
+        if (mounted)
+        {
+          setReloadCount((count) => count + 1);
+        }
+      }).then((unlistenFn) => {
+        if (mounted)
         {
-          // note that if we don't have an image set, this will throw
-          // and we'll get our LoadingError.jpg
-          const contents = await invoke(
-            "get_image_for_id",
-            {
-              // TODO: We should have an id here in the exif case, but it hasn't been populated
-              imageId: image.id !== undefined ? image.id.toString() : "0",
-              imageType: getImageTypeFromImage(image),
-              imagePath: image["filename"],
-              mode: "hi"
-            }
-          );
-          const base64string = new Uint8Array(contents).toBase64();
-          imageSrc = `data:image/jpg;base64,${base64string}`;
+          unlisten = unlistenFn;
         }
-        catch(error)
+        else
         {
-          console.log({error});
-          imageSrc = "/LoadingError.jpg";
-          loadingError = true;
+          unlistenFn();
         }
-        // todo: it's a better pattern to abort the request according to react sources
-        // though ... this seems to catch more cases we care about
-        if (mounted)
+      });
+      return () => {
+        mounted = false;
+        if (unlisten !== null)
         {
-          setImageState({image: imageSrc, error: loadingError});
+          unlisten();
         }
       };
-      awaitable();
-      return ()=>{ mounted=false; };
     },
-    [image]
+    [imageState]
   );
 
-  // TODO: It's not clear this is sufficient, I could set up a test-set?
-  const oClassMap = {
-    "AB": undefined,
-    "BC": "rotate90"
-  };
-  // if we've had a loading error and are displaying the freepic image
-  // force portrait orientation (similarly if we haven't loaded an image yet)
-  const usingDefaultImage = (imageState === null || imageState.error === true);
-  // note that the defaultImage is square so no need to rotate
-  const oClass = usingDefaultImage ?  undefined : oClassMap[orientation];
-  // this is a little awkward, could we read the dimensions from the image instead?
-  const rotatedWidth = oClass === undefined ? width: height;
-  const rotatedHeight = oClass === undefined ? height: width;
-
   const resolvedLoadingVariant = loadingVariant ?? "skeleton";
+  const loading = imageState === null;
 
   return <React.Fragment>
-    {imageState === null && (resolvedLoadingVariant === "skeleton") && <Skeleton variant="rectangular"
-      width={rotatedWidth} 
-      height={rotatedHeight} 
+    {loading && (resolvedLoadingVariant === "skeleton") && <Skeleton variant="rectangular"
+      width={width}
+      height={height}
       style={imageStyle}
     /> }
-    {imageState === null && (resolvedLoadingVariant === "spinner") && 
+    {loading && (resolvedLoadingVariant === "spinner") &&
       <div style={Object.assign(
         {
           display: "flex",
@@ -100,24 +138,27 @@ export default function AsyncImageFromApi({image, imageStyle, orientation, width
           justifyContent: "center"
         },
         imageStyle,
-        {width: rotatedWidth, height: rotatedHeight}
+        {width: width, height: height}
       )}>
         <div>
           <CircularProgress color="secondary"/>
         </div>
       </div>
     }
-    {imageState !== null && <img
-      src={imageState.image}
-      className={oClass}
+    {/* the image loads hidden behind the placeholder, then takes its place */}
+    <img
+      src={imageState !== null && imageState.error ? "/LoadingError.jpg" : imageSrc}
+      onLoad={() => setImageState((state) => state ?? {error: false})}
+      onError={() => setImageState((state) => (state !== null && state.error) ? state : {error: true})}
       style={Object.assign(
         {},
         imageStyle,
         {
-          width: rotatedWidth, 
-          height: rotatedHeight
-        }
+          width: width,
+          height: height
+        },
+        loading ? {display: "none"} : {}
       )}
-    />}
+    />
   </React.Fragment>
-}
\ No newline at end of file
+}
@@ -253,4 +253,37 @@ export const makeImageFromLightroom = (record) => {
     adobe: record
   };
   return image;
-};
\ No newline at end of file
+};
+// images read from the catalog in rust arrive with the same fields as folder images,
+// with the aperture and shutter speed already converted from lightroom's APEX values
+// and anything else metadatahelper.db has parsed into exif's enums
+export const makeImageFromCatalog = (record) => {
+  // neither is harvested for every image, so leave them empty rather than "Unknown"
+  const fromCode = (field) => (record[field] === null || record[field] === undefined)
+    ? null
+    : exif_parsers[field](record[field]);
+  const pickNames = {"1": "Picked", "0": "Unflagged", "-1": "Rejected"};
+  // develop settings are nested, the columns want them alongside the exif
+  const develop = record["develop"];
+  const hasDevelop = develop !== null && develop !== undefined;
+  const fromDevelop = (field) => hasDevelop ? develop[field] : null;
+  const slider = (name) => hasDevelop ? (develop.sliders[name] ?? null) : null;
+  return Object.assign(
+    {},
+    record,
+    {
+      exposure_program: fromCode("exposure_program"),
+      metering_mode: fromCode("metering_mode"),
+      pick: (record["pick"] === null || record["pick"] === undefined) ? null : pickNames[record["pick"]],
+      rating: record["embedded_rating"],
+      exposure: slider("exposure"),
+      contrast: slider("contrast"),
+      temperature: fromDevelop("temperature"),
+      tint: fromDevelop("tint"),
+      profile: fromDevelop("profile"),
+      cropped: hasDevelop ? (develop.crop !== null ? "Cropped" : "Uncropped") : null,
+      exif: null,
+      adobe: record
+    }
+  );
+};
@@ -125,6 +125,20 @@ const makeRationalColumn = (def) => {
     );
 }
 
+const makeListColumn = (def) => {
+    return Object.assign(
+        {},
+        {
+            cell: ({ cell, row }) => {
+                const val = row.original[def.accessorKey];
+                const valS = Array.isArray(val) ? val.join(", ") : "";
+                return <span title={valS} style={{minWidth:"5vw", maxWidth: "15vw", maxHeight: "inherit", textWrap: "nowrap", overflow: "hidden"}}>{valS}</span>;
+            }
+        },
+        def
+    );
+}
+
 const makeDefaultColumn = (def) => {
     return Object.assign(
         {},
@@ -155,6 +169,10 @@ const makeColumns = (onSelectImageIndex, repImage) =>
         {
             defs[i] = makeRationalColumn( defs[i] );
         }
+        else if(k === "keywords")
+        {
+            defs[i] = makeListColumn( defs[i] );
+        }
         else if(k in formatters)
         {
             defs[i] = makeDefaultColumnForEnum(
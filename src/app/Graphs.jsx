@@ -35,14 +35,19 @@ export function GetFormattedData(data, dataKey, fixedColor)
   for(const d of data)
   {
     // TODO: Drop null? Is this the right place?
-    if (d[dataKey] === null)
+    if (d[dataKey] === null || d[dataKey] === undefined)
     {
       continue;
     }
-    let key = formatterForField(d[dataKey]);
-    let existingEntry = valueCounts.get(key);
-    let count = existingEntry === undefined ? 1 : existingEntry.count + 1;
-    valueCounts.set(key, {value: d[dataKey], count});
+    // list valued fields (keywords) count the image once under each of its values
+    const values = Array.isArray(d[dataKey]) ? d[dataKey] : [d[dataKey]];
+    for(const value of values)
+    {
+      let key = formatterForField(value);
+      let existingEntry = valueCounts.get(key);
+      let count = existingEntry === undefined ? 1 : existingEntry.count + 1;
+      valueCounts.set(key, {value, count});
+    }
   }
   const defaultParser = (s) => {
     return s;
@@ -3,6 +3,7 @@ import Toolbar from '@mui/material/Toolbar';
 import CssBaseline from '@mui/material/CssBaseline';
 import List from '@mui/material/List';
 import Typography from '@mui/material/Typography';
+import Tooltip from '@mui/material/Tooltip';
 import Divider from '@mui/material/Divider';
 import IconButton from '@mui/material/IconButton';
 import Paper from '@mui/material/Paper';
@@ -30,6 +31,7 @@ import { TreeItem } from '@mui/x-tree-view/TreeItem';
 import React from 'react'
 import {pathsep} from "./defs"
 import InsertDriveFileIcon from '@mui/icons-material/InsertDriveFile';
+import CollectionsBookmarkIcon from '@mui/icons-material/CollectionsBookmark';
 import AsyncImageFromApi from "./AsyncImageFromApi";
 
 const SAMPLE_PRODUCTS = [
@@ -159,6 +161,34 @@ const createNodeFromCache = (fullPath, relPath, queryCache) =>
   return baseElement;
 }
 
+// tree items need string ids, the catalog's collection ids are numbers
+const createNodeFromCollection = (collection) =>
+{
+  const count = collection.image_count === null ? "unsupported rules" : collection.image_count;
+  let baseElement = {
+    id: String(collection.id),
+    label: `${collection.name} (${count})`
+  };
+  if (collection.children.length !== 0)
+  {
+    baseElement.children = collection.children.map(createNodeFromCollection);
+  }
+  return baseElement;
+}
+
+const describeAge = (seconds) => {
+  const minutes = Math.floor(seconds / 60);
+  if (minutes < 1)
+  {
+    return "under a minute ago";
+  }
+  if (minutes < 60)
+  {
+    return `${minutes} min ago`;
+  }
+  return `${Math.floor(minutes / 60)} h ${minutes % 60} min ago`;
+};
+
 function NavDrawer({
   folderData, 
   open,
@@ -168,9 +198,12 @@ function NavDrawer({
   onSelectFolders,
   selectedFilesystem,
   onSelectFilesystem,
+  collectionTree,
+  snapshotAge,
+  selectedCollections,
+  onSelectCollections,
   showImage,
   activeImageIndex,
-  imageToOrientation,
   imagePageLimits,
   images
 })
@@ -178,6 +211,7 @@ function NavDrawer({
   const theme = useTheme();
   const lightroomFolderActive = selectedFolders.length > 0;
   const filesystemActive = selectedFilesystem.length > 0;
+  const collectionsActive = selectedCollections.length > 0;
   const treeDataForFolders = React.useMemo(
     ()=>{
       let tree = [];
@@ -215,6 +249,18 @@ function NavDrawer({
     },
     [folderData]
   );
+  const treeDataForCollections = React.useMemo(
+    ()=>collectionTree.map(createNodeFromCollection),
+    [collectionTree]
+  );
+  const selectedCollectionItems = React.useMemo(
+    ()=>selectedCollections.map(String),
+    [selectedCollections]
+  );
+  const onSelectCollectionItems = React.useCallback(
+    (event, ids)=>onSelectCollections(event, ids.map(Number)),
+    [onSelectCollections]
+  );
 
   const mkListItem = ({active, text, onClick, Component}) => {
     return <ListItem 
@@ -259,7 +305,7 @@ function NavDrawer({
     </ListItem>
   };
 
-  const mkAccordionItem = ({active, text, onClick, Component}, index) => {
+  const mkAccordionItem = ({active, text, Component, treeData, multiSelect, onSelect, selected}) => {
     return <ListItem key={text} disablePadding sx={{ display: 'block' }} style={{maxWidth: "100%"}}>
       <Accordion style={{maxWidth: "100%", backgroundColor: active ? theme.palette.primary.main: undefined}} >
           <AccordionSummary
@@ -291,14 +337,12 @@ function NavDrawer({
             <AccordionDetails>
               <Paper>
                 <TreeView 
-                  treeData={index === 0 ? treeDataForFolders : treeDataForFilesystem}
-                  multiSelect={index === 0 ? true: false}
-                  checkboxSelection={index === 0? true: false}
-                  onSelectedItemsChange={
-                    index === 0 ? onSelectFolders : onSelectFilesystem
-                  }
+                  treeData={treeData}
+                  multiSelect={multiSelect}
+                  checkboxSelection={multiSelect}
+                  onSelectedItemsChange={onSelect}
                   active={active}
-                  selectedItems={index === 0 ? selectedFolders : selectedFilesystem}
+                  selectedItems={selected}
                 />
               </Paper>
             </AccordionDetails>
@@ -315,15 +359,37 @@ function NavDrawer({
       text: 'Lightroom Folder',
       active: lightroomFolderActive,
       Component: () => <FolderIcon />,
-      onClick: handleDrawerOpen
+      onClick: handleDrawerOpen,
+      treeData: treeDataForFolders,
+      multiSelect: true,
+      onSelect: onSelectFolders,
+      selected: selectedFolders
     },
     {
       text: 'Filesystem',
       active: filesystemActive,
       Component: () => <InsertDriveFileIcon />,
-      onClick: handleDrawerOpen
+      onClick: handleDrawerOpen,
+      treeData: treeDataForFilesystem,
+      multiSelect: false,
+      onSelect: onSelectFilesystem,
+      selected: selectedFilesystem
     }
   ];
+  // only lightroom catalogs have collections
+  if (collectionTree.length !== 0)
+  {
+    buttonElements.push({
+      text: 'Collections',
+      active: collectionsActive,
+      Component: () => <CollectionsBookmarkIcon />,
+      onClick: handleDrawerOpen,
+      treeData: treeDataForCollections,
+      multiSelect: true,
+      onSelect: onSelectCollectionItems,
+      selected: selectedCollectionItems
+    });
+  }
   const menuElements = [
     {
       text: 'Open',
@@ -340,7 +406,6 @@ function NavDrawer({
     image={images[index]}
     loadingVariant="spinner"
     height={200}
-    orientation={images[index] !== undefined ? imageToOrientation[images[index].id] : undefined}
     // we need to be very careful here not to set display to undefined, as this will mess with the internal
     // logic of AsyncImageFromApi
     imageStyle={Object.assign(
@@ -356,7 +421,15 @@ function NavDrawer({
   return <StyledDrawer variant="permanent" open={open}>
     {open && 
       <DrawerHeader>
-        <div style={{flexGrow: 1}} />
+        <div style={{flexGrow: 1}}>
+          {snapshotAge !== null &&
+            <Tooltip title={`Lightroom has this catalog open, so a copy of it is shown. Previews copied ${describeAge(snapshotAge.previews_seconds)}.`}>
+              <Typography variant="caption" sx={{color: "warning.main"}}>
+                Catalog copy from {describeAge(snapshotAge.catalog_seconds)}
+              </Typography>
+            </Tooltip>
+          }
+        </div>
         <IconButton onClick={open ? handleDrawerClose : handleDrawerOpen}>
           {((theme.direction === 'rtl') ^ open) ?  <ChevronLeftIcon /> : <ChevronRightIcon />}
         </IconButton>
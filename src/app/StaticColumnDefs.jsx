@@ -38,6 +38,22 @@ const StaticColumnDefs = [
         plottable: true,
         optional: true
     },
+    {
+        // lightroom's pick/reject flag
+        accessorKey: "pick",
+        header: "Flag",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        // the colour the catalog's label set gives the label, the name is in color_label
+        accessorKey: "label_color",
+        header: "Color Label",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
     {
         // accessorKey:"com_adobe_dateTime",
         accessorKey: "datetime_original",
@@ -100,6 +116,57 @@ const StaticColumnDefs = [
         header: "Metering",
         filterable: true,
         plottable: true
+    },
+    {
+        // a list per image, filtered and plotted by each of its keywords
+        accessorKey: "keywords",
+        header: "Keywords",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        // the develop settings, for lightroom images that have them
+        accessorKey: "exposure",
+        header: "Exposure",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        accessorKey: "contrast",
+        header: "Contrast",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        accessorKey: "temperature",
+        header: "Temp",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        accessorKey: "tint",
+        header: "Tint",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        accessorKey: "profile",
+        header: "Profile",
+        filterable: true,
+        plottable: true,
+        optional: true
+    },
+    {
+        accessorKey: "cropped",
+        header: "Crop",
+        filterable: true,
+        plottable: true,
+        optional: true
     }
     // flash is really messy the strings are horrendously complicated, let's remove it for now
     // as *I* don't care
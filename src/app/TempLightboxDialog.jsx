@@ -21,7 +21,7 @@ import "yet-another-react-lightbox/styles.css";
 import "yet-another-react-lightbox/plugins/captions.css";
 import "yet-another-react-lightbox/plugins/thumbnails.css";
 
-export default function TempLightboxDialog({images, focusedImageIndex, imageToOrientation, setFocusedImage, onClose}) {
+export default function TempLightboxDialog({images, focusedImageIndex, setFocusedImage, onClose}) {
   // Get the 0th element assuming this to be unique if-it-exists and undefined otherwise
   // the filepath may not be a useful title, so don't have one for now
   const memoizedSlides = React.useMemo( () => {
@@ -29,8 +29,7 @@ export default function TempLightboxDialog({images, focusedImageIndex, imageToOr
       {},
       images[index], 
       {
-        type: "custom-slide",
-        orientation: images[index].adobe !== null ? imageToOrientation[images[index].id] : undefined
+        type: "custom-slide"
       })
     );
   });
@@ -45,7 +44,6 @@ export default function TempLightboxDialog({images, focusedImageIndex, imageToOr
             image={slide}
             width={rect.width}
             height={rect.height}
-            orientation={slide.orientation}
             imageStyle={{objectFit: "contain"}}
           />
         ) : undefined;
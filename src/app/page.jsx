@@ -15,9 +15,7 @@ import {
     Paper
 } from "@mui/material";
 import {pathsep} from "./defs"
-import {metadata, preview} from "./LightroomDB"
 import useScript from "./useScript"
-import { readFile } from '@tauri-apps/plugin-fs';
 import { invoke } from '@tauri-apps/api/core';
 import Grid from '@mui/material/Grid';
 import Tabs from '@mui/material/Tabs';
@@ -215,6 +213,15 @@ const isNoLessFiltered = (filters, prevFilters) => {
   return filterSet.isSubsetOf(prevFilterSet);
 };
 
+// a list valued field (keywords, collections) passes if any of its values are included
+const passesCategoryFilter = (filter, value) => {
+  if (Array.isArray(value))
+  {
+    return value.some(v => filter.includes(v));
+  }
+  return filter.includes(value);
+};
+
 function useWindowSize() {
   const [size, setSize] = React.useState([0, 0]);
   React.useLayoutEffect(() => {
@@ -245,6 +252,9 @@ export default function Home() {
   const [navOpen, setNavOpen] = React.useState(true);
   const [filesystemFilters, setFilesystemFilters] = React.useState([]);
   const [filtersByMetric, setFiltersByMetric] = React.useState({});
+  const [collectionTree, setCollectionTree] = React.useState([]);
+  // how old the copies are, when lightroom has the catalog open and we're reading a snapshot
+  const [snapshotAge, setSnapshotAge] = React.useState(null);
   const [metricsToPlot, setMetricsToPlot] = React.useState([]);
   const [focusedImageIndex, setFocusedImageIndex] = React.useState(null);
   const [hoveredImageIndex, setHoveredImageIndex] = React.useState(null);
@@ -257,10 +267,7 @@ export default function Home() {
   };
 
   const [metadataDBPath, setMetadataDBPath] = React.useState(null);
-  const [previewDBPath, setPreviewDBPath] = React.useState(null);
   const [rootFolderToSearch, setRootFolderToSearch] = React.useState(null);
-  const [previewDB, setPreviewDB] = React.useState(null);
-  const [imageToOrientation, setImageToOrientation] = React.useState(null);
 
   // graph panel settings, managed by this component because I feel like I might make the
   // ratingsToGraph a lot more involved at some point
@@ -295,50 +302,6 @@ export default function Home() {
     },
     []
   );
-  const handlePreviewDBFileImport = React.useEffect(
-    () => {
-      let mounted = true;
-      const awaitable = async () => {
-        // this callback isn't super-faithful with it's use of the mounted variable
-        if (mounted === false)
-        {
-          return;
-        }
-        if (previewDBPath === null)
-        {
-          // todo: set something?
-          setPreviewDB(null);
-          setImageToOrientation({});
-          return;
-        }
-        if(SQL)
-        {
-          const blob = await readFile(
-            previewDBPath
-          );
-          const localPreviewDB = new SQL.Database(blob);
-          setPreviewDB(localPreviewDB);
-          // we don't set limit, offset as we reckon we can slurp the db in one pass
-          const entries = preview.queries.select.cache(localPreviewDB);
-          const orientationMap = Object.fromEntries(entries.map( e => [e.imageId, e.orientation]));
-          setImageToOrientation(orientationMap);
-        }
-        else
-        {
-          // if we ever hit this, we need to do some refactoring to have better error behaviour!
-          console.error("SQL not available");
-          setPreviewDB(null);
-          setImageToOrientation({});
-        }
-      };
-      awaitable(); 
-      return ()=>{
-        mounted = false;
-      };
-    },
-    [previewDBPath]
-  );
-
   const handleRootFolderSet = React.useEffect(
     () => {
       let mounted = true;
@@ -390,52 +353,49 @@ export default function Home() {
         {
           setDB(null);
           setImages([]);
+          setCollectionTree([]);
           return;
         }
-        if(SQL)
+        setInProgress(true);
+        // the catalog's images are read in rust when it's opened, we fetch them a chunk at a time
+        // TODO: exception handling in the below?
+        const chunkLength = 5000;
+        const total = await invoke("get_total_available_images");
+        if (mounted && total === 0)
         {
-          setInProgress(true);
-          const blob = await readFile(
-            metadataDBPath
+          setImages([]);
+        }
+        for (let currentTotal = 0; currentTotal < total; currentTotal += chunkLength)
+        {
+          const chunk = await invoke("get_available_images", {offset: currentTotal, limit: chunkLength});
+          if (!mounted)
+          {
+            return;
+          }
+          const chunkAsImages = chunk.map(
+            (x) => CameraData.makeImageFromCatalog(x)
           );
-          const localDB = new SQL.Database(blob);
-          setDB(localDB);
-          // TODO: exception handling in the below?
-          // Should the below be a separate callback?
-          // TODO: if I'm going to pull the data across in chunks,
-          // perhaps I should cache computation in chunks as well?
-          const maxLimit = 50000;
-          const chunkLength = 5000;
-          for (let currentTotal = 0; currentTotal < maxLimit; currentTotal += chunkLength)
+          if (currentTotal === 0)
           {
-            const metadataChunk = metadata.queries.select.images(localDB, chunkLength, currentTotal, "imageid DESC");
-            // todo: Andy reckon's he's guessed a bug, as he thinks he sees our image list getting "duplicated"
-            // into itself. Let's leave this fix here and diagnose later, if this is the right fix.
-            const metadataChunkAsImages = metadataChunk.map(
-              (x) => CameraData.makeImageFromLightroom(x)
-            );
-            if (currentTotal === 0)
-            {
-              setImages(metadataChunkAsImages);
-            }
-            else
-            {
-              setImages( prevImages => prevImages.concat(metadataChunkAsImages));
-            }
-            if(metadataChunk.length < chunkLength)
-            {
-              break;
-            }
+            setImages(chunkAsImages);
+          }
+          else
+          {
+            setImages( prevImages => prevImages.concat(chunkAsImages));
+          }
+          if(chunk.length < chunkLength)
+          {
+            break;
           }
-          setInProgress(false);
         }
-        else
+        // images carry the ids of their collections, the tree names them
+        const collections = await invoke("get_collection_tree");
+        if (!mounted)
         {
-          // if we ever hit this, we need to do some refactoring to have better error behaviour!
-          console.error("SQL not available");
-          setDB(null);
-          setImages([]);
+          return;
         }
+        setCollectionTree(collections);
+        setInProgress(false);
       };
       awaitable(); 
       return ()=>{
@@ -444,6 +404,49 @@ export default function Home() {
     },
     [metadataDBPath]
   );
+  const fetchSnapshotAge = React.useEffect(
+    () => {
+      let mounted = true;
+      let unlisten = null;
+      setSnapshotAge(null);
+      if (metadataDBPath === null)
+      {
+        return;
+      }
+      const update = () => {
+        invoke("get_snapshot_age").then(
+          (age) => {
+            if (mounted)
+            {
+              setSnapshotAge(age);
+            }
+          }
+        );
+      };
+      update();
+      // the ages are shown in minutes, and previews.db is copied again whenever it changes
+      const interval = setInterval(update, 60 * 1000);
+      listen("preview-index-loaded", update).then((unlistenFn) => {
+        if (mounted)
+        {
+          unlisten = unlistenFn;
+        }
+        else
+        {
+          unlistenFn();
+        }
+      });
+      return () => {
+        mounted = false;
+        clearInterval(interval);
+        if (unlisten !== null)
+        {
+          unlisten();
+        }
+      };
+    },
+    [metadataDBPath]
+  );
   const fetchMetadataPath = React.useEffect(
       ()=>{
         let mounted = true;
@@ -458,12 +461,10 @@ export default function Home() {
                 if( response.conf_dirs !== null)
                 {
                   setMetadataDBPath(response.conf_dirs.metadata_db_path);
-                  setPreviewDBPath(response.conf_dirs.preview_db_path);
                 }
                 else
                 {
                   setMetadataDBPath(null);
-                  setPreviewDBPath(null);
                 }
                 setRootFolderToSearch(response.root_dir);
               }
@@ -495,12 +496,10 @@ export default function Home() {
                 if( response.conf_dirs !== null)
                 {
                   setMetadataDBPath(response.conf_dirs.metadata_db_path);
-                  setPreviewDBPath(response.conf_dirs.preview_db_path);
                 }
                 else
                 {
                   setMetadataDBPath(null);
-                  setPreviewDBPath(null);
                 }
                 setRootFolderToSearch(response.root_dir);
               }
@@ -516,7 +515,6 @@ export default function Home() {
             // folder and reload it
             setInProgress(true);
             setMetadataDBPath(null); 
-            setPreviewDBPath(null);
             setRootFolderToSearch(null);
             invoke(
               "update_app_state_for_folder_and_emit_state",
@@ -530,7 +528,6 @@ export default function Home() {
           {
             setInProgress(true);
             setMetadataDBPath(null); 
-            setPreviewDBPath(null);
             setRootFolderToSearch(null);
             invoke(
               "update_app_state_for_cat_and_emit_state",
@@ -610,6 +607,15 @@ export default function Home() {
     },
     []
   );
+  const onFilterCollections = React.useCallback( 
+    (event, ids)=> {
+      onSetFiltersForMetric(
+        "collections",
+        ids.length === 0 ? undefined : ids
+      )
+    },
+    []
+  );
   const onFilterFilesystem = React.useCallback( 
     (event, id)=> {
       setFilesystemFilters([id]);
@@ -722,7 +728,7 @@ export default function Home() {
                 }
                 else
                 {
-                  passMetricFilters &= filter.includes(image[metric]);
+                  passMetricFilters &= passesCategoryFilter(filter, image[metric]);
                 }
             }
           }
@@ -759,7 +765,7 @@ export default function Home() {
                 }
                 else
                 {
-                  passMetricFilters &= filter.includes(image[metric]);
+                  passMetricFilters &= passesCategoryFilter(filter, image[metric]);
                 }
               }
             }
@@ -855,13 +861,16 @@ export default function Home() {
             onSelectFilesystem={onFilterFilesystem}
             selectedFolders={filtersByMetric.folder ?? []}
             selectedFilesystem={filesystemFilters}
+            collectionTree={collectionTree}
+            snapshotAge={snapshotAge}
+            onSelectCollections={onFilterCollections}
+            selectedCollections={filtersByMetric.collections ?? []}
             folderData={folderData}
             handleDrawerClose={handleDrawerClose}
             handleDrawerOpen={handleDrawerOpen}
 
             showImage
             images={filteredImageState.filteredImages}
-            imageToOrientation={imageToOrientation}
             activeImageIndex={hoveredImageIndex !== null ? hoveredImageIndex : selectedImageIndex}
             imagePageLimits={getBoundedPageLimits()}
           />
@@ -941,7 +950,6 @@ export default function Home() {
             images={filteredImageState.filteredImages}
             focusedImageIndex={focusedImageIndex}
             setFocusedImageIndex={setFocusedImageIndex}
-            imageToOrientation={imageToOrientation}
             onClose={()=>{setFocusedImageIndex(null);}}
           />
         }